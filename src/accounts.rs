@@ -0,0 +1,463 @@
+// This file is Copyright its original authors, visible in version contror
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use ldk_node::io::sqlite_store::SqliteStore;
+use ldk_node::lightning::impl_writeable_tlv_based;
+use ldk_node::payment::{PaymentDirection, PaymentStatus};
+
+use crate::error::Error;
+use crate::limits;
+use crate::options::TransferOptions;
+use crate::payments::Payment;
+use crate::store::{self, PersistedMap};
+
+/// The longest account id we accept, as account ids are used as keys in the store.
+const MAX_ACCOUNT_ID_LEN: usize = 120;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// A sub-account that payments and funds can be attributed to, e.g. one per end user.
+pub struct Account {
+	/// The id the account was created with.
+	pub id: String,
+	/// When the account was created, as a UNIX timestamp in seconds.
+	pub created_at: u64,
+}
+
+impl_writeable_tlv_based!(Account, {
+	(0, id, required),
+	(2, created_at, required),
+});
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// A movement of funds between two accounts, settled without a lightning payment.
+pub struct AccountTransfer {
+	/// The position of the transfer among all transfers, starting at zero.
+	pub sequence: u64,
+	/// When the transfer was made, as a UNIX timestamp in seconds.
+	pub timestamp: u64,
+	/// The account the funds moved from.
+	pub from_account_id: String,
+	/// The account the funds moved to.
+	pub to_account_id: String,
+	/// The amount moved.
+	pub amount_msat: u64,
+	/// A free-form note given with the transfer.
+	pub memo: Option<String>,
+	/// The key the transfer was requested with, see [`TransferOptions::idempotency_key`].
+	///
+	/// [`TransferOptions::idempotency_key`]: crate::TransferOptions::idempotency_key
+	pub idempotency_key: Option<String>,
+}
+
+impl_writeable_tlv_based!(AccountTransfer, {
+	(0, sequence, required),
+	(2, timestamp, required),
+	(4, from_account_id, required),
+	(6, to_account_id, required),
+	(8, amount_msat, required),
+	(10, memo, option),
+	(12, idempotency_key, option),
+});
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// The balance of an account, derived from the payments and transfers attributed to it.
+pub struct AccountBalance {
+	/// The account the balance belongs to.
+	pub account_id: String,
+	/// Received minus sent payments, including routing fees, plus net transfers.
+	///
+	/// Can only become negative if an outbound payment was attributed to the account outside of
+	/// [`Romer::send_from`].
+	///
+	/// [`Romer::send_from`]: crate::Romer::send_from
+	pub balance_msat: i64,
+	/// The amount of outbound payments in flight, along with the most they may pay in routing
+	/// fees, which can't be spent until they settle.
+	pub pending_outbound_msat: u64,
+}
+
+impl AccountBalance {
+	/// The amount that can still be sent or transferred from the account.
+	fn spendable_msat(&self) -> u64 {
+		(self.balance_msat - self.pending_outbound_msat as i64).max(0) as u64
+	}
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// The account balances compared with the funds the node actually holds.
+pub struct AccountReconciliation {
+	/// The balance of every account.
+	pub accounts: Vec<AccountBalance>,
+	/// The sum of all account balances.
+	pub accounts_total_msat: i64,
+	/// Our total on-chain and lightning balance, which is the source of truth.
+	pub node_balance_msat: u64,
+	/// The node balance not attributed to any account, e.g. from payments made without an account
+	/// or paid in channel opening and on-chain fees.
+	///
+	/// Negative if the accounts claim more than the node holds, which needs investigating.
+	pub unattributed_msat: i64,
+}
+
+struct AccountsState {
+	next_transfer_sequence: u64,
+	/// Amounts reserved by sends that are being initiated, by account id.
+	reserved_msat: HashMap<String, u64>,
+}
+
+/// The persisted sub-accounts and the transfers between them.
+///
+/// Balances aren't stored but derived from the payments attributed to each account, so they
+/// can't drift from what the node has actually sent and received.
+pub(crate) struct Accounts {
+	accounts: PersistedMap<Account>,
+	transfers: PersistedMap<AccountTransfer>,
+	/// Serializes checking a balance with spending from it.
+	state: Mutex<AccountsState>,
+}
+
+impl Accounts {
+	pub(crate) fn load(kv_store: Arc<SqliteStore>) -> Result<Self, Error> {
+		let accounts =
+			PersistedMap::load(Arc::clone(&kv_store), store::ACCOUNTS_SECONDARY_NAMESPACE)?;
+		let transfers: PersistedMap<AccountTransfer> =
+			PersistedMap::load(kv_store, store::ACCOUNT_TRANSFERS_SECONDARY_NAMESPACE)?;
+		let next_transfer_sequence =
+			transfers.values().iter().map(|transfer| transfer.sequence + 1).max().unwrap_or(0);
+		let state = AccountsState { next_transfer_sequence, reserved_msat: HashMap::new() };
+		Ok(Self { accounts, transfers, state: Mutex::new(state) })
+	}
+
+	pub(crate) fn create(&self, account_id: &str, now: u64) -> Result<Account, Error> {
+		if !is_valid_account_id(account_id) {
+			return Err(Error::InvalidAccountId);
+		}
+		let _state = self.state.lock().unwrap();
+		if self.accounts.get(account_id).is_some() {
+			return Err(Error::AccountAlreadyExists);
+		}
+		let account = Account { id: account_id.to_string(), created_at: now };
+		self.accounts.insert(account_id, account.clone())?;
+		Ok(account)
+	}
+
+	/// Returns all accounts, oldest first.
+	pub(crate) fn list(&self) -> Vec<Account> {
+		let mut accounts = self.accounts.values();
+		accounts.sort_unstable_by(|a, b| (a.created_at, &a.id).cmp(&(b.created_at, &b.id)));
+		accounts
+	}
+
+	/// Fails with [`Error::UnknownAccount`] if there is no account with the given id.
+	pub(crate) fn ensure_exists(&self, account_id: &str) -> Result<(), Error> {
+		match self.accounts.get(account_id) {
+			Some(_) => Ok(()),
+			None => Err(Error::UnknownAccount),
+		}
+	}
+
+	/// Returns the transfers from or to `account_id`, in order.
+	pub(crate) fn transfers(&self, account_id: &str) -> Vec<AccountTransfer> {
+		let mut transfers: Vec<AccountTransfer> = self
+			.transfers
+			.values()
+			.into_iter()
+			.filter(|t| t.from_account_id == account_id || t.to_account_id == account_id)
+			.collect();
+		transfers.sort_unstable_by_key(|transfer| transfer.sequence);
+		transfers
+	}
+
+	pub(crate) fn balance(&self, account_id: &str, payments: &[Payment]) -> AccountBalance {
+		let state = self.state.lock().unwrap();
+		self.balance_locked(&state, account_id, payments)
+	}
+
+	/// Compares the balances of all accounts with our balance.
+	pub(crate) fn reconcile(
+		&self, payments: &[Payment], node_balance_msat: u64,
+	) -> AccountReconciliation {
+		let state = self.state.lock().unwrap();
+		let accounts: Vec<AccountBalance> = self
+			.list()
+			.iter()
+			.map(|account| self.balance_locked(&state, &account.id, payments))
+			.collect();
+		let accounts_total_msat = accounts.iter().map(|balance| balance.balance_msat).sum();
+		AccountReconciliation {
+			accounts,
+			accounts_total_msat,
+			node_balance_msat,
+			unattributed_msat: node_balance_msat as i64 - accounts_total_msat,
+		}
+	}
+
+	/// Checks that `account_id` can afford to send `amount_msat` plus routing fees and reserves
+	/// the amount until the returned [`Reservation`] is dropped.
+	///
+	/// `payments` is called once the balance can't change anymore, and `attribute` once the
+	/// balance check passed, both while no other spend from any account can start.
+	pub(crate) fn reserve<P, A>(
+		&self, account_id: &str, amount_msat: u64, payments: P, attribute: A,
+	) -> Result<Reservation<'_>, Error>
+	where
+		P: FnOnce() -> Vec<Payment>,
+		A: FnOnce() -> Result<(), Error>,
+	{
+		self.ensure_exists(account_id)?;
+		let mut state = self.state.lock().unwrap();
+		let amount_msat = amount_msat + limits::routing_fee_budget_msat(amount_msat);
+		let balance = self.balance_locked(&state, account_id, &payments());
+		if balance.spendable_msat() < amount_msat {
+			return Err(Error::InsufficientAccountBalance);
+		}
+		attribute()?;
+		*state.reserved_msat.entry(account_id.to_string()).or_default() += amount_msat;
+		Ok(Reservation { accounts: self, account_id: account_id.to_string(), amount_msat })
+	}
+
+	/// Moves `amount_msat` from one account to another.
+	pub(crate) fn transfer<P: FnOnce() -> Vec<Payment>>(
+		&self, from_account_id: &str, to_account_id: &str, amount_msat: u64,
+		options: TransferOptions, now: u64, payments: P,
+	) -> Result<AccountTransfer, Error> {
+		self.ensure_exists(from_account_id)?;
+		self.ensure_exists(to_account_id)?;
+		if amount_msat == 0 || from_account_id == to_account_id {
+			return Err(Error::InvalidAmount);
+		}
+
+		let mut state = self.state.lock().unwrap();
+		if let Some(idempotency_key) = &options.idempotency_key {
+			let previous = self
+				.transfers
+				.values()
+				.into_iter()
+				.find(|transfer| transfer.idempotency_key.as_ref() == Some(idempotency_key));
+			if let Some(previous) = previous {
+				if previous.from_account_id != from_account_id
+					|| previous.to_account_id != to_account_id
+					|| previous.amount_msat != amount_msat
+				{
+					return Err(Error::IdempotencyKeyConflict);
+				}
+				return Ok(previous);
+			}
+		}
+
+		let balance = self.balance_locked(&state, from_account_id, &payments());
+		if balance.spendable_msat() < amount_msat {
+			return Err(Error::InsufficientAccountBalance);
+		}
+		let transfer = AccountTransfer {
+			sequence: state.next_transfer_sequence,
+			timestamp: now,
+			from_account_id: from_account_id.to_string(),
+			to_account_id: to_account_id.to_string(),
+			amount_msat,
+			memo: options.memo,
+			idempotency_key: options.idempotency_key,
+		};
+		self.transfers.insert(&format!("{:020}", transfer.sequence), transfer.clone())?;
+		state.next_transfer_sequence += 1;
+		Ok(transfer)
+	}
+
+	fn balance_locked(
+		&self, state: &AccountsState, account_id: &str, payments: &[Payment],
+	) -> AccountBalance {
+		let mut balance_msat: i64 = 0;
+		let mut pending_outbound_msat = state.reserved_msat.get(account_id).copied().unwrap_or(0);
+		let attributed =
+			payments.iter().filter(|payment| payment.account_id.as_deref() == Some(account_id));
+		for payment in attributed {
+			let amount_msat = payment.details.amount_msat.unwrap_or(0);
+			match (payment.details.direction, payment.details.status) {
+				(PaymentDirection::Inbound, PaymentStatus::Succeeded) => {
+					balance_msat += amount_msat as i64;
+				},
+				(PaymentDirection::Outbound, PaymentStatus::Succeeded) => {
+					balance_msat -= (amount_msat + payment.fee_paid_msat.unwrap_or(0)) as i64;
+				},
+				(PaymentDirection::Outbound, PaymentStatus::Pending) => {
+					pending_outbound_msat +=
+						amount_msat + limits::routing_fee_budget_msat(amount_msat);
+				},
+				_ => {},
+			}
+		}
+		for transfer in self.transfers.values() {
+			if transfer.from_account_id == account_id {
+				balance_msat -= transfer.amount_msat as i64;
+			}
+			if transfer.to_account_id == account_id {
+				balance_msat += transfer.amount_msat as i64;
+			}
+		}
+		AccountBalance { account_id: account_id.to_string(), balance_msat, pending_outbound_msat }
+	}
+}
+
+/// An amount reserved for a send from an account, released when dropped.
+///
+/// Keep it until the payment is known to LDK Node, which then tracks the amount as pending.
+pub(crate) struct Reservation<'a> {
+	accounts: &'a Accounts,
+	account_id: String,
+	amount_msat: u64,
+}
+
+impl Drop for Reservation<'_> {
+	fn drop(&mut self) {
+		let mut state = self.accounts.state.lock().unwrap();
+		if let Some(reserved_msat) = state.reserved_msat.get_mut(&self.account_id) {
+			*reserved_msat -= self.amount_msat;
+			if *reserved_msat == 0 {
+				state.reserved_msat.remove(&self.account_id);
+			}
+		}
+	}
+}
+
+/// Account ids are used as store keys, so are limited to the characters those allow.
+fn is_valid_account_id(account_id: &str) -> bool {
+	!account_id.is_empty()
+		&& account_id.len() <= MAX_ACCOUNT_ID_LEN
+		&& account_id.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+#[cfg(test)]
+mod test {
+	use ldk_node::lightning::ln::channelmanager::PaymentId;
+	use ldk_node::payment::{PaymentDetails, PaymentKind};
+
+	use super::*;
+
+	fn payment(
+		account_id: &str, direction: PaymentDirection, status: PaymentStatus, amount_msat: u64,
+		fee_paid_msat: Option<u64>,
+	) -> Payment {
+		Payment {
+			details: PaymentDetails {
+				id: PaymentId([0; 32]),
+				kind: PaymentKind::Onchain,
+				amount_msat: Some(amount_msat),
+				direction,
+				status,
+				latest_update_timestamp: 0,
+			},
+			fee_paid_msat,
+			label: None,
+			metadata: HashMap::new(),
+			external_ref: None,
+			fiat_value: None,
+			account_id: Some(account_id.to_string()),
+			internal: false,
+		}
+	}
+
+	#[test]
+	fn validates_account_ids() {
+		let accounts = Accounts::load(store::test_store("accounts-ids")).unwrap();
+		assert_eq!(accounts.create("", 0), Err(Error::InvalidAccountId));
+		assert_eq!(accounts.create("alice/bob", 0), Err(Error::InvalidAccountId));
+		assert_eq!(accounts.create(&"a".repeat(121), 0), Err(Error::InvalidAccountId));
+		accounts.create("user-1_a", 0).unwrap();
+		assert_eq!(accounts.create("user-1_a", 1), Err(Error::AccountAlreadyExists));
+		assert_eq!(accounts.ensure_exists("user-2"), Err(Error::UnknownAccount));
+	}
+
+	#[test]
+	fn derives_balances_from_payments_and_transfers() {
+		let kv_store = store::test_store("accounts-balances");
+		let accounts = Accounts::load(Arc::clone(&kv_store)).unwrap();
+		accounts.create("alice", 1).unwrap();
+		accounts.create("bob", 2).unwrap();
+
+		let payments = vec![
+			payment("alice", PaymentDirection::Inbound, PaymentStatus::Succeeded, 1_000_000, None),
+			payment("alice", PaymentDirection::Inbound, PaymentStatus::Pending, 5_000_000, None),
+			payment(
+				"alice",
+				PaymentDirection::Outbound,
+				PaymentStatus::Succeeded,
+				100_000,
+				Some(10),
+			),
+			payment("alice", PaymentDirection::Outbound, PaymentStatus::Failed, 100_000, None),
+			payment("alice", PaymentDirection::Outbound, PaymentStatus::Pending, 200_000, None),
+		];
+		assert_eq!(
+			accounts.transfer("alice", "bob", 700_000, Default::default(), 10, || payments.clone()),
+			Err(Error::InsufficientAccountBalance)
+		);
+		let options = TransferOptions {
+			memo: Some("rent".to_string()),
+			idempotency_key: Some("rent-march".to_string()),
+			actor_id: None,
+		};
+		let transfer =
+			accounts.transfer("alice", "bob", 300_000, options.clone(), 10, || payments.clone());
+		// Retrying with the same key doesn't move the funds again.
+		assert_eq!(
+			accounts.transfer("alice", "bob", 300_000, options.clone(), 20, || payments.clone()),
+			transfer
+		);
+		assert_eq!(
+			accounts.transfer("alice", "bob", 200_000, options, 20, || payments.clone()),
+			Err(Error::IdempotencyKeyConflict)
+		);
+
+		let accounts = Accounts::load(kv_store).unwrap();
+		assert_eq!(
+			accounts.list().iter().map(|a| a.id.as_str()).collect::<Vec<_>>(),
+			["alice", "bob"]
+		);
+		let alice = accounts.balance("alice", &payments);
+		assert_eq!(alice.balance_msat, 1_000_000 - 100_010 - 300_000);
+		assert_eq!(alice.pending_outbound_msat, 200_000 + 52_000);
+		assert_eq!(accounts.transfers("bob")[0].memo, Some("rent".to_string()));
+
+		let reconciliation = accounts.reconcile(&payments, 1_000_000);
+		assert_eq!(reconciliation.accounts[1].balance_msat, 300_000);
+		assert_eq!(reconciliation.accounts_total_msat, 1_000_000 - 100_010);
+		assert_eq!(reconciliation.unattributed_msat, 100_010);
+	}
+
+	#[test]
+	fn reservations_block_concurrent_spends() {
+		let accounts = Accounts::load(store::test_store("accounts-reservations")).unwrap();
+		accounts.create("alice", 0).unwrap();
+		let payments = vec![payment(
+			"alice",
+			PaymentDirection::Inbound,
+			PaymentStatus::Succeeded,
+			200_000,
+			None,
+		)];
+
+		let reservation =
+			accounts.reserve("alice", 100_000, || payments.clone(), || Ok(())).unwrap();
+		assert_eq!(accounts.balance("alice", &payments).pending_outbound_msat, 151_000);
+		assert!(matches!(
+			accounts.reserve("alice", 10_000, || payments.clone(), || Ok(())),
+			Err(Error::InsufficientAccountBalance)
+		));
+
+		// Failing to attribute the payment doesn't reserve anything.
+		drop(reservation);
+		assert!(matches!(
+			accounts.reserve("alice", 10_000, || payments.clone(), || Err(Error::DuplicatePayment)),
+			Err(Error::DuplicatePayment)
+		));
+		assert_eq!(accounts.balance("alice", &payments).pending_outbound_msat, 0);
+	}
+}
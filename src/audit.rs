@@ -0,0 +1,326 @@
+// This file is Copyright its original authors, visible in version contror
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use ldk_node::bitcoin::hashes::sha256::Hash as Sha256;
+use ldk_node::bitcoin::hashes::{Hash, HashEngine};
+use ldk_node::io::sqlite_store::SqliteStore;
+use ldk_node::lightning::util::ser::Writeable;
+use ldk_node::lightning::{impl_writeable_tlv_based, impl_writeable_tlv_based_enum};
+
+use crate::error::Error;
+use crate::hex_utils;
+use crate::payments::TimeRange;
+use crate::store::{self, PersistedMap};
+
+/// The previous hash of the first entry in the audit log.
+const GENESIS_HASH: [u8; 32] = [0; 32];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// A wallet operation recorded in the audit log.
+pub enum AuditOperation {
+	/// A lightning payment via [`Romer::send`] or one of its variants.
+	///
+	/// [`Romer::send`]: crate::Romer::send
+	Send,
+	/// An on-chain payment via [`Romer::send_onchain`] or one of its variants.
+	///
+	/// [`Romer::send_onchain`]: crate::Romer::send_onchain
+	SendOnchain,
+	/// An invoice created via [`Romer::receive`] or one of its variants.
+	///
+	/// [`Romer::receive`]: crate::Romer::receive
+	Receive,
+	/// A channel was opened, usually by our LSP to receive a payment.
+	ChannelOpened,
+	/// A channel was closed.
+	ChannelClosed,
+	/// The node was started with a configuration that differs from the previous one.
+	ConfigChanged,
+	/// An exchange rate provider was set via [`Romer::set_exchange_rate_provider`].
+	///
+	/// [`Romer::set_exchange_rate_provider`]: crate::Romer::set_exchange_rate_provider
+	ExchangeRateProviderSet,
+	/// A sub-account was created via [`Romer::create_account`].
+	///
+	/// [`Romer::create_account`]: crate::Romer::create_account
+	CreateAccount,
+	/// Funds were moved between sub-accounts via [`Romer::transfer_between_accounts`].
+	///
+	/// [`Romer::transfer_between_accounts`]: crate::Romer::transfer_between_accounts
+	TransferBetweenAccounts,
+	/// A payment to a hold invoice was settled via [`Romer::claim`].
+	///
+	/// [`Romer::claim`]: crate::Romer::claim
+	Claim,
+	/// A hold invoice was cancelled via [`Romer::cancel`].
+	///
+	/// [`Romer::cancel`]: crate::Romer::cancel
+	Cancel,
+	/// A payment to a hold invoice was cancelled as nobody claimed it ahead of its deadline.
+	HoldInvoiceExpired,
+}
+
+impl_writeable_tlv_based_enum!(AuditOperation,
+	(0, Send) => {},
+	(2, SendOnchain) => {},
+	(4, Receive) => {},
+	(6, ChannelOpened) => {},
+	(8, ChannelClosed) => {},
+	(10, ConfigChanged) => {},
+	(12, ExchangeRateProviderSet) => {},
+	(14, CreateAccount) => {},
+	(16, TransferBetweenAccounts) => {},
+	(18, Claim) => {},
+	(20, Cancel) => {},
+	(22, HoldInvoiceExpired) => {},
+);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// A single wallet operation recorded in the audit log.
+///
+/// Every entry commits to the one before it through [`Self::previous_hash`], so changing or
+/// removing an entry breaks the chain, see [`Romer::verify_audit_log`].
+///
+/// [`Romer::verify_audit_log`]: crate::Romer::verify_audit_log
+pub struct AuditEntry {
+	/// The position of the entry in the audit log, starting at zero.
+	pub sequence: u64,
+	/// When the entry was recorded, as a UNIX timestamp in seconds.
+	pub timestamp: u64,
+	/// The operation that was attempted.
+	pub operation: AuditOperation,
+	/// Who initiated the operation, as given by the caller, e.g. via [`SendOptions::actor_id`].
+	///
+	/// `None` for operations the caller didn't identify themselves for and ones the node
+	/// initiated itself, like channel closures.
+	///
+	/// [`SendOptions::actor_id`]: crate::SendOptions::actor_id
+	pub actor_id: Option<String>,
+	/// The parameters the operation was called with, e.g. the destination and amount.
+	pub parameters: HashMap<String, String>,
+	/// The error the operation failed with, or `None` if it succeeded.
+	pub error: Option<String>,
+	/// The hash of the previous entry, or all zeros for the first one, hex-encoded.
+	pub previous_hash: String,
+	/// The SHA-256 hash of this entry's contents including [`Self::previous_hash`],
+	/// hex-encoded.
+	///
+	/// Keep the hash of the latest entry outside the wallet to also detect removal of the most
+	/// recent entries.
+	pub hash: String,
+}
+
+impl_writeable_tlv_based!(AuditEntry, {
+	(0, sequence, required),
+	(2, timestamp, required),
+	(4, operation, required),
+	(6, parameters, (default_value, HashMap::new())),
+	(8, error, option),
+	(10, actor_id, option),
+	(12, previous_hash, required),
+	(14, hash, required),
+});
+
+impl AuditEntry {
+	fn compute_hash(&self) -> String {
+		let mut engine = Sha256::engine();
+		engine.input(&self.sequence.to_be_bytes());
+		engine.input(&self.timestamp.to_be_bytes());
+		engine.input(&self.operation.encode());
+		hash_optional_str(&mut engine, self.actor_id.as_deref());
+		// Parameters are hashed in key order, as the map has no order of its own.
+		let mut parameters: Vec<_> = self.parameters.iter().collect();
+		parameters.sort_unstable();
+		engine.input(&(parameters.len() as u64).to_be_bytes());
+		for (key, value) in parameters {
+			hash_str(&mut engine, key);
+			hash_str(&mut engine, value);
+		}
+		hash_optional_str(&mut engine, self.error.as_deref());
+		hash_str(&mut engine, &self.previous_hash);
+		hex_utils::to_string(&Sha256::from_engine(engine).to_byte_array())
+	}
+}
+
+/// Hashes `s` prefixed with its length, so adjacent fields can't be shifted into each other.
+fn hash_str(engine: &mut <Sha256 as Hash>::Engine, s: &str) {
+	engine.input(&(s.len() as u64).to_be_bytes());
+	engine.input(s.as_bytes());
+}
+
+fn hash_optional_str(engine: &mut <Sha256 as Hash>::Engine, s: Option<&str>) {
+	match s {
+		Some(s) => {
+			engine.input(&[1]);
+			hash_str(engine, s);
+		},
+		None => engine.input(&[0]),
+	}
+}
+
+/// The end of the audit log new entries are appended to.
+struct Head {
+	next_sequence: u64,
+	hash: String,
+}
+
+/// A persisted, append-only and hash-chained log of wallet operations.
+pub(crate) struct AuditLog {
+	entries: PersistedMap<AuditEntry>,
+	head: Mutex<Head>,
+}
+
+impl AuditLog {
+	pub(crate) fn load(kv_store: Arc<SqliteStore>) -> Result<Self, Error> {
+		let entries: PersistedMap<AuditEntry> =
+			PersistedMap::load(kv_store, store::AUDIT_LOG_SECONDARY_NAMESPACE)?;
+		let head = match entries.values().into_iter().max_by_key(|entry| entry.sequence) {
+			Some(last) => Head { next_sequence: last.sequence + 1, hash: last.hash },
+			None => Head { next_sequence: 0, hash: hex_utils::to_string(&GENESIS_HASH) },
+		};
+		Ok(Self { entries, head: Mutex::new(head) })
+	}
+
+	/// Appends an entry for `operation`, failed with `error` if given.
+	pub(crate) fn record(
+		&self, operation: AuditOperation, actor_id: Option<String>,
+		parameters: HashMap<String, String>, error: Option<&Error>, timestamp: u64,
+	) -> Result<(), Error> {
+		let mut head = self.head.lock().unwrap();
+		let mut entry = AuditEntry {
+			sequence: head.next_sequence,
+			timestamp,
+			operation,
+			actor_id,
+			parameters,
+			error: error.map(|e| format!("{:?}", e)),
+			previous_hash: head.hash.clone(),
+			hash: String::new(),
+		};
+		entry.hash = entry.compute_hash();
+		let hash = entry.hash.clone();
+		self.entries.insert(&format!("{:020}", entry.sequence), entry)?;
+		head.next_sequence += 1;
+		head.hash = hash;
+		Ok(())
+	}
+
+	/// Returns the entries recorded within `range`, in order.
+	pub(crate) fn entries(&self, range: &TimeRange) -> Vec<AuditEntry> {
+		let mut entries: Vec<AuditEntry> =
+			self.entries.values().into_iter().filter(|e| range.contains(e.timestamp)).collect();
+		entries.sort_unstable_by_key(|entry| entry.sequence);
+		entries
+	}
+
+	/// Returns the most recent entry for `operation`, if any.
+	pub(crate) fn last(&self, operation: AuditOperation) -> Option<AuditEntry> {
+		self.entries
+			.values()
+			.into_iter()
+			.filter(|entry| entry.operation == operation)
+			.max_by_key(|entry| entry.sequence)
+	}
+
+	/// Checks that no entry was changed, removed or inserted, returning the number of entries.
+	pub(crate) fn verify(&self) -> Result<u64, Error> {
+		// Hold the head so no entry is appended while we're checking.
+		let head = self.head.lock().unwrap();
+		let entries = self.entries(&TimeRange::default());
+		let mut previous_hash = hex_utils::to_string(&GENESIS_HASH);
+		for (sequence, entry) in entries.iter().enumerate() {
+			if entry.sequence != sequence as u64
+				|| entry.previous_hash != previous_hash
+				|| entry.hash != entry.compute_hash()
+			{
+				return Err(Error::AuditLogTampered);
+			}
+			previous_hash = entry.hash.clone();
+		}
+		if head.next_sequence != entries.len() as u64 || head.hash != previous_hash {
+			return Err(Error::AuditLogTampered);
+		}
+		Ok(entries.len() as u64)
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	fn record(log: &AuditLog, operation: AuditOperation, parameters: &[(&str, &str)]) {
+		let parameters =
+			parameters.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect();
+		log.record(operation, Some("alice".to_string()), parameters, None, 100).unwrap();
+	}
+
+	#[test]
+	fn appends_entries_across_restarts() {
+		let kv_store = store::test_store("audit-log");
+		let mut parameters = HashMap::new();
+		parameters.insert("address".to_string(), "bc1qexample".to_string());
+
+		let log = AuditLog::load(Arc::clone(&kv_store)).unwrap();
+		log.record(AuditOperation::SendOnchain, None, parameters.clone(), None, 100).unwrap();
+		let log = AuditLog::load(kv_store).unwrap();
+		log.record(
+			AuditOperation::Send,
+			Some("alice".to_string()),
+			HashMap::new(),
+			Some(&Error::DestinationNotAllowed),
+			200,
+		)
+		.unwrap();
+
+		let entries = log.entries(&TimeRange::default());
+		assert_eq!(entries.len(), 2);
+		assert_eq!(entries[0].parameters, parameters);
+		assert_eq!(entries[0].error, None);
+		assert_eq!(entries[0].previous_hash, hex_utils::to_string(&GENESIS_HASH));
+		assert_eq!(entries[1].sequence, 1);
+		assert_eq!(entries[1].actor_id, Some("alice".to_string()));
+		assert_eq!(entries[1].error, Some("DestinationNotAllowed".to_string()));
+		assert_eq!(entries[1].previous_hash, entries[0].hash);
+		assert_eq!(log.verify(), Ok(2));
+		assert_eq!(log.last(AuditOperation::SendOnchain), Some(entries[0].clone()));
+
+		let range = TimeRange { from_timestamp: Some(150), to_timestamp: None };
+		assert_eq!(log.entries(&range), vec![entries[1].clone()]);
+	}
+
+	#[test]
+	fn detects_tampering() {
+		let kv_store = store::test_store("audit-log-tampering");
+		let log = AuditLog::load(Arc::clone(&kv_store)).unwrap();
+		for amount in ["1000", "2000", "3000"] {
+			record(&log, AuditOperation::Send, &[("amount_msat", amount)]);
+		}
+		assert_eq!(log.verify(), Ok(3));
+
+		// Rewriting an entry is detected even if its own hash is updated to match.
+		let mut entry = log.entries.get(&format!("{:020}", 1)).unwrap();
+		entry.parameters.insert("amount_msat".to_string(), "1".to_string());
+		log.entries.insert(&format!("{:020}", 1), entry.clone()).unwrap();
+		assert_eq!(log.verify(), Err(Error::AuditLogTampered));
+		entry.hash = entry.compute_hash();
+		log.entries.insert(&format!("{:020}", 1), entry).unwrap();
+		assert_eq!(log.verify(), Err(Error::AuditLogTampered));
+
+		// Removing an entry, including the latest one, is detected by the running log.
+		let log = AuditLog::load(Arc::clone(&kv_store)).unwrap();
+		let original = log.entries(&TimeRange::default());
+		log.entries.remove(&format!("{:020}", 2)).unwrap();
+		assert_eq!(log.verify(), Err(Error::AuditLogTampered));
+		log.entries.insert(&format!("{:020}", 2), original[2].clone()).unwrap();
+		log.entries.remove(&format!("{:020}", 0)).unwrap();
+		assert_eq!(log.verify(), Err(Error::AuditLogTampered));
+	}
+}
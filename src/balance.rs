@@ -0,0 +1,239 @@
+// This file is Copyright its original authors, visible in version contror
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+use ldk_node::lightning::chain::channelmonitor::ANTI_REORG_DELAY;
+use ldk_node::lightning::ln::types::ChannelId;
+use ldk_node::{BalanceDetails, ChannelDetails, LightningBalance, PendingSweepBalance};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Funds from a closed channel that will become spendable on-chain.
+pub struct ClaimableBalance {
+	/// The channel the funds come from, if known.
+	pub channel_id: Option<ChannelId>,
+	/// The amount we expect to claim.
+	pub amount_sats: u64,
+	/// The block height at which the funds become spendable, if already known.
+	pub maturity_height: Option<u32>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+/// Where the funds making up our lightning balance currently are.
+pub struct LightningBalanceBreakdown {
+	/// What we can send right now over channels that are ready and whose peer is connected.
+	pub spendable_sats: u64,
+	/// What we could send over channels that are ready, but whose peer is disconnected.
+	pub offline_sats: u64,
+	/// Outbound HTLCs that are still in flight, which we get back if the payment fails.
+	pub in_flight_outbound_htlcs_sats: u64,
+	/// Inbound HTLCs that are still in flight, which we only get if we claim them in time.
+	pub in_flight_inbound_htlcs_sats: u64,
+	/// Our balance in channels that are still waiting for their funding to confirm.
+	pub pending_channel_opens_sats: u64,
+	/// Funds from closed channels that will become spendable on-chain.
+	pub claimable_after_close: Vec<ClaimableBalance>,
+	/// The sum of [`Self::claimable_after_close`].
+	pub claimable_after_close_sats: u64,
+	/// Our balance held back in open channels as the reserve required by our peers.
+	pub channel_reserves_sats: u64,
+	/// On-chain funds held back to pay for fee bumping anchor channels.
+	pub anchor_channels_reserve_sats: u64,
+}
+
+impl LightningBalanceBreakdown {
+	fn add_claimable(
+		&mut self, channel_id: Option<ChannelId>, amount_sats: u64, maturity_height: Option<u32>,
+	) {
+		self.claimable_after_close_sats += amount_sats;
+		self.claimable_after_close.push(ClaimableBalance {
+			channel_id,
+			amount_sats,
+			maturity_height,
+		});
+	}
+}
+
+/// Sorts our channel balances and pending sweeps into [`LightningBalanceBreakdown`] buckets.
+pub(crate) fn breakdown(
+	channels: &[ChannelDetails], balances: &BalanceDetails,
+) -> LightningBalanceBreakdown {
+	let mut breakdown = LightningBalanceBreakdown {
+		anchor_channels_reserve_sats: balances.total_anchor_channels_reserve_sats,
+		..Default::default()
+	};
+	let is_open = |channel_id: &ChannelId| channels.iter().any(|c| &c.channel_id == channel_id);
+	let is_pending_open = |channel_id: &ChannelId| {
+		channels.iter().any(|c| &c.channel_id == channel_id && !c.is_channel_ready)
+	};
+
+	for channel in channels.iter().filter(|c| c.is_channel_ready) {
+		let outbound_capacity_sats = channel.outbound_capacity_msat / 1000;
+		if channel.is_usable {
+			breakdown.spendable_sats += outbound_capacity_sats;
+		} else {
+			breakdown.offline_sats += outbound_capacity_sats;
+		}
+		breakdown.channel_reserves_sats += channel.unspendable_punishment_reserve.unwrap_or(0);
+	}
+
+	for balance in balances.lightning_balances.iter() {
+		match balance {
+			LightningBalance::ClaimableOnChannelClose { channel_id, amount_satoshis, .. } => {
+				// The balance of ready channels is covered by their capacity and reserve above.
+				if is_pending_open(channel_id) {
+					breakdown.pending_channel_opens_sats += amount_satoshis;
+				}
+			},
+			LightningBalance::ClaimableAwaitingConfirmations {
+				channel_id,
+				amount_satoshis,
+				confirmation_height,
+				..
+			} => breakdown.add_claimable(
+				Some(*channel_id),
+				*amount_satoshis,
+				Some(*confirmation_height),
+			),
+			LightningBalance::ContentiousClaimable { channel_id, amount_satoshis, .. }
+			| LightningBalance::CounterpartyRevokedOutputClaimable {
+				channel_id,
+				amount_satoshis,
+				..
+			} => breakdown.add_claimable(Some(*channel_id), *amount_satoshis, None),
+			LightningBalance::MaybeTimeoutClaimableHTLC {
+				channel_id,
+				amount_satoshis,
+				claimable_height,
+				..
+			} => {
+				if is_open(channel_id) {
+					breakdown.in_flight_outbound_htlcs_sats += amount_satoshis;
+				} else {
+					breakdown.add_claimable(
+						Some(*channel_id),
+						*amount_satoshis,
+						Some(*claimable_height),
+					);
+				}
+			},
+			LightningBalance::MaybePreimageClaimableHTLC { amount_satoshis, .. } => {
+				breakdown.in_flight_inbound_htlcs_sats += amount_satoshis;
+			},
+		}
+	}
+
+	for balance in balances.pending_balances_from_channel_closures.iter() {
+		match balance {
+			PendingSweepBalance::PendingBroadcast { channel_id, amount_satoshis }
+			| PendingSweepBalance::BroadcastAwaitingConfirmation {
+				channel_id,
+				amount_satoshis,
+				..
+			} => breakdown.add_claimable(*channel_id, *amount_satoshis, None),
+			PendingSweepBalance::AwaitingThresholdConfirmations {
+				channel_id,
+				amount_satoshis,
+				confirmation_height,
+				..
+			} => breakdown.add_claimable(
+				*channel_id,
+				*amount_satoshis,
+				Some(confirmation_height + ANTI_REORG_DELAY - 1),
+			),
+		}
+	}
+
+	breakdown
+}
+
+#[cfg(test)]
+mod test {
+	use ldk_node::bitcoin::hashes::Hash;
+	use ldk_node::bitcoin::secp256k1::PublicKey;
+	use ldk_node::bitcoin::{BlockHash, Txid};
+	use ldk_node::lightning::chain::channelmonitor::BalanceSource;
+	use ldk_node::lightning::ln::PaymentHash;
+
+	use super::*;
+
+	fn counterparty() -> PublicKey {
+		PublicKey::from_slice(&[2; 33]).unwrap()
+	}
+
+	#[test]
+	fn sorts_closed_channel_balances() {
+		let channel_id = ChannelId([1; 32]);
+		let balances = BalanceDetails {
+			total_onchain_balance_sats: 0,
+			spendable_onchain_balance_sats: 0,
+			total_anchor_channels_reserve_sats: 25_000,
+			total_lightning_balance_sats: 0,
+			lightning_balances: vec![
+				LightningBalance::ClaimableAwaitingConfirmations {
+					channel_id,
+					counterparty_node_id: counterparty(),
+					amount_satoshis: 10_000,
+					confirmation_height: 800_144,
+					source: BalanceSource::CoopClose,
+				},
+				LightningBalance::MaybeTimeoutClaimableHTLC {
+					channel_id,
+					counterparty_node_id: counterparty(),
+					amount_satoshis: 2_000,
+					claimable_height: 800_200,
+					payment_hash: PaymentHash([3; 32]),
+					outbound_payment: true,
+				},
+				LightningBalance::MaybePreimageClaimableHTLC {
+					channel_id,
+					counterparty_node_id: counterparty(),
+					amount_satoshis: 3_000,
+					expiry_height: 800_100,
+					payment_hash: PaymentHash([4; 32]),
+				},
+			],
+			pending_balances_from_channel_closures: vec![
+				PendingSweepBalance::PendingBroadcast { channel_id: None, amount_satoshis: 500 },
+				PendingSweepBalance::AwaitingThresholdConfirmations {
+					channel_id: Some(channel_id),
+					latest_spending_txid: Txid::all_zeros(),
+					confirmation_hash: BlockHash::all_zeros(),
+					confirmation_height: 800_000,
+					amount_satoshis: 7_000,
+				},
+			],
+		};
+
+		let breakdown = breakdown(&[], &balances);
+		assert_eq!(breakdown.spendable_sats, 0);
+		assert_eq!(breakdown.in_flight_outbound_htlcs_sats, 0);
+		assert_eq!(breakdown.in_flight_inbound_htlcs_sats, 3_000);
+		assert_eq!(breakdown.anchor_channels_reserve_sats, 25_000);
+		assert_eq!(breakdown.claimable_after_close_sats, 19_500);
+		assert_eq!(
+			breakdown.claimable_after_close,
+			vec![
+				ClaimableBalance {
+					channel_id: Some(channel_id),
+					amount_sats: 10_000,
+					maturity_height: Some(800_144),
+				},
+				ClaimableBalance {
+					channel_id: Some(channel_id),
+					amount_sats: 2_000,
+					maturity_height: Some(800_200),
+				},
+				ClaimableBalance { channel_id: None, amount_sats: 500, maturity_height: None },
+				ClaimableBalance {
+					channel_id: Some(channel_id),
+					amount_sats: 7_000,
+					maturity_height: Some(800_005),
+				},
+			]
+		);
+	}
+}
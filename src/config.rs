@@ -0,0 +1,198 @@
+// This file is Copyright its original authors, visible in version contror
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+use std::collections::HashMap;
+
+use ldk_node::{
+	bitcoin::secp256k1::PublicKey, lightning::ln::msgs::SocketAddress, payment::LSPFeeLimits,
+};
+
+use crate::policy::{DestinationPolicy, SpendingPolicy};
+
+#[derive(Debug, Clone)]
+/// Options to customize a [`Romer`] instance.
+///
+/// [`Romer`]: crate::Romer
+pub struct Config {
+	/// The LSPs we source inbound liquidity from, ranked by preference.
+	///
+	/// If empty, the c= LSP for the network of the API token is used.
+	pub lsps: Vec<LspConfig>,
+	/// The most we allow any LSP to charge for opening a JIT channel to us.
+	///
+	/// Can be overridden per call via [`ReceiveOptions::lsp_fee_limits`]. Limits set on the
+	/// [`LspConfig`] apply in addition.
+	///
+	/// [`ReceiveOptions::lsp_fee_limits`]: crate::ReceiveOptions::lsp_fee_limits
+	pub lsp_fee_limits: LSPFeeLimits,
+	/// The most we allow to pay in routing fees when sending over lightning.
+	///
+	/// Can be overridden per call via [`SendOptions::routing_fee_limits`].
+	///
+	/// [`SendOptions::routing_fee_limits`]: crate::SendOptions::routing_fee_limits
+	pub routing_fee_limits: RoutingFeeLimits,
+	/// Limits on how much can be sent, enforced on every outbound payment.
+	pub spending_policy: SpendingPolicy,
+	/// Restrictions on where funds can be sent, enforced on every outbound payment.
+	///
+	/// Rejected payments are recorded in the audit log, see [`Romer::audit_log`]. Building a
+	/// [`Romer`] fails with [`Error::InvalidConfig`] if any of the listed addresses is invalid
+	/// for the network.
+	///
+	/// [`Romer::audit_log`]: crate::Romer::audit_log
+	/// [`Romer`]: crate::Romer
+	/// [`Error::InvalidConfig`]: crate::Error::InvalidConfig
+	pub destination_policy: DestinationPolicy,
+	/// Whether to record every send, receive, channel operation and configuration change in the
+	/// audit log, see [`Romer::audit_log`].
+	///
+	/// [`Romer::audit_log`]: crate::Romer::audit_log
+	pub audit_log: bool,
+	/// The fiat currency to record the value of payments in when they settle, e.g. `"USD"`.
+	///
+	/// Requires an exchange rate provider, see [`Romer::set_exchange_rate_provider`]. Invoices
+	/// requested via [`Romer::receive_fiat`] are valued in their own currency instead.
+	///
+	/// [`Romer::set_exchange_rate_provider`]: crate::Romer::set_exchange_rate_provider
+	/// [`Romer::receive_fiat`]: crate::Romer::receive_fiat
+	pub reporting_currency: Option<String>,
+}
+
+impl Default for Config {
+	fn default() -> Self {
+		Self {
+			lsps: Vec::new(),
+			lsp_fee_limits: no_fee_limits(),
+			routing_fee_limits: RoutingFeeLimits::default(),
+			spending_policy: SpendingPolicy::default(),
+			destination_policy: DestinationPolicy::default(),
+			audit_log: false,
+			reporting_currency: None,
+		}
+	}
+}
+
+impl Config {
+	/// The settings recorded in the audit log when they change, leaving out LSP tokens.
+	pub(crate) fn audit_parameters(&self) -> HashMap<String, String> {
+		let lsps: Vec<String> =
+			self.lsps.iter().map(|lsp| format!("{}@{}", lsp.node_id, lsp.address)).collect();
+		let mut parameters = HashMap::new();
+		parameters.insert("lsps".to_string(), lsps.join(","));
+		parameters.insert("lsp_fee_limits".to_string(), format!("{:?}", self.lsp_fee_limits));
+		parameters
+			.insert("routing_fee_limits".to_string(), format!("{:?}", self.routing_fee_limits));
+		parameters.insert("spending_policy".to_string(), format!("{:?}", self.spending_policy));
+		parameters
+			.insert("destination_policy".to_string(), format!("{:?}", self.destination_policy));
+		parameters.insert("audit_log".to_string(), self.audit_log.to_string());
+		parameters
+			.insert("reporting_currency".to_string(), format!("{:?}", self.reporting_currency));
+		parameters
+	}
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+/// The most we allow to pay in routing fees for a lightning payment.
+///
+/// If both limits are set, the lower one applies. LDK additionally never pays more than 1% plus
+/// 50 sats in routing fees.
+pub struct RoutingFeeLimits {
+	/// The most we allow to pay in total.
+	pub max_fee_msat: Option<u64>,
+	/// The most we allow to pay in proportion to the amount sent, in parts per million.
+	pub max_proportional_fee_ppm: Option<u64>,
+}
+
+impl RoutingFeeLimits {
+	/// The highest fee these limits allow when sending `amount_msat`, or `None` if they don't
+	/// set a limit.
+	pub(crate) fn max_fee_msat(&self, amount_msat: u64) -> Option<u64> {
+		let proportional_limit_msat = self
+			.max_proportional_fee_ppm
+			.map(|ppm| (amount_msat as u128 * ppm as u128 / 1_000_000) as u64);
+		self.max_fee_msat.into_iter().chain(proportional_limit_msat).min()
+	}
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// An [LSPS2]-compliant LSP that can open just-in-time channels to us.
+///
+/// [LSPS2]: https://github.com/BitcoinAndLightningLayerSpecs/lsp/blob/main/LSPS2/README.md
+pub struct LspConfig {
+	/// The node id of the LSP.
+	pub node_id: PublicKey,
+	/// The address we reach the LSP at.
+	pub address: SocketAddress,
+	/// The token used to authenticate with the LSP, if it requires one.
+	pub token: Option<String>,
+	/// The most we allow the LSP to charge for opening a channel to us.
+	pub fee_limits: LSPFeeLimits,
+}
+
+pub(crate) fn no_fee_limits() -> LSPFeeLimits {
+	LSPFeeLimits { max_total_opening_fee_msat: None, max_proportional_opening_fee_ppm_msat: None }
+}
+
+/// The highest total opening fee that satisfies all of the given limits when receiving
+/// `amount_msat`, or `None` if none of them sets a limit.
+pub(crate) fn max_total_opening_fee_msat(
+	limits: &[&LSPFeeLimits], amount_msat: u64,
+) -> Option<u64> {
+	limits
+		.iter()
+		.flat_map(|limits| {
+			let proportional_limit_msat = limits
+				.max_proportional_opening_fee_ppm_msat
+				.map(|ppm| (amount_msat as u128 * ppm as u128 / 1_000_000) as u64);
+			limits.max_total_opening_fee_msat.into_iter().chain(proportional_limit_msat)
+		})
+		.min()
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	fn limits(total: Option<u64>, ppm: Option<u64>) -> LSPFeeLimits {
+		LSPFeeLimits {
+			max_total_opening_fee_msat: total,
+			max_proportional_opening_fee_ppm_msat: ppm,
+		}
+	}
+
+	#[test]
+	fn no_limits() {
+		assert_eq!(max_total_opening_fee_msat(&[], 1_000_000), None);
+		assert_eq!(max_total_opening_fee_msat(&[&no_fee_limits()], 1_000_000), None);
+	}
+
+	#[test]
+	fn proportional_limit_scales_with_amount() {
+		let ppm = limits(None, Some(5_000));
+		assert_eq!(max_total_opening_fee_msat(&[&ppm], 10_000_000), Some(50_000));
+	}
+
+	#[test]
+	fn strictest_limit_wins() {
+		let wallet = limits(Some(40_000), None);
+		let lsp = limits(Some(100_000), Some(5_000));
+		assert_eq!(max_total_opening_fee_msat(&[&wallet, &lsp], 10_000_000), Some(40_000));
+		assert_eq!(max_total_opening_fee_msat(&[&wallet, &lsp], 1_000_000), Some(5_000));
+	}
+
+	#[test]
+	fn lower_routing_fee_limit_wins() {
+		assert_eq!(RoutingFeeLimits::default().max_fee_msat(1_000_000), None);
+
+		let limits =
+			RoutingFeeLimits { max_fee_msat: Some(20_000), max_proportional_fee_ppm: Some(5_000) };
+		assert_eq!(limits.max_fee_msat(1_000_000), Some(5_000));
+		assert_eq!(limits.max_fee_msat(10_000_000), Some(20_000));
+	}
+}
@@ -10,7 +10,7 @@ use std::fmt;
 
 use ldk_node::lightning::events::PaymentFailureReason;
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 /// An error that possibly needs to be handled by the user.
 pub enum Error {
 	/// Invalid API Token provided
@@ -25,16 +25,67 @@ pub enum Error {
 	InvalidOfferId,
 	/// Invalid payment id
 	InvalidPaymentId,
+	/// Invalid channel id
+	InvalidChannelId,
+	/// Invalid public key
+	InvalidPublicKey,
+	/// Invalid socket address
+	InvalidSocketAddress,
+	/// Invalid pagination cursor
+	InvalidCursor,
 	/// Invalid payment hash
 	InvalidPaymentHash,
 	/// Invalid payment preimage
 	InvalidPaymentPreimage,
 	/// Invalid payment secret
 	InvalidPaymentSecret,
+	/// Invalid amount
+	InvalidAmount,
+	/// The idempotency key was already used for a different payment or transfer
+	IdempotencyKeyConflict,
+	/// The payment is larger than the spending policy allows for a single payment
+	PaymentLimitExceeded,
+	/// The payment would exceed the daily limit of the spending policy
+	DailyLimitExceeded,
+	/// The payment would exceed the weekly limit of the spending policy
+	WeeklyLimitExceeded,
+	/// The payment has to be confirmed with a second factor
+	SecondFactorRequired,
+	/// The destination policy doesn't allow sending funds to the destination
+	DestinationNotAllowed,
+	/// The audit log was changed after the fact
+	AuditLogTampered,
+	/// The account id is empty, too long or contains characters other than letters, digits, `-`
+	/// and `_`
+	InvalidAccountId,
+	/// An account with the given id already exists
+	AccountAlreadyExists,
+	/// There is no account with the given id
+	UnknownAccount,
+	/// The account's balance is too low to cover the amount and routing fees
+	InsufficientAccountBalance,
+	/// No payment to a hold invoice with the given hash is waiting to be claimed
+	PaymentNotClaimable,
+	/// The given Lightning Address is invalid
+	InvalidLightningAddress,
+	/// The given LNURL is invalid, or the service's response doesn't follow LNURL-pay
+	InvalidLnurl,
+	/// A request to the LNURL service failed or the service reported an error
+	LnurlRequestFailed,
+	/// The comment is longer than the LNURL service allows
+	LnurlCommentTooLong,
+	/// The invoice returned by the LNURL service doesn't match the requested payment
+	LnurlInvoiceMismatch,
+	/// The config contains an invalid value, such as an invalid address in the destination policy
+	InvalidConfig,
 	/// Failed to build node
 	FailedToBuildNode,
+	/// Failed to persist data
+	PersistenceFailed,
 	/// Internal LDK Node error
 	LDKNodeError,
+	/// A payment for the invoice is already in flight or was already made.
+	DuplicatePayment,
 	/// The intended recipient rejected our payment.
 	RecipientRejected,
 	/// We exhausted all of our retry attempts while trying to send the payment.
@@ -47,6 +98,23 @@ pub enum Error {
 	/// routes - we tried the payment over a few routes but were not able to find any further
 	/// candidate routes beyond those.
 	RouteNotFound,
+	/// There are routes to the recipient, but none within our routing fee limits.
+	FeeLimitExceeded,
+	/// The node did not become ready in time.
+	NotReady,
+	/// The instance was stopped via [`Romer::stop`].
+	///
+	/// [`Romer::stop`]: crate::Romer::stop
+	Stopped,
+	/// The LSP failed to provide a JIT channel or didn't respond in time.
+	LspUnavailable,
+	/// The LSP charges more for opening a JIT channel than our fee limits allow.
+	LspFeeTooHigh,
+	/// The invoice was issued by our own node, but can't be settled internally, as it is a hold
+	/// invoice or one for a JIT channel.
+	InternalSettlementUnsupported,
+	/// No exchange rate is available for the requested currency.
+	ExchangeRateUnavailable,
 	/// Something unexpected happened.
 	UnexpectedError,
 }
@@ -60,11 +128,63 @@ impl fmt::Display for Error {
 			Self::InvalidBitcoinAddress => write!(f, "Invalid bitcoin address provided."),
 			Self::InvalidOfferId => write!(f, "Invalid offer id."),
 			Self::InvalidPaymentId => write!(f, "Invalid payment id."),
+			Self::InvalidChannelId => write!(f, "Invalid channel id."),
+			Self::InvalidPublicKey => write!(f, "Invalid public key provided."),
+			Self::InvalidSocketAddress => write!(f, "Invalid socket address provided."),
+			Self::InvalidCursor => write!(f, "Invalid pagination cursor provided."),
 			Self::InvalidPaymentHash => write!(f, "Invalid payment hash provided."),
 			Self::InvalidPaymentPreimage => write!(f, "Invalid payment preimage provided."),
 			Self::InvalidPaymentSecret => write!(f, "Invalid payment secret provided."),
+			Self::InvalidAmount => write!(f, "Invalid amount provided."),
+			Self::IdempotencyKeyConflict => {
+				write!(
+					f,
+					"The idempotency key was already used for a different payment or transfer."
+				)
+			},
+			Self::PaymentLimitExceeded => {
+				write!(f, "The payment is larger than the spending policy allows.")
+			},
+			Self::DailyLimitExceeded => {
+				write!(f, "The payment would exceed the daily spending limit.")
+			},
+			Self::WeeklyLimitExceeded => {
+				write!(f, "The payment would exceed the weekly spending limit.")
+			},
+			Self::SecondFactorRequired => {
+				write!(f, "The payment has to be confirmed with a second factor.")
+			},
+			Self::DestinationNotAllowed => {
+				write!(f, "The destination policy doesn't allow sending funds there.")
+			},
+			Self::AuditLogTampered => write!(f, "The audit log was changed after the fact."),
+			Self::InvalidAccountId => write!(f, "The given account id is invalid."),
+			Self::AccountAlreadyExists => write!(f, "An account with the given id already exists."),
+			Self::UnknownAccount => write!(f, "There is no account with the given id."),
+			Self::InsufficientAccountBalance => {
+				write!(f, "The account's balance is too low for this payment.")
+			},
+			Self::PaymentNotClaimable => {
+				write!(f, "No payment to the hold invoice is waiting to be claimed.")
+			},
+			Self::InvalidLightningAddress => write!(f, "The given Lightning Address is invalid."),
+			Self::InvalidLnurl => {
+				write!(f, "The given LNURL or the service's response is invalid.")
+			},
+			Self::LnurlRequestFailed => write!(f, "The request to the LNURL service failed."),
+			Self::LnurlCommentTooLong => {
+				write!(f, "The comment is longer than the LNURL service allows.")
+			},
+			Self::LnurlInvoiceMismatch => {
+				write!(f, "The LNURL service returned an invoice that doesn't match the payment.")
+			},
+			Self::InvalidConfig => write!(f, "The given config contains an invalid value."),
 			Self::FailedToBuildNode => write!(f, "Failed to build node"),
+			Self::PersistenceFailed => write!(f, "Failed to persist data."),
 			Self::LDKNodeError => write!(f, "Internal LDK Node error."),
+			Self::DuplicatePayment => {
+				write!(f, "A payment for the invoice is already in flight or was already made.")
+			},
 			Self::RecipientRejected => write!(f, "The intendfed recipient rejected the payment."),
 			Self::RetriesExhausted => write!(
 				f,
@@ -74,6 +194,23 @@ impl fmt::Display for Error {
 			Self::RouteNotFound => {
 				write!(f, "We failed to find a route while retrying the payment.")
 			},
+			Self::FeeLimitExceeded => {
+				write!(f, "No route to the recipient is within our routing fee limits.")
+			},
+			Self::NotReady => write!(f, "The node did not become ready in time."),
+			Self::Stopped => write!(f, "The instance was stopped."),
+			Self::LspUnavailable => {
+				write!(f, "The LSP failed to provide a JIT channel or didn't respond in time.")
+			},
+			Self::LspFeeTooHigh => {
+				write!(f, "The LSP charges more for opening a JIT channel than our limits allow.")
+			},
+			Self::InternalSettlementUnsupported => {
+				write!(f, "Hold and JIT channel invoices of our own node can't be paid.")
+			},
+			Self::ExchangeRateUnavailable => {
+				write!(f, "No exchange rate is available for the requested currency.")
+			},
 			Self::UnexpectedError => write!(f, "Something unexpected happened."),
 		}
 	}
@@ -88,8 +225,13 @@ impl From<ldk_node::BuildError> for Error {
 }
 
 impl From<ldk_node::NodeError> for Error {
-	fn from(_value: ldk_node::NodeError) -> Self {
-		Error::LDKNodeError
+	fn from(value: ldk_node::NodeError) -> Self {
+		match value {
+			ldk_node::NodeError::DuplicatePayment => Error::DuplicatePayment,
+			ldk_node::NodeError::InvalidPaymentHash => Error::InvalidPaymentHash,
+			ldk_node::NodeError::InvalidPaymentPreimage => Error::InvalidPaymentPreimage,
+			_ => Error::LDKNodeError,
+		}
 	}
 }
 
@@ -101,7 +243,10 @@ impl From<PaymentFailureReason> for Error {
 			PaymentFailureReason::RetriesExhausted => Error::RetriesExhausted,
 			PaymentFailureReason::PaymentExpired => Error::PaymentExpired,
 			PaymentFailureReason::RouteNotFound => Error::RouteNotFound,
-			PaymentFailureReason::UnexpectedError => Error::UnexpectedError,
+			PaymentFailureReason::UnexpectedError
+			| PaymentFailureReason::UnknownRequiredFeatures
+			| PaymentFailureReason::InvoiceRequestExpired
+			| PaymentFailureReason::InvoiceRequestRejected => Error::UnexpectedError,
 		}
 	}
 }
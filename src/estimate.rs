@@ -0,0 +1,513 @@
+// This file is Copyright its original authors, visible in version contror
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+use std::io::Cursor;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use ldk_node::bitcoin::secp256k1::PublicKey;
+use ldk_node::bitcoin::Network;
+use ldk_node::io::sqlite_store::SqliteStore;
+use ldk_node::lightning::ln::bolt11_payment::payment_parameters_from_invoice;
+use ldk_node::lightning::ln::channel_state::{
+	ChannelCounterparty, ChannelDetails as LdkChannelDetails,
+};
+use ldk_node::lightning::ln::channelmanager::provided_init_features;
+use ldk_node::lightning::routing::gossip::{NetworkGraph, NodeId};
+use ldk_node::lightning::routing::router::{find_route, Path, Route, RouteParameters};
+use ldk_node::lightning::routing::scoring::{
+	ProbabilisticScorer, ProbabilisticScoringDecayParameters, ProbabilisticScoringFeeParameters,
+};
+use ldk_node::lightning::util::config::UserConfig;
+use ldk_node::lightning::util::logger::{Logger, Record};
+use ldk_node::lightning::util::persist::{
+	KVStore, NETWORK_GRAPH_PERSISTENCE_KEY, NETWORK_GRAPH_PERSISTENCE_PRIMARY_NAMESPACE,
+	NETWORK_GRAPH_PERSISTENCE_SECONDARY_NAMESPACE, SCORER_PERSISTENCE_KEY,
+	SCORER_PERSISTENCE_PRIMARY_NAMESPACE, SCORER_PERSISTENCE_SECONDARY_NAMESPACE,
+};
+use ldk_node::lightning::util::ser::ReadableArgs;
+use ldk_node::lightning_invoice::Bolt11Invoice;
+use ldk_node::ChannelDetails;
+
+/// How many alternative routes we consider, as LDK retries a failed payment over other routes.
+const MAX_ROUTES: usize = 3;
+
+/// How long we reuse the network graph and scorer read from the store.
+///
+/// LDK Node persists both about once an hour, so reading them more often doesn't gain much.
+const ROUTING_DATA_MAX_AGE: Duration = Duration::from_secs(10 * 60);
+
+type Graph = NetworkGraph<Arc<NoopLogger>>;
+type Scorer = ProbabilisticScorer<Arc<Graph>, Arc<NoopLogger>>;
+
+#[derive(Debug, Clone, PartialEq)]
+/// The expected cost of paying an invoice over lightning.
+pub struct FeeEstimate {
+	/// The amount the recipient will receive.
+	pub amount_msat: u64,
+	/// The routing fee of the cheapest route we found.
+	pub min_fee_msat: u64,
+	/// The routing fee of the most expensive route we'd fall back to if cheaper ones fail.
+	pub max_fee_msat: u64,
+	/// How likely it is that the payment succeeds over one of the routes, from 0 to 1.
+	pub success_probability: f64,
+	/// How many routes the estimate is based on.
+	pub route_count: u32,
+}
+
+/// LDK's router logs every candidate it looks at, which we don't need for an estimate.
+struct NoopLogger;
+
+impl Logger for NoopLogger {
+	fn log(&self, _record: Record) {}
+}
+
+/// Estimates routing fees with LDK's own router and what LDK Node's scorer has learned about the
+/// liquidity of channels, so the routes match the ones LDK would pay over.
+pub(crate) struct RouteEstimator {
+	network: Network,
+	kv_store: Arc<SqliteStore>,
+	routing_data: Mutex<Option<(Instant, Arc<RoutingData>)>>,
+}
+
+impl RouteEstimator {
+	pub(crate) fn new(network: Network, kv_store: Arc<SqliteStore>) -> Self {
+		Self { network, kv_store, routing_data: Mutex::new(None) }
+	}
+
+	/// Estimates the fee for paying `invoice` from `payer` over its `channels`.
+	///
+	/// Considers the cheapest few routes whose fee is within `fee_budget_msat`, each avoiding the
+	/// channel least likely to forward the payment on the previous one, like LDK does on retries.
+	pub(crate) fn estimate(
+		&self, payer: PublicKey, channels: &[ChannelDetails], invoice: &Bolt11Invoice,
+		fee_budget_msat: u64,
+	) -> Option<FeeEstimate> {
+		self.routing_data().estimate(payer, channels, invoice, fee_budget_msat)
+	}
+
+	/// Sends probes via `send_probes`, then forgets the routing data we read so the next estimate
+	/// picks up what LDK's scorer learned from them once LDK Node persisted it.
+	pub(crate) fn probe<E>(&self, send_probes: impl FnOnce() -> Result<(), E>) -> Result<(), E> {
+		send_probes()?;
+		*self.routing_data.lock().unwrap() = None;
+		Ok(())
+	}
+
+	fn routing_data(&self) -> Arc<RoutingData> {
+		let mut routing_data = self.routing_data.lock().unwrap();
+		match &*routing_data {
+			Some((loaded_at, data)) if loaded_at.elapsed() < ROUTING_DATA_MAX_AGE => {
+				Arc::clone(data)
+			},
+			_ => {
+				let data = Arc::new(RoutingData::read(self.network, &self.kv_store));
+				*routing_data = Some((Instant::now(), Arc::clone(&data)));
+				data
+			},
+		}
+	}
+}
+
+/// A snapshot of the network graph and of the scorer's view of channel liquidity.
+struct RoutingData {
+	network_graph: Arc<Graph>,
+	scorer: Scorer,
+}
+
+impl RoutingData {
+	/// Reads the network graph and scorer LDK Node persisted, starting from scratch if it hasn't
+	/// persisted them yet.
+	fn read(network: Network, kv_store: &SqliteStore) -> Self {
+		let logger = Arc::new(NoopLogger);
+		let network_graph = kv_store
+			.read(
+				NETWORK_GRAPH_PERSISTENCE_PRIMARY_NAMESPACE,
+				NETWORK_GRAPH_PERSISTENCE_SECONDARY_NAMESPACE,
+				NETWORK_GRAPH_PERSISTENCE_KEY,
+			)
+			.ok()
+			.and_then(|bytes| Graph::read(&mut Cursor::new(bytes), Arc::clone(&logger)).ok())
+			.unwrap_or_else(|| Graph::new(network, Arc::clone(&logger)));
+		let network_graph = Arc::new(network_graph);
+
+		let decay_params = ProbabilisticScoringDecayParameters::default();
+		let scorer = kv_store
+			.read(
+				SCORER_PERSISTENCE_PRIMARY_NAMESPACE,
+				SCORER_PERSISTENCE_SECONDARY_NAMESPACE,
+				SCORER_PERSISTENCE_KEY,
+			)
+			.ok()
+			.and_then(|bytes| {
+				let args = (decay_params, Arc::clone(&network_graph), Arc::clone(&logger));
+				Scorer::read(&mut Cursor::new(bytes), args).ok()
+			})
+			.unwrap_or_else(|| {
+				Scorer::new(decay_params, Arc::clone(&network_graph), Arc::clone(&logger))
+			});
+
+		Self { network_graph, scorer }
+	}
+
+	fn estimate(
+		&self, payer: PublicKey, channels: &[ChannelDetails], invoice: &Bolt11Invoice,
+		fee_budget_msat: u64,
+	) -> Option<FeeEstimate> {
+		let (_, _, mut route_params) = payment_parameters_from_invoice(invoice).ok()?;
+		route_params.max_total_routing_fee_msat = Some(fee_budget_msat);
+
+		let first_hops = first_hops(channels);
+		let first_hops: Vec<&LdkChannelDetails> = first_hops.iter().collect();
+
+		let mut routes: Vec<(u64, f64)> = Vec::new();
+		while routes.len() < MAX_ROUTES {
+			let route = match self.find_route(payer, &route_params, &first_hops) {
+				Some(route) => route,
+				None => break,
+			};
+			let (success_probability, bottleneck) = self.route_success_probability(&route);
+			routes.push((route.get_total_fees(), success_probability));
+			match bottleneck {
+				Some(short_channel_id) => {
+					route_params.payment_params.previously_failed_channels.push(short_channel_id)
+				},
+				// The route only uses our own channels, so there's nothing to fall back from.
+				None => break,
+			}
+		}
+
+		let min_fee_msat = routes.iter().map(|(fee_msat, _)| *fee_msat).min()?;
+		let failure_probability: f64 =
+			routes.iter().map(|(_, success_probability)| 1.0 - success_probability).product();
+		Some(FeeEstimate {
+			amount_msat: route_params.final_value_msat,
+			min_fee_msat,
+			max_fee_msat: routes.iter().map(|(fee_msat, _)| *fee_msat).max().unwrap_or(0),
+			success_probability: 1.0 - failure_probability,
+			route_count: routes.len() as u32,
+		})
+	}
+
+	fn find_route(
+		&self, payer: PublicKey, route_params: &RouteParameters, first_hops: &[&LdkChannelDetails],
+	) -> Option<Route> {
+		// The seed only randomizes the CLTV offsets of the route, which don't affect the fee.
+		let random_seed_bytes = [0; 32];
+		find_route(
+			&payer,
+			route_params,
+			&self.network_graph,
+			Some(first_hops),
+			Arc::new(NoopLogger),
+			&self.scorer,
+			&ProbabilisticScoringFeeParameters::default(),
+			&random_seed_bytes,
+		)
+		.ok()
+	}
+
+	/// The chance that all paths of `route` succeed, along with the channel least likely to
+	/// forward its share of the payment.
+	fn route_success_probability(&self, route: &Route) -> (f64, Option<u64>) {
+		let mut success_probability = 1.0;
+		let mut bottleneck: Option<(f64, u64)> = None;
+		for path in &route.paths {
+			// The first hop is our own channel, whose liquidity the router already accounted for.
+			for (index, hop) in path.hops.iter().enumerate().skip(1) {
+				let hop_probability = self.hop_success_probability(
+					hop.short_channel_id,
+					&NodeId::from_pubkey(&hop.pubkey),
+					forwarded_msat(path, index),
+				);
+				success_probability *= hop_probability;
+				if !matches!(bottleneck, Some((lowest, _)) if lowest <= hop_probability) {
+					bottleneck = Some((hop_probability, hop.short_channel_id));
+				}
+			}
+		}
+		(success_probability, bottleneck.map(|(_, short_channel_id)| short_channel_id))
+	}
+
+	/// The chance the channel can forward `amount_msat` to `target`.
+	///
+	/// Prefers the scorer's history of the channel, then its current liquidity bounds or, if it
+	/// hasn't learned any yet, the channel's capacity, assuming liquidity is distributed uniformly
+	/// within them. Channels LDK knows nothing about, such as the private ones hinted at by the
+	/// invoice, don't lower the probability.
+	fn hop_success_probability(
+		&self, short_channel_id: u64, target: &NodeId, amount_msat: u64,
+	) -> f64 {
+		let params = ProbabilisticScoringFeeParameters::default();
+		if let Some(probability) = self.scorer.historical_estimated_payment_success_probability(
+			short_channel_id,
+			target,
+			amount_msat,
+			&params,
+		) {
+			return probability;
+		}
+
+		let liquidity_range =
+			self.scorer.estimated_channel_liquidity_range(short_channel_id, target).or_else(|| {
+				let graph = self.network_graph.read_only();
+				let (channel, _) = graph.channel(short_channel_id)?.as_directed_to(target)?;
+				Some((0, channel.effective_capacity().as_msat()))
+			});
+		match liquidity_range {
+			Some((min_msat, _)) if amount_msat <= min_msat => 1.0,
+			Some((_, max_msat)) if amount_msat > max_msat => 0.0,
+			Some((min_msat, max_msat)) => {
+				(max_msat - amount_msat + 1) as f64 / (max_msat - min_msat + 1) as f64
+			},
+			None => 1.0,
+		}
+	}
+}
+
+/// The amount the hop at `index` of `path` has to forward, including the fees of later hops.
+fn forwarded_msat(path: &Path, index: usize) -> u64 {
+	path.hops[index..].iter().map(|hop| hop.fee_msat).sum()
+}
+
+/// Describes our usable channels the way LDK's router expects its first hops.
+fn first_hops(channels: &[ChannelDetails]) -> Vec<LdkChannelDetails> {
+	let features = provided_init_features(&UserConfig::default());
+	channels
+		.iter()
+		.filter(|channel| channel.is_usable)
+		.enumerate()
+		.map(|(index, channel)| {
+			// LDK Node doesn't tell us the short channel ids of our channels, but the router only
+			// needs them to tell first hops apart. Ids this high would be from a block height the
+			// chain won't reach, so they don't clash with the network graph.
+			let short_channel_id = u64::MAX - index as u64;
+			#[allow(deprecated)]
+			LdkChannelDetails {
+				channel_id: channel.channel_id,
+				counterparty: ChannelCounterparty {
+					node_id: channel.counterparty_node_id,
+					features: features.clone(),
+					unspendable_punishment_reserve: channel
+						.counterparty_unspendable_punishment_reserve,
+					forwarding_info: None,
+					outbound_htlc_minimum_msat: channel.counterparty_outbound_htlc_minimum_msat,
+					outbound_htlc_maximum_msat: channel.counterparty_outbound_htlc_maximum_msat,
+				},
+				funding_txo: None,
+				channel_type: None,
+				short_channel_id: Some(short_channel_id),
+				outbound_scid_alias: None,
+				inbound_scid_alias: None,
+				channel_value_satoshis: channel.channel_value_sats,
+				unspendable_punishment_reserve: channel.unspendable_punishment_reserve,
+				user_channel_id: channel.user_channel_id.0,
+				feerate_sat_per_1000_weight: Some(channel.feerate_sat_per_1000_weight),
+				balance_msat: channel.outbound_capacity_msat,
+				outbound_capacity_msat: channel.outbound_capacity_msat,
+				next_outbound_htlc_limit_msat: channel.next_outbound_htlc_limit_msat,
+				next_outbound_htlc_minimum_msat: channel.next_outbound_htlc_minimum_msat,
+				inbound_capacity_msat: channel.inbound_capacity_msat,
+				confirmations_required: channel.confirmations_required,
+				confirmations: channel.confirmations,
+				force_close_spend_delay: channel.force_close_spend_delay,
+				is_outbound: channel.is_outbound,
+				is_channel_ready: channel.is_channel_ready,
+				channel_shutdown_state: None,
+				is_usable: channel.is_usable,
+				is_announced: channel.is_announced,
+				inbound_htlc_minimum_msat: Some(channel.inbound_htlc_minimum_msat),
+				inbound_htlc_maximum_msat: channel.inbound_htlc_maximum_msat,
+				config: None,
+				pending_inbound_htlcs: Vec::new(),
+				pending_outbound_htlcs: Vec::new(),
+			}
+		})
+		.collect()
+}
+
+#[cfg(test)]
+mod test {
+	use std::time::{SystemTime, UNIX_EPOCH};
+
+	use ldk_node::bitcoin::constants::ChainHash;
+	use ldk_node::bitcoin::hashes::sha256::Hash as Sha256;
+	use ldk_node::bitcoin::hashes::Hash;
+	use ldk_node::bitcoin::secp256k1::{Secp256k1, SecretKey};
+	use ldk_node::config::ChannelConfig;
+	use ldk_node::lightning::ln::features::ChannelFeatures;
+	use ldk_node::lightning::ln::msgs::UnsignedChannelUpdate;
+	use ldk_node::lightning::ln::types::ChannelId;
+	use ldk_node::lightning::ln::PaymentSecret;
+	use ldk_node::lightning_invoice::{Currency, InvoiceBuilder};
+	use ldk_node::UserChannelId;
+
+	use super::*;
+	use crate::store;
+
+	const PAYEE: u8 = 9;
+
+	fn key(byte: u8) -> SecretKey {
+		SecretKey::from_slice(&[byte + 1; 32]).unwrap()
+	}
+
+	fn node(byte: u8) -> PublicKey {
+		PublicKey::from_secret_key(&Secp256k1::new(), &key(byte))
+	}
+
+	/// A routing data snapshot of public channels from `source` to `target`, each forwarding at
+	/// most `htlc_maximum_msat` for a base fee of `base_msat` plus 0.1%.
+	fn routing_data(channels: &[(u64, u8, u8, u32, u64)]) -> RoutingData {
+		let logger = Arc::new(NoopLogger);
+		let network_graph = Arc::new(Graph::new(Network::Regtest, Arc::clone(&logger)));
+		let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+		for &(short_channel_id, source, target, base_msat, htlc_maximum_msat) in channels {
+			network_graph
+				.add_channel_from_partial_announcement(
+					short_channel_id,
+					timestamp,
+					ChannelFeatures::empty(),
+					node(source),
+					node(target),
+				)
+				.unwrap();
+			// LDK only routes over channels with updates for both directions.
+			for channel_flags in [0, 1] {
+				network_graph
+					.update_channel_unsigned(&UnsignedChannelUpdate {
+						chain_hash: ChainHash::using_genesis_block(Network::Regtest),
+						short_channel_id,
+						timestamp: timestamp as u32,
+						message_flags: 1,
+						channel_flags,
+						cltv_expiry_delta: 40,
+						htlc_minimum_msat: 1,
+						htlc_maximum_msat,
+						fee_base_msat: base_msat,
+						fee_proportional_millionths: 1_000,
+						excess_data: Vec::new(),
+					})
+					.unwrap();
+			}
+		}
+		let scorer = Scorer::new(
+			ProbabilisticScoringDecayParameters::default(),
+			Arc::clone(&network_graph),
+			logger,
+		);
+		RoutingData { network_graph, scorer }
+	}
+
+	/// Our usable channel to `counterparty`.
+	fn channel(counterparty: u8, outbound_capacity_msat: u64) -> ChannelDetails {
+		ChannelDetails {
+			channel_id: ChannelId([counterparty; 32]),
+			counterparty_node_id: node(counterparty),
+			funding_txo: None,
+			channel_value_sats: outbound_capacity_msat / 1000 * 2,
+			unspendable_punishment_reserve: Some(10_000),
+			user_channel_id: UserChannelId(counterparty as u128),
+			outbound_capacity_msat,
+			inbound_capacity_msat: outbound_capacity_msat,
+			confirmations_required: None,
+			confirmations: None,
+			is_outbound: true,
+			feerate_sat_per_1000_weight: 1_000,
+			is_channel_ready: true,
+			is_usable: true,
+			is_announced: false,
+			cltv_expiry_delta: None,
+			counterparty_unspendable_punishment_reserve: 10_000,
+			counterparty_outbound_htlc_minimum_msat: None,
+			counterparty_outbound_htlc_maximum_msat: None,
+			counterparty_forwarding_info_fee_base_msat: None,
+			counterparty_forwarding_info_fee_proportional_millionths: None,
+			counterparty_forwarding_info_cltv_expiry_delta: None,
+			next_outbound_htlc_limit_msat: outbound_capacity_msat,
+			next_outbound_htlc_minimum_msat: 1,
+			force_close_spend_delay: None,
+			inbound_htlc_minimum_msat: 1,
+			inbound_htlc_maximum_msat: None,
+			config: ChannelConfig::default(),
+		}
+	}
+
+	fn invoice(amount_msat: u64) -> Bolt11Invoice {
+		let secp = Secp256k1::new();
+		InvoiceBuilder::new(Currency::Regtest)
+			.description("Coffee".to_string())
+			.payment_hash(Sha256::from_slice(&[1; 32]).unwrap())
+			.payment_secret(PaymentSecret([2; 32]))
+			.current_timestamp()
+			.min_final_cltv_expiry_delta(144)
+			.amount_milli_satoshis(amount_msat)
+			.build_signed(|hash| secp.sign_ecdsa_recoverable(hash, &key(PAYEE)))
+			.unwrap()
+	}
+
+	#[test]
+	fn estimates_fee_range_over_alternative_routes() {
+		// Two routes via our peer 1: through 2 (cheap but small) and through 3 (pricier but large).
+		let data = routing_data(&[
+			(1, 1, 2, 1_000, 20_000_000),
+			(2, 2, PAYEE, 1_000, 20_000_000),
+			(3, 1, 3, 5_000, 100_000_000),
+			(4, 3, PAYEE, 5_000, 100_000_000),
+		]);
+		let channels = [channel(1, 500_000_000)];
+
+		let estimate = data.estimate(node(0), &channels, &invoice(10_000_000), 150_000).unwrap();
+		assert_eq!(estimate.amount_msat, 10_000_000);
+		assert_eq!(estimate.route_count, 2);
+		// 2 charges 1 sat + 0.1% of 10k sats, 1 charges the same on top of that.
+		assert_eq!(estimate.min_fee_msat, 11_000 + 11_011);
+		assert_eq!(estimate.max_fee_msat, 15_000 + 15_015);
+		// The cheap route likely lacks liquidity, but the large one is likely to work.
+		assert!(estimate.success_probability > 0.85 && estimate.success_probability < 0.86);
+
+		// Only the cheap route fits a tighter budget.
+		let estimate = data.estimate(node(0), &channels, &invoice(10_000_000), 25_000).unwrap();
+		assert_eq!(estimate.min_fee_msat, 11_000 + 11_011);
+		assert_eq!(estimate.route_count, 1);
+	}
+
+	#[test]
+	fn skips_routes_over_fee_budget() {
+		let data = routing_data(&[(1, 1, PAYEE, 100_000, 100_000_000)]);
+		let channels = [channel(1, 500_000_000)];
+		assert_eq!(data.estimate(node(0), &channels, &invoice(1_000_000), 60_000), None);
+		let estimate = data.estimate(node(0), &channels, &invoice(1_000_000), 101_000).unwrap();
+		assert_eq!(estimate.min_fee_msat, 101_000);
+
+		// Paying our peer directly is free and certain.
+		let channels = [channel(PAYEE, 500_000_000)];
+		let estimate = data.estimate(node(0), &channels, &invoice(1_000_000), 0).unwrap();
+		assert_eq!(estimate.min_fee_msat, 0);
+		assert_eq!(estimate.success_probability, 1.0);
+	}
+
+	#[test]
+	fn probing_rereads_routing_data() {
+		let estimator = RouteEstimator::new(Network::Regtest, store::test_store("estimate-probe"));
+		let data = estimator.routing_data();
+		assert!(Arc::ptr_eq(&data, &estimator.routing_data()));
+
+		assert_eq!(estimator.probe(|| Err("no route")), Err("no route"));
+		assert!(Arc::ptr_eq(&data, &estimator.routing_data()));
+
+		let mut probed = false;
+		estimator
+			.probe(|| {
+				probed = true;
+				Ok::<(), ()>(())
+			})
+			.unwrap();
+		assert!(probed);
+		assert!(!Arc::ptr_eq(&data, &estimator.routing_data()));
+	}
+}
@@ -0,0 +1,132 @@
+// This file is Copyright its original authors, visible in version contror
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+use std::collections::VecDeque;
+use std::sync::{Condvar, Mutex};
+
+use ldk_node::bitcoin::secp256k1::PublicKey;
+use ldk_node::lightning::ln::PaymentHash;
+
+/// How many unhandled events we keep around before dropping the oldest ones.
+///
+/// [`Event::PaymentClaimable`] is never dropped, as the payment has to be decided on.
+const MAX_QUEUED_EVENTS: usize = 1000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// An event emitted by [`Romer`], which may be handled by the user.
+///
+/// [`Romer`]: crate::Romer
+pub enum Event {
+	/// We established a peer connection with the LSP.
+	LspConnected {
+		/// The node id of the LSP.
+		node_id: PublicKey,
+	},
+	/// We lost our peer connection with the LSP.
+	///
+	/// We'll keep trying to reconnect in the background.
+	LspDisconnected {
+		/// The node id of the LSP.
+		node_id: PublicKey,
+	},
+	/// A payment to a hold invoice arrived and can be claimed via [`Romer::claim`] or cancelled
+	/// via [`Romer::cancel`].
+	///
+	/// Payments nobody decided on are cancelled a few blocks before `claim_deadline`. Those still
+	/// waiting are also listed by [`Romer::list_claimable`].
+	///
+	/// [`Romer::claim`]: crate::Romer::claim
+	/// [`Romer::cancel`]: crate::Romer::cancel
+	/// [`Romer::list_claimable`]: crate::Romer::list_claimable
+	PaymentClaimable {
+		/// The hash the hold invoice was created for.
+		payment_hash: PaymentHash,
+		/// The amount that will be received when claiming the payment.
+		claimable_amount_msat: u64,
+		/// The block height at which the payment can no longer be claimed, if known.
+		claim_deadline: Option<u32>,
+	},
+}
+
+pub(crate) struct EventQueue {
+	queue: Mutex<VecDeque<Event>>,
+	notifier: Condvar,
+}
+
+impl EventQueue {
+	pub(crate) fn new() -> Self {
+		Self { queue: Mutex::new(VecDeque::new()), notifier: Condvar::new() }
+	}
+
+	pub(crate) fn add_event(&self, event: Event) {
+		{
+			let mut queue = self.queue.lock().unwrap();
+			if queue.len() >= MAX_QUEUED_EVENTS {
+				let droppable =
+					queue.iter().position(|event| !matches!(event, Event::PaymentClaimable { .. }));
+				if let Some(index) = droppable {
+					queue.remove(index);
+				}
+			}
+			queue.push_back(event);
+		}
+		self.notifier.notify_one();
+	}
+
+	pub(crate) fn next_event(&self) -> Option<Event> {
+		self.queue.lock().unwrap().pop_front()
+	}
+
+	pub(crate) fn wait_next_event(&self) -> Event {
+		let mut queue =
+			self.notifier.wait_while(self.queue.lock().unwrap(), |queue| queue.is_empty()).unwrap();
+		queue.pop_front().expect("queue is not empty")
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use ldk_node::bitcoin::secp256k1::{Secp256k1, SecretKey};
+
+	use super::*;
+
+	fn claimable(byte: u8) -> Event {
+		Event::PaymentClaimable {
+			payment_hash: PaymentHash([byte; 32]),
+			claimable_amount_msat: 1_000,
+			claim_deadline: None,
+		}
+	}
+
+	#[test]
+	fn never_drops_claimable_payments() {
+		let node_id = PublicKey::from_secret_key(
+			&Secp256k1::new(),
+			&SecretKey::from_slice(&[1; 32]).unwrap(),
+		);
+		let queue = EventQueue::new();
+		queue.add_event(claimable(0));
+		queue.add_event(Event::LspConnected { node_id });
+		for _ in 2..MAX_QUEUED_EVENTS {
+			queue.add_event(Event::LspDisconnected { node_id });
+		}
+
+		// The oldest event that isn't a claimable payment makes room.
+		queue.add_event(claimable(1));
+		assert_eq!(queue.next_event(), Some(claimable(0)));
+		assert_eq!(queue.next_event(), Some(Event::LspDisconnected { node_id }));
+
+		// With only claimable payments left, the queue grows past its limit.
+		let queue = EventQueue::new();
+		for byte in 0..=MAX_QUEUED_EVENTS {
+			queue.add_event(claimable(byte as u8));
+		}
+		assert_eq!(queue.queue.lock().unwrap().len(), MAX_QUEUED_EVENTS + 1);
+		assert_eq!(queue.next_event(), Some(claimable(0)));
+	}
+}
@@ -0,0 +1,288 @@
+// This file is Copyright its original authors, visible in version contror
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+use ldk_node::bitcoin::hashes::Hash;
+use ldk_node::bitcoin::Txid;
+use ldk_node::payment::{PaymentDirection, PaymentKind, PaymentStatus};
+use serde_json::{json, Value};
+
+use crate::hex_utils;
+use crate::ledger::LedgerEntry;
+use crate::payments::{self, Payment};
+
+const COLUMNS: &[&str] =
+	&["timestamp", "direction", "kind", "amount_msat", "fee_msat", "status", "reference", "label"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// The format of a payment export.
+pub enum ExportFormat {
+	/// Comma-separated values with a header row.
+	Csv,
+	/// A JSON array with one object per payment.
+	Json,
+}
+
+/// A payment flattened into the columns of an export.
+struct Row {
+	timestamp: u64,
+	direction: &'static str,
+	kind: &'static str,
+	amount_msat: Option<u64>,
+	fee_msat: Option<u64>,
+	status: &'static str,
+	/// The payment hash of a lightning payment, or the txid of an on-chain one we sent.
+	reference: Option<String>,
+	label: Option<String>,
+}
+
+impl Row {
+	fn new(payment: &Payment) -> Self {
+		let details = &payment.details;
+		let direction = match details.direction {
+			PaymentDirection::Inbound => "inbound",
+			PaymentDirection::Outbound => "outbound",
+		};
+		let kind = match details.kind {
+			PaymentKind::Onchain => "onchain",
+			PaymentKind::Bolt11 { .. } => "bolt11",
+			PaymentKind::Bolt11Jit { .. } => "bolt11_jit",
+			PaymentKind::Bolt12Offer { .. } => "bolt12_offer",
+			PaymentKind::Bolt12Refund { .. } => "bolt12_refund",
+			PaymentKind::Spontaneous { .. } => "spontaneous",
+		};
+		let status = match details.status {
+			PaymentStatus::Pending => "pending",
+			PaymentStatus::Succeeded => "succeeded",
+			PaymentStatus::Failed => "failed",
+		};
+		let reference = match details.kind {
+			// On-chain payments are keyed by their txid.
+			PaymentKind::Onchain => Some(Txid::from_byte_array(details.id.0).to_string()),
+			_ => payments::payment_hash(&details.kind).map(|hash| hex_utils::to_string(&hash.0)),
+		};
+
+		Row {
+			timestamp: details.latest_update_timestamp,
+			direction,
+			kind,
+			amount_msat: details.amount_msat,
+			fee_msat: payment.fee_paid_msat,
+			status,
+			reference,
+			label: payment.label.clone(),
+		}
+	}
+
+	/// An on-chain payment we received, which we only know of from the deposit it booked in our
+	/// ledger.
+	fn deposit(entry: &LedgerEntry) -> Self {
+		Row {
+			timestamp: entry.timestamp,
+			direction: "inbound",
+			kind: "onchain",
+			amount_msat: Some(entry.amount_msat),
+			fee_msat: None,
+			status: "succeeded",
+			reference: None,
+			label: None,
+		}
+	}
+}
+
+/// Renders the given payments and on-chain deposits, oldest first, in the given format.
+///
+/// LDK Node doesn't track the on-chain payments we receive, so they are exported from the
+/// [`LedgerEntryKind::OnchainDeposit`] entries of our ledger, without a txid.
+///
+/// [`LedgerEntryKind::OnchainDeposit`]: crate::LedgerEntryKind::OnchainDeposit
+pub(crate) fn export(
+	payments: Vec<Payment>, deposits: Vec<LedgerEntry>, format: ExportFormat,
+) -> String {
+	let mut rows: Vec<Row> =
+		payments.iter().map(Row::new).chain(deposits.iter().map(Row::deposit)).collect();
+	rows.sort_by(|a, b| (a.timestamp, &a.reference).cmp(&(b.timestamp, &b.reference)));
+	let rows = rows.into_iter();
+	match format {
+		ExportFormat::Csv => to_csv(rows),
+		ExportFormat::Json => to_json(rows),
+	}
+}
+
+fn to_csv(rows: impl Iterator<Item = Row>) -> String {
+	let mut out = COLUMNS.join(",");
+	out.push('\n');
+	for row in rows {
+		let fields = [
+			row.timestamp.to_string(),
+			row.direction.to_string(),
+			row.kind.to_string(),
+			row.amount_msat.map(|a| a.to_string()).unwrap_or_default(),
+			row.fee_msat.map(|f| f.to_string()).unwrap_or_default(),
+			row.status.to_string(),
+			row.reference.unwrap_or_default(),
+			row.label.map(|label| csv_field(&label)).unwrap_or_default(),
+		];
+		out.push_str(&fields.join(","));
+		out.push('\n');
+	}
+	out
+}
+
+fn csv_field(value: &str) -> String {
+	if value.contains([',', '"', '\n', '\r']) {
+		format!("\"{}\"", value.replace('"', "\"\""))
+	} else {
+		value.to_string()
+	}
+}
+
+fn to_json(rows: impl Iterator<Item = Row>) -> String {
+	let objects: Vec<Value> = rows
+		.map(|row| {
+			json!({
+				"timestamp": row.timestamp,
+				"direction": row.direction,
+				"kind": row.kind,
+				"amount_msat": row.amount_msat,
+				"fee_msat": row.fee_msat,
+				"status": row.status,
+				"reference": row.reference,
+				"label": row.label,
+			})
+		})
+		.collect();
+	Value::Array(objects).to_string()
+}
+
+#[cfg(test)]
+mod test {
+	use ldk_node::lightning::ln::channelmanager::PaymentId;
+	use ldk_node::lightning::ln::PaymentHash;
+	use ldk_node::payment::PaymentDetails;
+
+	use super::*;
+	use crate::ledger::{LedgerAccount, LedgerEntryKind};
+	use crate::payments::PaymentRecord;
+
+	fn payments() -> Vec<Payment> {
+		let lightning = PaymentDetails {
+			id: PaymentId([0xab; 32]),
+			kind: PaymentKind::Bolt11 {
+				hash: PaymentHash([0xab; 32]),
+				preimage: None,
+				secret: None,
+			},
+			amount_msat: Some(10_000),
+			direction: PaymentDirection::Outbound,
+			status: PaymentStatus::Succeeded,
+			latest_update_timestamp: 200,
+		};
+		let record = PaymentRecord {
+			fee_paid_msat: Some(15),
+			label: Some("socks, \"wool\"".to_string()),
+			..Default::default()
+		};
+
+		let mut txid = [0u8; 32];
+		txid[0] = 1;
+		let onchain = PaymentDetails {
+			id: PaymentId(txid),
+			kind: PaymentKind::Onchain,
+			amount_msat: Some(50_000_000),
+			direction: PaymentDirection::Outbound,
+			status: PaymentStatus::Succeeded,
+			latest_update_timestamp: 100,
+		};
+
+		vec![Payment::new(lightning, record), Payment::new(onchain, PaymentRecord::default())]
+	}
+
+	fn deposits() -> Vec<LedgerEntry> {
+		vec![LedgerEntry {
+			sequence: 3,
+			timestamp: 150,
+			kind: LedgerEntryKind::OnchainDeposit,
+			debit: LedgerAccount::Wallet,
+			credit: LedgerAccount::External,
+			amount_msat: 20_000_000,
+			payment_id: None,
+			balance_msat: 70_000_000,
+		}]
+	}
+
+	#[test]
+	fn exports_csv_oldest_first() {
+		let csv = export(payments(), deposits(), ExportFormat::Csv);
+		let lines: Vec<&str> = csv.lines().collect();
+		assert_eq!(
+			lines[0],
+			"timestamp,direction,kind,amount_msat,fee_msat,status,reference,label"
+		);
+		assert_eq!(
+			lines[1],
+			format!("100,outbound,onchain,50000000,,succeeded,{}01,", "00".repeat(31))
+		);
+		assert_eq!(lines[2], "150,inbound,onchain,20000000,,succeeded,,");
+		assert_eq!(
+			lines[3],
+			format!(
+				"200,outbound,bolt11,10000,15,succeeded,{},\"socks, \"\"wool\"\"\"",
+				"ab".repeat(32)
+			)
+		);
+		assert_eq!(lines.len(), 4);
+	}
+
+	#[test]
+	fn exports_json() {
+		let json = export(payments(), deposits(), ExportFormat::Json);
+		let json: Value = serde_json::from_str(&json).unwrap();
+		assert_eq!(
+			json,
+			json!([
+				{
+					"timestamp": 100,
+					"direction": "outbound",
+					"kind": "onchain",
+					"amount_msat": 50_000_000,
+					"fee_msat": null,
+					"status": "succeeded",
+					"reference": format!("{}01", "00".repeat(31)),
+					"label": null,
+				},
+				{
+					"timestamp": 150,
+					"direction": "inbound",
+					"kind": "onchain",
+					"amount_msat": 20_000_000,
+					"fee_msat": null,
+					"status": "succeeded",
+					"reference": null,
+					"label": null,
+				},
+				{
+					"timestamp": 200,
+					"direction": "outbound",
+					"kind": "bolt11",
+					"amount_msat": 10_000,
+					"fee_msat": 15,
+					"status": "succeeded",
+					"reference": "ab".repeat(32),
+					"label": "socks, \"wool\"",
+				},
+			])
+		);
+	}
+
+	#[test]
+	fn exports_nothing_but_headers() {
+		let csv = export(Vec::new(), Vec::new(), ExportFormat::Csv);
+		assert_eq!(csv, format!("{}\n", COLUMNS.join(",")));
+		assert_eq!(export(Vec::new(), Vec::new(), ExportFormat::Json), "[]");
+	}
+}
@@ -0,0 +1,229 @@
+// This file is Copyright its original authors, visible in version contror
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use ldk_node::lightning::io;
+use ldk_node::lightning::ln::msgs::DecodeError;
+use ldk_node::lightning::util::ser::{Readable, Writeable, Writer};
+use ldk_node::lightning::{_init_and_read_len_prefixed_tlv_fields, write_tlv_fields};
+
+use crate::error::Error;
+use crate::Balances;
+
+const MSAT_PER_BTC: f64 = 100_000_000_000.0;
+const SATS_PER_BTC: f64 = 100_000_000.0;
+/// No amount can be larger than the 21 million bitcoin that will ever exist.
+const MAX_SUPPLY_SATS: f64 = 2_100_000_000_000_000.0;
+
+/// A source of bitcoin exchange rates.
+pub trait ExchangeRateProvider: Send + Sync {
+	/// Returns the price of one bitcoin in `currency`, e.g. `"USD"`.
+	///
+	/// Should fail with [`Error::ExchangeRateUnavailable`] if the currency isn't supported.
+	fn btc_price(&self, currency: String) -> Result<f64, Error>;
+}
+
+/// An [`ExchangeRateProvider`] with fixed rates, e.g. for tests.
+pub struct StaticExchangeRates {
+	btc_prices: HashMap<String, f64>,
+}
+
+impl StaticExchangeRates {
+	/// Creates a provider with the given price of one bitcoin per currency.
+	pub fn new(btc_prices: HashMap<String, f64>) -> Self {
+		Self { btc_prices }
+	}
+}
+
+impl ExchangeRateProvider for StaticExchangeRates {
+	fn btc_price(&self, currency: String) -> Result<f64, Error> {
+		self.btc_prices
+			.iter()
+			.find(|(c, _)| c.eq_ignore_ascii_case(&currency))
+			.map(|(_, price)| *price)
+			.ok_or(Error::ExchangeRateUnavailable)
+	}
+}
+
+/// An [`ExchangeRateProvider`] that reads rates from a file on every lookup.
+///
+/// The file lists one currency per line followed by the price of one bitcoin, e.g. `USD 65000.00`,
+/// so it can be kept up to date by another process.
+pub struct FileExchangeRates {
+	path: PathBuf,
+}
+
+impl FileExchangeRates {
+	/// Creates a provider reading from the file at `path`.
+	pub fn new(path: PathBuf) -> Self {
+		Self { path }
+	}
+}
+
+impl ExchangeRateProvider for FileExchangeRates {
+	fn btc_price(&self, currency: String) -> Result<f64, Error> {
+		let contents =
+			std::fs::read_to_string(&self.path).map_err(|_e| Error::ExchangeRateUnavailable)?;
+		contents
+			.lines()
+			.filter_map(|line| {
+				let mut parts = line.split_whitespace();
+				match (parts.next(), parts.next()) {
+					(Some(c), Some(price)) if c.eq_ignore_ascii_case(&currency) => {
+						price.parse().ok()
+					},
+					_ => None,
+				}
+			})
+			.next()
+			.ok_or(Error::ExchangeRateUnavailable)
+	}
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// The value of an amount of bitcoin in a fiat currency at a given exchange rate.
+pub struct FiatValue {
+	/// The fiat currency, e.g. `"USD"`.
+	pub currency: String,
+	/// The value in `currency`.
+	pub amount: f64,
+	/// The price of one bitcoin in `currency` the value was computed with.
+	pub btc_price: f64,
+}
+
+impl FiatValue {
+	pub(crate) fn new(amount_msat: u64, currency: String, btc_price: f64) -> Self {
+		Self { currency, amount: amount_msat as f64 / MSAT_PER_BTC * btc_price, btc_price }
+	}
+}
+
+impl Writeable for FiatValue {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), io::Error> {
+		write_tlv_fields!(writer, {
+			(0, self.currency, required),
+			(2, self.amount.to_bits(), required),
+			(4, self.btc_price.to_bits(), required),
+		});
+		Ok(())
+	}
+}
+
+impl Readable for FiatValue {
+	fn read<R: io::Read>(reader: &mut R) -> Result<Self, DecodeError> {
+		_init_and_read_len_prefixed_tlv_fields!(reader, {
+			(0, currency, required),
+			(2, amount_bits, required),
+			(4, btc_price_bits, required),
+		});
+		let currency: String = currency.0.ok_or(DecodeError::InvalidValue)?;
+		let amount_bits: u64 = amount_bits.0.ok_or(DecodeError::InvalidValue)?;
+		let btc_price_bits: u64 = btc_price_bits.0.ok_or(DecodeError::InvalidValue)?;
+		Ok(Self {
+			currency,
+			amount: f64::from_bits(amount_bits),
+			btc_price: f64::from_bits(btc_price_bits),
+		})
+	}
+}
+
+/// The current balances, valued in a fiat currency.
+pub struct FiatBalances {
+	/// The fiat currency, e.g. `"USD"`.
+	pub currency: String,
+	/// The price of one bitcoin in `currency` the balances were valued at.
+	pub btc_price: f64,
+	/// The value of the currently spendable onchain balance.
+	pub spendable_onchain_balance: f64,
+	/// The value of the total onchain balance.
+	pub total_onchain_balance: f64,
+	/// The value of the funds held in reserve to pay for fee bumping.
+	pub total_anchor_channels_reserve: f64,
+	/// The value of the funds we hold in lightning channels.
+	pub total_lightning_balance: f64,
+	/// The value of what we can send over lightning.
+	pub outbound_capacity_lightning: f64,
+	/// The value of what we can receive over lightning.
+	pub inbound_capacity_lightning: f64,
+}
+
+impl FiatBalances {
+	pub(crate) fn new(balances: &Balances, currency: String, btc_price: f64) -> Self {
+		let value = |sats: u64| sats as f64 / SATS_PER_BTC * btc_price;
+		Self {
+			currency,
+			btc_price,
+			spendable_onchain_balance: value(balances.spendable_onchain_balance_sats),
+			total_onchain_balance: value(balances.total_onchain_balance_sats),
+			total_anchor_channels_reserve: value(balances.total_anchor_channels_reserve_sats),
+			total_lightning_balance: value(balances.total_lightning_balance_sats),
+			outbound_capacity_lightning: value(balances.outbound_capacity_lightning_sats),
+			inbound_capacity_lightning: value(balances.inbound_capacity_lightning_sats),
+		}
+	}
+}
+
+/// Converts a fiat amount to sats, rounding up so the payee gets at least the requested value.
+///
+/// Fails with [`Error::InvalidAmount`] for amounts worth more than the total bitcoin supply.
+pub(crate) fn fiat_to_sats(amount: f64, btc_price: f64) -> Result<u64, Error> {
+	if !amount.is_finite() || amount <= 0.0 {
+		return Err(Error::InvalidAmount);
+	}
+	if !btc_price.is_finite() || btc_price <= 0.0 {
+		return Err(Error::ExchangeRateUnavailable);
+	}
+	let amount_sats = (amount / btc_price * SATS_PER_BTC).ceil();
+	if amount_sats > MAX_SUPPLY_SATS {
+		return Err(Error::InvalidAmount);
+	}
+	Ok(amount_sats as u64)
+}
+
+#[cfg(test)]
+mod test {
+	use std::io::Write;
+
+	use super::*;
+
+	#[test]
+	fn converts_between_sats_and_fiat() {
+		assert_eq!(fiat_to_sats(10.0, 50_000.0), Ok(20_000));
+		assert_eq!(fiat_to_sats(0.01, 30_000.0), Ok(34));
+		assert_eq!(fiat_to_sats(0.0, 50_000.0), Err(Error::InvalidAmount));
+		assert_eq!(fiat_to_sats(f64::NAN, 50_000.0), Err(Error::InvalidAmount));
+		assert_eq!(fiat_to_sats(10.0, 0.0), Err(Error::ExchangeRateUnavailable));
+		assert_eq!(fiat_to_sats(21_000_000.0, 1.0), Ok(2_100_000_000_000_000));
+		assert_eq!(fiat_to_sats(21_000_001.0, 1.0), Err(Error::InvalidAmount));
+
+		let value = FiatValue::new(20_000_000, "USD".to_string(), 50_000.0);
+		assert_eq!(value.amount, 10.0);
+	}
+
+	#[test]
+	fn reads_rates_from_file() {
+		let path = std::env::temp_dir().join(format!("romer-rates-{}", std::process::id()));
+		let mut file = std::fs::File::create(&path).unwrap();
+		writeln!(file, "USD 65000.50\nEUR\t60000").unwrap();
+
+		let provider = FileExchangeRates::new(path.clone());
+		assert_eq!(provider.btc_price("USD".to_string()), Ok(65_000.5));
+		assert_eq!(provider.btc_price("eur".to_string()), Ok(60_000.0));
+		assert_eq!(provider.btc_price("GBP".to_string()), Err(Error::ExchangeRateUnavailable));
+
+		std::fs::remove_file(&path).unwrap();
+		assert_eq!(provider.btc_price("USD".to_string()), Err(Error::ExchangeRateUnavailable));
+	}
+
+	#[test]
+	fn round_trips_fiat_values() {
+		let value = FiatValue::new(123_456, "EUR".to_string(), 61_234.56);
+		assert_eq!(FiatValue::read(&mut &value.encode()[..]).unwrap(), value);
+	}
+}
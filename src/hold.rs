@@ -0,0 +1,122 @@
+// This file is Copyright its original authors, visible in version contror
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use ldk_node::payment::{PaymentDirection, PaymentKind, PaymentStatus};
+use ldk_node::Node;
+
+use crate::audit::{AuditLog, AuditOperation};
+use crate::error::Error;
+use crate::payments::{self, PaymentRecord};
+use crate::shutdown::Shutdown;
+use crate::store::PersistedMap;
+
+/// How often we check for hold invoice payments that are about to expire.
+const EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How many blocks before its claim deadline we cancel a payment nobody claimed.
+///
+/// LDK fails the payment back itself at the deadline, but by then a claim could race the
+/// cancellation, so we give up a little earlier.
+const CANCEL_BUFFER_BLOCKS: u32 = 3;
+
+/// Whether a payment with the given claim deadline has to be cancelled at `best_block_height`.
+fn must_cancel(claim_deadline: u32, best_block_height: u32) -> bool {
+	best_block_height + CANCEL_BUFFER_BLOCKS >= claim_deadline
+}
+
+/// Cancels payments to hold invoices that weren't claimed or cancelled in time.
+pub(crate) struct HoldInvoiceWatcher {
+	node: Arc<Node>,
+	payment_records: Arc<PersistedMap<PaymentRecord>>,
+	/// Where we record cancellations, if auditing is enabled.
+	audit_log: Option<Arc<AuditLog>>,
+	shutdown: Arc<Shutdown>,
+}
+
+impl HoldInvoiceWatcher {
+	pub(crate) fn new(
+		node: Arc<Node>, payment_records: Arc<PersistedMap<PaymentRecord>>,
+		audit_log: Option<Arc<AuditLog>>, shutdown: Arc<Shutdown>,
+	) -> Self {
+		Self { node, payment_records, audit_log, shutdown }
+	}
+
+	pub(crate) fn run(&self) {
+		while !self.shutdown.is_stopped() {
+			self.cancel_expiring();
+			self.shutdown.wait(EXPIRY_CHECK_INTERVAL);
+		}
+	}
+
+	fn cancel_expiring(&self) {
+		let best_block_height = self.node.status().current_best_block.height;
+		let pending = self.node.list_payments_with_filter(|payment| {
+			payment.direction == PaymentDirection::Inbound
+				&& payment.status == PaymentStatus::Pending
+				&& matches!(payment.kind, PaymentKind::Bolt11 { .. })
+		});
+		for payment in pending {
+			let record = match self.payment_records.get(&payments::record_key(&payment)) {
+				Some(record) => record,
+				None => continue,
+			};
+			let claim_deadline = match record.claim_deadline {
+				Some(claim_deadline) => claim_deadline,
+				None => continue,
+			};
+			if !must_cancel(claim_deadline, best_block_height) {
+				continue;
+			}
+			if let Some(payment_hash) = payments::payment_hash(&payment.kind) {
+				let res =
+					self.node.bolt11_payment().fail_for_hash(payment_hash).map_err(Error::from);
+				if let Err(_e) = &res {
+					// TODO: log?
+				}
+				self.audit(payment_hash.to_string(), claim_deadline, &res);
+			}
+		}
+	}
+
+	fn audit(&self, payment_hash: String, claim_deadline: u32, res: &Result<(), Error>) {
+		let audit_log = match &self.audit_log {
+			Some(audit_log) => audit_log,
+			None => return,
+		};
+		let mut parameters = HashMap::new();
+		parameters.insert("payment_hash".to_string(), payment_hash);
+		parameters.insert("claim_deadline".to_string(), claim_deadline.to_string());
+		let res = audit_log.record(
+			AuditOperation::HoldInvoiceExpired,
+			None,
+			parameters,
+			res.as_ref().err(),
+			crate::unix_time_secs(),
+		);
+		if let Err(_e) = res {
+			// TODO: log?
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn cancels_ahead_of_the_claim_deadline() {
+		assert!(!must_cancel(800_010, 800_000));
+		assert!(!must_cancel(800_010, 800_006));
+		assert!(must_cancel(800_010, 800_007));
+		assert!(must_cancel(800_010, 800_020));
+	}
+}
@@ -0,0 +1,489 @@
+// This file is Copyright its original authors, visible in version contror
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+use ldk_node::io::sqlite_store::SqliteStore;
+use ldk_node::lightning::ln::channelmanager::PaymentId;
+use ldk_node::lightning::{impl_writeable_tlv_based, impl_writeable_tlv_based_enum};
+
+use crate::error::Error;
+use crate::payments::TimeRange;
+use crate::store::{self, PersistedMap};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+/// An account of the double-entry ledger.
+pub enum LedgerAccount {
+	/// Our own funds, on-chain and in lightning channels.
+	Wallet,
+	/// The parties we send payments to and receive payments from.
+	External,
+	/// Fees paid to route outbound lightning payments.
+	RoutingFees,
+	/// Fees paid to the LSP for opening JIT channels.
+	LspFees,
+	/// Fees paid to miners for on-chain payments.
+	OnchainFees,
+	/// Fees paid to open and cooperatively close channels.
+	ChannelFees,
+	/// Funds lost to force-closing channels, e.g. to fees or HTLCs that timed out.
+	ForceCloseCosts,
+	/// Funds held before the ledger was started, and corrections we can't attribute.
+	Equity,
+}
+
+impl_writeable_tlv_based_enum!(LedgerAccount,
+	(0, Wallet) => {},
+	(2, External) => {},
+	(4, RoutingFees) => {},
+	(6, LspFees) => {},
+	(8, OnchainFees) => {},
+	(10, ChannelFees) => {},
+	(12, ForceCloseCosts) => {},
+	(14, Equity) => {},
+);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+/// What caused a [`LedgerEntry`].
+pub enum LedgerEntryKind {
+	/// The balance held when the ledger was started, booked once the on-chain wallet synced for
+	/// the first time.
+	OpeningBalance,
+	/// A lightning payment was received.
+	PaymentReceived,
+	/// A lightning payment was sent.
+	PaymentSent,
+	/// The routing fee of a lightning payment we sent.
+	RoutingFee,
+	/// The fee the LSP deducted from a payment received via a JIT channel.
+	LspFee,
+	/// An on-chain payment was sent.
+	OnchainPaymentSent,
+	/// The mining fee of an on-chain payment we sent.
+	OnchainFee,
+	/// The cost of opening a channel.
+	ChannelOpenFee,
+	/// The cost of cooperatively closing a channel.
+	ChannelCloseFee,
+	/// The cost of a channel being force-closed.
+	ForceCloseCost,
+	/// Funds were received on-chain.
+	OnchainDeposit,
+	/// A change in balance we couldn't attribute to any of the above.
+	Adjustment,
+}
+
+impl_writeable_tlv_based_enum!(LedgerEntryKind,
+	(0, OpeningBalance) => {},
+	(2, PaymentReceived) => {},
+	(4, PaymentSent) => {},
+	(6, RoutingFee) => {},
+	(8, LspFee) => {},
+	(10, OnchainPaymentSent) => {},
+	(12, OnchainFee) => {},
+	(14, ChannelOpenFee) => {},
+	(16, ChannelCloseFee) => {},
+	(18, ForceCloseCost) => {},
+	(20, OnchainDeposit) => {},
+	(22, Adjustment) => {},
+);
+
+impl LedgerEntryKind {
+	/// The account on the other side of [`LedgerAccount::Wallet`].
+	fn counter_account(&self) -> LedgerAccount {
+		match self {
+			LedgerEntryKind::OpeningBalance | LedgerEntryKind::Adjustment => LedgerAccount::Equity,
+			LedgerEntryKind::PaymentReceived
+			| LedgerEntryKind::PaymentSent
+			| LedgerEntryKind::OnchainPaymentSent
+			| LedgerEntryKind::OnchainDeposit => LedgerAccount::External,
+			LedgerEntryKind::RoutingFee => LedgerAccount::RoutingFees,
+			LedgerEntryKind::LspFee => LedgerAccount::LspFees,
+			LedgerEntryKind::OnchainFee => LedgerAccount::OnchainFees,
+			LedgerEntryKind::ChannelOpenFee | LedgerEntryKind::ChannelCloseFee => {
+				LedgerAccount::ChannelFees
+			},
+			LedgerEntryKind::ForceCloseCost => LedgerAccount::ForceCloseCosts,
+		}
+	}
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// A single movement of funds between two [`LedgerAccount`]s.
+pub struct LedgerEntry {
+	/// The position of the entry in the ledger, starting at zero.
+	pub sequence: u64,
+	/// When the entry was recorded, as a UNIX timestamp in seconds.
+	pub timestamp: u64,
+	/// What caused the entry.
+	pub kind: LedgerEntryKind,
+	/// The account the funds moved to.
+	pub debit: LedgerAccount,
+	/// The account the funds moved from.
+	pub credit: LedgerAccount,
+	/// The amount moved.
+	pub amount_msat: u64,
+	/// The payment the entry belongs to, if any.
+	pub payment_id: Option<PaymentId>,
+	/// The balance of [`LedgerAccount::Wallet`] after this entry.
+	pub balance_msat: i64,
+}
+
+impl_writeable_tlv_based!(LedgerEntry, {
+	(0, sequence, required),
+	(2, timestamp, required),
+	(4, kind, required),
+	(6, debit, required),
+	(8, credit, required),
+	(10, amount_msat, required),
+	(12, payment_id, option),
+	(14, balance_msat, required),
+});
+
+/// The wallet balance as observed from the node, used to find movements we weren't told about.
+pub(crate) struct BalanceSnapshot {
+	/// Our total on-chain and lightning balance.
+	pub(crate) balance_msat: i64,
+	/// Differences smaller than this are rounding, as LDK Node reports balances in whole sats.
+	pub(crate) tolerance_msat: u64,
+	/// When the on-chain wallet was last synced.
+	pub(crate) onchain_synced_at: Option<u64>,
+	/// Whether funds from closed channels are still waiting to be swept.
+	pub(crate) sweeps_pending: bool,
+	/// Whether outbound lightning payments are in flight.
+	pub(crate) payments_in_flight: bool,
+}
+
+/// An event that may have cost us funds we only see once our balance reflects it.
+#[derive(Debug, Clone, Copy)]
+struct Attribution {
+	kind: LedgerEntryKind,
+	since: u64,
+}
+
+struct LedgerState {
+	/// Whether the balance we started out with was booked.
+	opened: bool,
+	next_sequence: u64,
+	balance_msat: i64,
+	recorded_payments: HashSet<(PaymentId, LedgerEntryKind)>,
+	/// Kept in memory only, so costs still unaccounted for at restart become adjustments.
+	attributions: Vec<Attribution>,
+}
+
+/// A persisted double-entry ledger of everything that changes our balance.
+///
+/// Payments and the fees we are told about are recorded as they happen. Costs LDK Node doesn't
+/// report, such as on-chain and channel closing fees, are found by reconciling the ledger with our
+/// actual balance and attributed to the event that most likely caused them.
+pub(crate) struct Ledger {
+	entries: PersistedMap<LedgerEntry>,
+	state: Mutex<LedgerState>,
+}
+
+impl Ledger {
+	pub(crate) fn load(kv_store: Arc<SqliteStore>) -> Result<Self, Error> {
+		let entries: PersistedMap<LedgerEntry> =
+			PersistedMap::load(kv_store, store::LEDGER_SECONDARY_NAMESPACE)?;
+
+		let mut state = LedgerState {
+			opened: false,
+			next_sequence: 0,
+			balance_msat: 0,
+			recorded_payments: HashSet::new(),
+			attributions: Vec::new(),
+		};
+		for entry in entries.values() {
+			if entry.sequence >= state.next_sequence {
+				state.next_sequence = entry.sequence + 1;
+				state.balance_msat = entry.balance_msat;
+			}
+			if let Some(payment_id) = entry.payment_id {
+				state.recorded_payments.insert((payment_id, entry.kind));
+			}
+			if entry.kind == LedgerEntryKind::OpeningBalance {
+				state.opened = true;
+			}
+		}
+
+		Ok(Self { entries, state: Mutex::new(state) })
+	}
+
+	/// Records a movement of funds in or out of our wallet.
+	///
+	/// Entries for a payment are only recorded once per kind, so events replayed after a restart
+	/// don't count twice.
+	pub(crate) fn record(
+		&self, kind: LedgerEntryKind, amount_msat: u64, inbound: bool,
+		payment_id: Option<PaymentId>, timestamp: u64,
+	) -> Result<(), Error> {
+		if amount_msat == 0 {
+			return Ok(());
+		}
+		let mut state = self.state.lock().unwrap();
+		if let Some(payment_id) = payment_id {
+			if state.recorded_payments.contains(&(payment_id, kind)) {
+				return Ok(());
+			}
+		}
+		self.append(&mut state, kind, amount_msat, inbound, payment_id, timestamp)
+	}
+
+	/// Remembers that funds may have been spent on `kind`, to attribute the next unexplained
+	/// decrease in balance to it.
+	pub(crate) fn expect_cost(&self, kind: LedgerEntryKind, timestamp: u64) {
+		let mut state = self.state.lock().unwrap();
+		state.attributions.push(Attribution { kind, since: timestamp });
+	}
+
+	/// Records any difference between the ledger and the observed balance.
+	///
+	/// Skipped while the observed balance is known to be in flux, i.e. while payments are in flight
+	/// or the on-chain wallet hasn't caught up with a cost we expect.
+	pub(crate) fn reconcile(
+		&self, snapshot: &BalanceSnapshot, timestamp: u64,
+	) -> Result<(), Error> {
+		let mut state = self.state.lock().unwrap();
+
+		if snapshot.payments_in_flight {
+			return Ok(());
+		}
+		if !state.opened {
+			// Our on-chain balance isn't known before the wallet synced for the first time. Once
+			// it is, whatever the payments recorded so far don't explain is what we started with.
+			if snapshot.onchain_synced_at.is_none() {
+				return Ok(());
+			}
+			let delta_msat = snapshot.balance_msat - state.balance_msat;
+			self.append(
+				&mut state,
+				LedgerEntryKind::OpeningBalance,
+				delta_msat.unsigned_abs(),
+				delta_msat >= 0,
+				None,
+				timestamp,
+			)?;
+			state.opened = true;
+			return Ok(());
+		}
+		let synced_at = snapshot.onchain_synced_at.unwrap_or(0);
+		if state.attributions.iter().any(|attribution| attribution.since >= synced_at) {
+			return Ok(());
+		}
+
+		let delta_msat = snapshot.balance_msat - state.balance_msat;
+		if delta_msat.unsigned_abs() >= snapshot.tolerance_msat {
+			let inbound = delta_msat > 0;
+			let kind = if inbound {
+				if state.attributions.is_empty() {
+					LedgerEntryKind::OnchainDeposit
+				} else {
+					LedgerEntryKind::Adjustment
+				}
+			} else {
+				most_likely_cost(&state.attributions)
+			};
+			self.append(&mut state, kind, delta_msat.unsigned_abs(), inbound, None, timestamp)?;
+		}
+
+		if !snapshot.sweeps_pending {
+			state.attributions.clear();
+		}
+		Ok(())
+	}
+
+	/// Returns the entries recorded within `range`, in order.
+	pub(crate) fn entries(&self, range: &TimeRange) -> Vec<LedgerEntry> {
+		let mut entries: Vec<LedgerEntry> =
+			self.entries.values().into_iter().filter(|e| range.contains(e.timestamp)).collect();
+		entries.sort_unstable_by_key(|entry| entry.sequence);
+		entries
+	}
+
+	fn append(
+		&self, state: &mut LedgerState, kind: LedgerEntryKind, amount_msat: u64, inbound: bool,
+		payment_id: Option<PaymentId>, timestamp: u64,
+	) -> Result<(), Error> {
+		let (debit, credit, balance_msat) = if inbound {
+			(LedgerAccount::Wallet, kind.counter_account(), state.balance_msat + amount_msat as i64)
+		} else {
+			(kind.counter_account(), LedgerAccount::Wallet, state.balance_msat - amount_msat as i64)
+		};
+		let entry = LedgerEntry {
+			sequence: state.next_sequence,
+			timestamp,
+			kind,
+			debit,
+			credit,
+			amount_msat,
+			payment_id,
+			balance_msat,
+		};
+		self.entries.insert(&format!("{:020}", entry.sequence), entry)?;
+
+		state.next_sequence += 1;
+		state.balance_msat = balance_msat;
+		if let Some(payment_id) = payment_id {
+			state.recorded_payments.insert((payment_id, kind));
+		}
+		Ok(())
+	}
+}
+
+/// Picks the most expensive kind of event among those that may explain a loss of funds.
+fn most_likely_cost(attributions: &[Attribution]) -> LedgerEntryKind {
+	const PRIORITY: &[LedgerEntryKind] = &[
+		LedgerEntryKind::ForceCloseCost,
+		LedgerEntryKind::ChannelCloseFee,
+		LedgerEntryKind::ChannelOpenFee,
+		LedgerEntryKind::OnchainFee,
+	];
+	PRIORITY
+		.iter()
+		.copied()
+		.find(|kind| attributions.iter().any(|attribution| attribution.kind == *kind))
+		.unwrap_or(LedgerEntryKind::Adjustment)
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	fn snapshot(balance_msat: i64) -> BalanceSnapshot {
+		BalanceSnapshot {
+			balance_msat,
+			tolerance_msat: 1_000,
+			onchain_synced_at: Some(100),
+			sweeps_pending: false,
+			payments_in_flight: false,
+		}
+	}
+
+	fn kinds(ledger: &Ledger) -> Vec<(LedgerEntryKind, u64, i64)> {
+		let entries = ledger.entries(&TimeRange::default());
+		entries.into_iter().map(|e| (e.kind, e.amount_msat, e.balance_msat)).collect()
+	}
+
+	#[test]
+	fn keeps_a_running_balance() {
+		let ledger = Ledger::load(store::test_store("ledger-running")).unwrap();
+		ledger.reconcile(&snapshot(50_000_000), 10).unwrap();
+		ledger.record(LedgerEntryKind::PaymentReceived, 10_000_000, true, None, 20).unwrap();
+		ledger.record(LedgerEntryKind::LspFee, 2_000_000, false, None, 20).unwrap();
+		let payment_id = Some(PaymentId([1; 32]));
+		ledger.record(LedgerEntryKind::PaymentSent, 5_000_000, false, payment_id, 30).unwrap();
+		ledger.record(LedgerEntryKind::PaymentSent, 5_000_000, false, payment_id, 30).unwrap();
+
+		assert_eq!(
+			kinds(&ledger),
+			vec![
+				(LedgerEntryKind::OpeningBalance, 50_000_000, 50_000_000),
+				(LedgerEntryKind::PaymentReceived, 10_000_000, 60_000_000),
+				(LedgerEntryKind::LspFee, 2_000_000, 58_000_000),
+				(LedgerEntryKind::PaymentSent, 5_000_000, 53_000_000),
+			]
+		);
+
+		let range = TimeRange { from_timestamp: Some(20), to_timestamp: Some(30) };
+		let entries = ledger.entries(&range);
+		assert_eq!(entries.len(), 2);
+		assert_eq!(entries[1].debit, LedgerAccount::LspFees);
+		assert_eq!(entries[1].credit, LedgerAccount::Wallet);
+	}
+
+	#[test]
+	fn attributes_unexplained_losses_to_expected_costs() {
+		let ledger = Ledger::load(store::test_store("ledger-attribution")).unwrap();
+		ledger.reconcile(&snapshot(100_000_000), 10).unwrap();
+
+		// The on-chain wallet hasn't seen the transaction yet, so there's nothing to reconcile.
+		ledger.record(LedgerEntryKind::OnchainPaymentSent, 40_000_000, false, None, 150).unwrap();
+		ledger.expect_cost(LedgerEntryKind::OnchainFee, 150);
+		ledger.reconcile(&snapshot(100_000_000), 150).unwrap();
+
+		let synced = BalanceSnapshot { onchain_synced_at: Some(200), ..snapshot(59_800_000) };
+		ledger.reconcile(&synced, 200).unwrap();
+
+		// Rounding differences are ignored, and unexplained gains are deposits.
+		let synced = BalanceSnapshot { onchain_synced_at: Some(300), ..snapshot(59_800_500) };
+		ledger.reconcile(&synced, 300).unwrap();
+		let synced = BalanceSnapshot { onchain_synced_at: Some(400), ..snapshot(69_800_000) };
+		ledger.reconcile(&synced, 400).unwrap();
+
+		assert_eq!(
+			kinds(&ledger),
+			vec![
+				(LedgerEntryKind::OpeningBalance, 100_000_000, 100_000_000),
+				(LedgerEntryKind::OnchainPaymentSent, 40_000_000, 60_000_000),
+				(LedgerEntryKind::OnchainFee, 200_000, 59_800_000),
+				(LedgerEntryKind::OnchainDeposit, 10_000_000, 69_800_000),
+			]
+		);
+	}
+
+	#[test]
+	fn books_the_opening_balance_once_the_wallet_synced() {
+		let kv_store = store::test_store("ledger-opening");
+		let ledger = Ledger::load(Arc::clone(&kv_store)).unwrap();
+		ledger.record(LedgerEntryKind::PaymentReceived, 10_000_000, true, None, 20).unwrap();
+		let unsynced = BalanceSnapshot { onchain_synced_at: None, ..snapshot(10_000_000) };
+		ledger.reconcile(&unsynced, 30).unwrap();
+		ledger.reconcile(&snapshot(60_000_000), 40).unwrap();
+
+		// Later gains are deposits, also after a restart.
+		let ledger = Ledger::load(kv_store).unwrap();
+		ledger.reconcile(&snapshot(70_000_000), 50).unwrap();
+
+		assert_eq!(
+			kinds(&ledger),
+			vec![
+				(LedgerEntryKind::PaymentReceived, 10_000_000, 10_000_000),
+				(LedgerEntryKind::OpeningBalance, 50_000_000, 60_000_000),
+				(LedgerEntryKind::OnchainDeposit, 10_000_000, 70_000_000),
+			]
+		);
+	}
+
+	#[test]
+	fn books_an_empty_opening_balance() {
+		let kv_store = store::test_store("ledger-empty-opening");
+		let ledger = Ledger::load(Arc::clone(&kv_store)).unwrap();
+		ledger.reconcile(&snapshot(0), 10).unwrap();
+
+		let ledger = Ledger::load(kv_store).unwrap();
+		ledger.reconcile(&snapshot(5_000_000), 20).unwrap();
+
+		assert_eq!(
+			kinds(&ledger),
+			vec![
+				(LedgerEntryKind::OpeningBalance, 0, 0),
+				(LedgerEntryKind::OnchainDeposit, 5_000_000, 5_000_000),
+			]
+		);
+	}
+
+	#[test]
+	fn survives_restarts() {
+		let kv_store = store::test_store("ledger-restart");
+		let ledger = Ledger::load(Arc::clone(&kv_store)).unwrap();
+		let payment_id = Some(PaymentId([2; 32]));
+		ledger.record(LedgerEntryKind::PaymentReceived, 1_000, true, payment_id, 10).unwrap();
+
+		let ledger = Ledger::load(kv_store).unwrap();
+		ledger.record(LedgerEntryKind::PaymentReceived, 1_000, true, payment_id, 10).unwrap();
+		ledger.record(LedgerEntryKind::PaymentReceived, 2_000, true, None, 20).unwrap();
+		assert_eq!(
+			kinds(&ledger),
+			vec![
+				(LedgerEntryKind::PaymentReceived, 1_000, 1_000),
+				(LedgerEntryKind::PaymentReceived, 2_000, 3_000),
+			]
+		);
+	}
+}
@@ -27,14 +27,14 @@
 //! use romer::Romer;
 //!
 //! fn main() {
-//! 	let romer = Romer::new("my-api-key").unwrap();
+//!     let romer = Romer::new("my-api-key").unwrap();
 //!
-//! 	let invoice = romer.receive(100_000, "alpaca socks").unwrap();
+//!     let invoice = romer.receive(100_000, "alpaca socks").unwrap();
 //!
-//! 	romer.send("INVOICE_STR").unwrap();
+//!     romer.send("INVOICE_STR").unwrap();
 //!
-//! 	let payments = romer.list_payments();
-//! 	let balance = romer.balance();
+//!     let payments = romer.list_payments();
+//!     let balance = romer.balance();
 //! }
 //! ```
 //!
@@ -48,37 +48,114 @@
 #![deny(rustdoc::private_intra_doc_links)]
 #![allow(bare_trait_objects)]
 #![allow(ellipsis_inclusive_range_patterns)]
+// LDK's serialization macros drop readers that don't implement `Drop`.
+#![allow(clippy::drop_non_drop)]
 #![cfg_attr(docsrs, feature(doc_auto_cfg))]
 
+mod accounts;
+mod audit;
+mod balance;
+mod config;
 mod error;
+mod estimate;
+mod event;
+mod export;
+mod fiat;
+mod hex_utils;
+mod hold;
+mod ledger;
+mod limits;
+mod lnurl;
+#[cfg(feature = "metrics")]
+mod metrics;
+mod options;
+mod payments;
+mod policy;
 mod services;
+mod shutdown;
+mod store;
+mod supervisor;
 mod token;
 #[cfg(feature = "uniffi")]
 mod uniffi_types;
+mod waiters;
+mod worker;
 
 use std::collections::HashMap;
 use std::str::FromStr;
-use std::sync::{mpsc, Arc, Mutex};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+use accounts::Accounts;
+pub use accounts::{Account, AccountBalance, AccountReconciliation, AccountTransfer};
+use audit::AuditLog;
+pub use audit::{AuditEntry, AuditOperation};
+pub use balance::{ClaimableBalance, LightningBalanceBreakdown};
+pub use config::{Config, LspConfig, RoutingFeeLimits};
 use error::Error;
 pub use error::Error as RomerError;
+pub use estimate::FeeEstimate;
+use estimate::RouteEstimator;
+pub use event::Event;
+use event::EventQueue;
+pub use export::ExportFormat;
+pub use fiat::{
+	ExchangeRateProvider, FiatBalances, FiatValue, FileExchangeRates, StaticExchangeRates,
+};
+use hold::HoldInvoiceWatcher;
+use ldk_node::bitcoin::secp256k1::{self, PublicKey};
 use ldk_node::bitcoin::BlockHash;
+use ldk_node::io::sqlite_store::{self, SqliteStore};
+use ldk_node::lightning::events::ClosureReason;
 use ldk_node::lightning::ln::channelmanager::PaymentId;
+#[cfg(not(feature = "uniffi"))]
+use ldk_node::payment::{LSPFeeLimits, PaymentDirection, PaymentKind, PaymentStatus};
 use ldk_node::{
-	bitcoin::{hashes::Hash, Address, Network, Txid},
-	lightning::{events::PaymentFailureReason, ln::PaymentHash},
+	bitcoin::{
+		hashes::{sha256::Hash as Sha256, Hash},
+		Address, Network, Txid,
+	},
+	lightning::{
+		events::PaymentFailureReason,
+		ln::{PaymentHash, PaymentPreimage},
+	},
 	lightning_invoice::Bolt11Invoice,
-	payment::PaymentDetails,
-	Builder, Node,
+	payment::{PaymentDetails, SendingParameters},
+	Builder, Node, NodeError,
+};
+use ledger::{BalanceSnapshot, Ledger};
+pub use ledger::{LedgerAccount, LedgerEntry, LedgerEntryKind};
+pub use limits::MaxAmounts;
+use lnurl::DefaultHttpFetcher;
+pub use lnurl::HttpFetcher;
+#[cfg(feature = "metrics")]
+use metrics::Metrics;
+pub use options::{ReceiveOptions, SendOnchainOptions, SendOptions, TransferOptions};
+use payments::{ClaimAction, PaymentRecord, PaymentTags};
+pub use payments::{
+	Page, Payment, PaymentFilter, PaymentKindFilter, PaymentPage, TimeRange, MAX_PAGE_SIZE,
 };
+use policy::{DestinationFilter, SpendTracker};
+pub use policy::{DestinationPolicy, SpendingPolicy};
 use services::ServiceConfig;
+use shutdown::Shutdown;
+use store::PersistedMap;
+use supervisor::LspSupervisor;
 #[cfg(feature = "uniffi")]
 use uniffi_types::*;
+use waiters::PaymentWaiters;
+use worker::BoundedWorkers;
 
 #[cfg(feature = "uniffi")]
 uniffi::include_scaffolding!("romer");
 
 const DEFAULT_INVOICE_EXPIRY_SECS: u32 = 3600;
+const READINESS_POLL_INTERVAL: Duration = Duration::from_millis(250);
+const LSP_JIT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// How many JIT channel requests may wait on the LSP at once, including ones we gave up on.
+const MAX_LSP_JIT_REQUESTS: usize = 4;
+const EVENT_POLL_INTERVAL: Duration = Duration::from_millis(100);
 
 /// The current balances across onchain and lightning wallets
 pub struct Balances {
@@ -100,7 +177,7 @@ pub struct Balances {
 pub struct Status {
 	/// The lightning node id.
 	pub node_id: String,
-	/// Have a peer connection with cequals.
+	/// Have a peer connection with the active LSP.
 	pub connected: bool,
 	/// Have a usable channel with cequals.
 	pub usable_channels: bool,
@@ -116,60 +193,433 @@ pub struct Status {
 	pub latest_fee_rate_cache_update_timestamp: Option<u64>,
 	/// Timestamp we last updated our graph using RGS.
 	pub latest_rgs_snapshot_timestamp: Option<u64>,
+	/// Connectivity of every configured LSP, in order of preference.
+	pub lsps: Vec<LspStatus>,
+}
+
+/// The connectivity of a configured LSP.
+pub struct LspStatus {
+	/// The node id of the LSP.
+	pub node_id: PublicKey,
+	/// Whether the LSP is the one used to open JIT channels.
+	pub active: bool,
+	/// Have a peer connection with the LSP.
+	pub connected: bool,
+	/// Have a usable channel with the LSP.
+	pub usable_channels: bool,
+}
+
+/// Whether the node is ready to send and receive payments, or which precondition is missing.
+///
+/// Preconditions are checked in the order of the variants below, so the first missing one is
+/// reported. A new wallet can receive before it can send, see [`Readiness::can_receive`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Readiness {
+	/// All preconditions are met.
+	Ready,
+	/// The lightning and onchain wallets have not completed their initial sync.
+	AwaitingChainSync,
+	/// The fee rate cache has not been populated yet.
+	AwaitingFeeRateCache,
+	/// No RGS snapshot has been applied to our network graph yet.
+	AwaitingGossipSnapshot,
+	/// We are not connected to the LSP.
+	AwaitingLspConnection,
+	/// We don't have a usable channel yet.
+	///
+	/// Receiving is still possible in this state as the LSP will open a JIT channel to us.
+	AwaitingUsableChannel,
+}
+
+impl Readiness {
+	fn from_status(status: &Status) -> Self {
+		if status.latest_wallet_sync_timestamp.is_none()
+			|| status.latest_onchain_wallet_sync_timestamp.is_none()
+		{
+			Readiness::AwaitingChainSync
+		} else if status.latest_fee_rate_cache_update_timestamp.is_none() {
+			Readiness::AwaitingFeeRateCache
+		} else if status.latest_rgs_snapshot_timestamp.is_none() {
+			Readiness::AwaitingGossipSnapshot
+		} else if !status.connected {
+			Readiness::AwaitingLspConnection
+		} else if !status.usable_channels {
+			Readiness::AwaitingUsableChannel
+		} else {
+			Readiness::Ready
+		}
+	}
+
+	/// Whether payments can be received, which doesn't need a channel as the LSP opens a JIT
+	/// channel to us.
+	pub fn can_receive(&self) -> bool {
+		matches!(self, Readiness::Ready | Readiness::AwaitingUsableChannel)
+	}
+
+	/// Whether payments can be sent, which needs a usable channel.
+	pub fn can_send(&self) -> bool {
+		*self == Readiness::Ready
+	}
 }
 
 #[derive(Clone)]
 /// The main interface to the lightning network
 pub struct Romer {
 	network: Network,
+	config: Config,
 	services: ServiceConfig,
+	destination_filter: Arc<DestinationFilter>,
+	/// The LSP the node sources JIT channels from.
+	active_lsp: LspConfig,
 	node: Arc<Node>,
-	pending_payments:
-		Arc<Mutex<HashMap<PaymentHash, mpsc::SyncSender<Result<u64, PaymentFailureReason>>>>>,
+	/// Runs JIT channel requests, which we give up on if the LSP doesn't answer in time.
+	jit_requests: Arc<BoundedWorkers>,
+	payment_records: Arc<PersistedMap<PaymentRecord>>,
+	idempotency_keys: Arc<PersistedMap<PaymentId>>,
+	spend_tracker: Arc<SpendTracker>,
+	audit_log: Arc<AuditLog>,
+	accounts: Arc<Accounts>,
+	internal_settlement_lock: Arc<Mutex<()>>,
+	ledger: Arc<Ledger>,
+	route_estimator: Arc<RouteEstimator>,
+	exchange_rate_provider: Arc<RwLock<Option<Arc<dyn ExchangeRateProvider>>>>,
+	http_fetcher: Arc<RwLock<Arc<dyn HttpFetcher>>>,
+	payment_waiters: Arc<PaymentWaiters>,
+	shutdown: Arc<Shutdown>,
+	/// Our background threads, to wait for them to exit when stopping.
+	threads: Arc<Mutex<Vec<JoinHandle<()>>>>,
+	event_queue: Arc<EventQueue>,
+	#[cfg(feature = "metrics")]
+	metrics: Arc<Metrics>,
 }
 
 impl Romer {
 	/// Create a new Romer instance using a c= API token.
 	pub fn new(api_token: &str) -> Result<Self, Error> {
+		Self::with_config(api_token, Config::default())
+	}
+
+	/// Create a new Romer instance using a c= API token and custom options.
+	///
+	/// JIT channels are sourced from the highest-ranked LSP in [`Config::lsps`] that is reachable
+	/// at startup. LDK Node sources JIT channels from a single LSP per node, so if that LSP fails
+	/// to provide them, receiving via JIT channels fails with [`Error::LspUnavailable`] until a
+	/// new instance is created, which picks the LSP anew.
+	pub fn with_config(api_token: &str, config: Config) -> Result<Self, Error> {
 		let network = token::network_from_token(api_token)?;
-		let services = ServiceConfig::new(network)?;
-
-		let services_builder = services.clone();
-		let mut builder = Builder::new();
-		builder.set_network(network);
-		builder.set_esplora_server(services_builder.esplora_url);
-		builder.set_gossip_source_rgs(services_builder.rgs_url);
-		builder.set_liquidity_source_lsps2(
-			services_builder.lsp_ip_port,
-			services_builder.lsp_node_id,
-			Some(api_token.to_string()),
+		let services = ServiceConfig::new(network, api_token, &config)?;
+		let destination_filter = DestinationFilter::new(&config.destination_policy, network)?;
+		let active_lsp = services.select_lsp(services::is_reachable).clone();
+
+		let storage_dir_path = ldk_node::config::Config::default().storage_dir_path;
+		std::fs::create_dir_all(&storage_dir_path).map_err(|_e| Error::FailedToBuildNode)?;
+		let kv_store = Arc::new(
+			SqliteStore::new(
+				storage_dir_path.into(),
+				Some(sqlite_store::SQLITE_DB_FILE_NAME.to_string()),
+				Some(sqlite_store::KV_TABLE_NAME.to_string()),
+			)
+			.map_err(|_e| Error::FailedToBuildNode)?,
 		);
+		let payment_records =
+			PersistedMap::load(Arc::clone(&kv_store), store::PAYMENT_RECORDS_SECONDARY_NAMESPACE)?;
+		let idempotency_keys =
+			PersistedMap::load(Arc::clone(&kv_store), store::IDEMPOTENCY_KEYS_SECONDARY_NAMESPACE)?;
+		let spend_tracker = SpendTracker::load(Arc::clone(&kv_store))?;
+		let audit_log = AuditLog::load(Arc::clone(&kv_store))?;
+		if config.audit_log {
+			let parameters = config.audit_parameters();
+			let changed = !matches!(
+				audit_log.last(AuditOperation::ConfigChanged),
+				Some(entry) if entry.parameters == parameters
+			);
+			if changed {
+				audit_log.record(
+					AuditOperation::ConfigChanged,
+					None,
+					parameters,
+					None,
+					unix_time_secs(),
+				)?;
+			}
+		}
+		let accounts = Accounts::load(Arc::clone(&kv_store))?;
+		let ledger = Ledger::load(Arc::clone(&kv_store))?;
 
-		let node = builder.build()?;
+		let route_estimator = RouteEstimator::new(network, Arc::clone(&kv_store));
+
+		let node = build_node(network, &services, &active_lsp, Arc::clone(&kv_store))?;
 
 		node.start()?;
 
 		let romer = Romer {
 			network,
+			config,
 			services,
+			destination_filter: Arc::new(destination_filter),
+			active_lsp,
 			node: Arc::new(node),
-			pending_payments: Arc::new(Mutex::new(HashMap::new())),
+			jit_requests: Arc::new(BoundedWorkers::new(MAX_LSP_JIT_REQUESTS)),
+			payment_records: Arc::new(payment_records),
+			idempotency_keys: Arc::new(idempotency_keys),
+			spend_tracker: Arc::new(spend_tracker),
+			audit_log: Arc::new(audit_log),
+			accounts: Arc::new(accounts),
+			internal_settlement_lock: Arc::new(Mutex::new(())),
+			ledger: Arc::new(ledger),
+			route_estimator: Arc::new(route_estimator),
+			exchange_rate_provider: Arc::new(RwLock::new(None)),
+			http_fetcher: Arc::new(RwLock::new(Arc::new(DefaultHttpFetcher::new()))),
+			payment_waiters: Arc::new(PaymentWaiters::new()),
+			shutdown: Arc::new(Shutdown::new()),
+			threads: Arc::new(Mutex::new(Vec::new())),
+			event_queue: Arc::new(EventQueue::new()),
+			#[cfg(feature = "metrics")]
+			metrics: Arc::new(Metrics::new()),
 		};
 
+		let mut threads = Vec::new();
 		let romer_events = romer.clone();
-		std::thread::spawn(move || {
+		threads.push(std::thread::spawn(move || {
 			romer_events.handle_events();
-		});
+		}));
+
+		let supervised_lsps =
+			romer.services.lsps.iter().map(|lsp| (lsp.node_id, lsp.address.clone())).collect();
+		let supervisor = LspSupervisor::new(
+			Arc::clone(&romer.node),
+			supervised_lsps,
+			Arc::clone(&romer.event_queue),
+			Arc::clone(&romer.shutdown),
+		);
+		threads.push(std::thread::spawn(move || {
+			supervisor.run();
+		}));
+
+		let hold_invoice_watcher = HoldInvoiceWatcher::new(
+			Arc::clone(&romer.node),
+			Arc::clone(&romer.payment_records),
+			romer.config.audit_log.then(|| Arc::clone(&romer.audit_log)),
+			Arc::clone(&romer.shutdown),
+		);
+		threads.push(std::thread::spawn(move || {
+			hold_invoice_watcher.run();
+		}));
+		*romer.threads.lock().unwrap() = threads;
 
 		Ok(romer)
 	}
 
+	/// Stop the node and the background threads handling its events.
+	///
+	/// Waits for an event that is being handled to be done with. The instance can't be used to
+	/// send or receive anymore afterwards, and calls waiting on the outcome of a payment fail
+	/// with [`Error::Stopped`].
+	pub fn stop(&self) -> Result<(), Error> {
+		self.shutdown.stop();
+		// Without the event handler, nobody would learn of the outcome of a payment anymore.
+		self.payment_waiters.close();
+		let threads = std::mem::take(&mut *self.threads.lock().unwrap());
+		for thread in threads {
+			if let Err(_e) = thread.join() {
+				// TODO: log?
+			}
+		}
+
+		self.node.stop()?;
+		Ok(())
+	}
+
 	/// Receive bitcoin over the lightning network by creating an invoice to be paid a specific amount.
 	///
 	/// Will automatically determine if you need more liquidity and provide either a JIT-channel invoice
 	/// or a regular bolt11 invoice as needed.
 	pub fn receive(&self, amount_sats: u64, description: &str) -> Result<Bolt11Invoice, Error> {
-		let amount_msat = amount_sats * 1000;
+		self.receive_with_options(amount_sats, description, ReceiveOptions::default())
+	}
+
+	/// Receive bitcoin over the lightning network, customizing the request via [`ReceiveOptions`].
+	///
+	/// Fails with [`Error::LspFeeTooHigh`] if a JIT channel is needed and the LSP charges more
+	/// than the applicable fee limits allow.
+	pub fn receive_with_options(
+		&self, amount_sats: u64, description: &str, options: ReceiveOptions,
+	) -> Result<Bolt11Invoice, Error> {
+		let actor_id = options.actor_id.clone();
+		let res = self.receive_inner(amount_sats, description, options);
+		#[cfg(feature = "metrics")]
+		if let Err(e) = &res {
+			self.metrics.record_receive_failure(e);
+		}
+
+		let mut parameters = HashMap::new();
+		parameters.insert("amount_sats".to_string(), amount_sats.to_string());
+		parameters.insert("description".to_string(), description.to_string());
+		if let Ok(invoice) = &res {
+			parameters.insert("invoice".to_string(), invoice.to_string());
+		}
+		self.audit(AuditOperation::Receive, actor_id, parameters, &res);
+		res
+	}
+
+	/// Receive bitcoin over the lightning network by creating an invoice to be paid an amount in a
+	/// fiat currency, e.g. `"USD"`.
+	///
+	/// The amount is converted to sats at the current rate of the exchange rate provider set via
+	/// [`Romer::set_exchange_rate_provider`], and the payment is valued in `currency` once it
+	/// settles.
+	pub fn receive_fiat(
+		&self, amount_fiat: f64, currency: &str, description: &str,
+	) -> Result<Bolt11Invoice, Error> {
+		let amount_sats = fiat::fiat_to_sats(amount_fiat, self.btc_price(currency)?)?;
+		let invoice = self.receive(amount_sats, description)?;
+
+		let key = hex_utils::to_string(&invoice.payment_hash().to_byte_array());
+		self.payment_records.update(&key, PaymentRecord::default, |record| {
+			record.fiat_currency = Some(currency.to_string())
+		})?;
+
+		Ok(invoice)
+	}
+
+	/// Receive a payment to a hold invoice for a `payment_hash` whose preimage only the caller
+	/// knows, deciding later whether to accept it.
+	///
+	/// Once the payment arrives, an [`Event::PaymentClaimable`] is emitted and the payment can be
+	/// settled via [`Romer::claim`] or refunded via [`Romer::cancel`]. Payments that are neither
+	/// are cancelled a few blocks before their claim deadline. Hold invoices can't be paid
+	/// internally by our own node, and need existing inbound liquidity, as LSPs don't open JIT
+	/// channels for them.
+	pub fn receive_hold(
+		&self, amount_sats: u64, description: &str, payment_hash: PaymentHash,
+	) -> Result<Bolt11Invoice, Error> {
+		let res = amount_sats
+			.checked_mul(1000)
+			.ok_or(Error::InvalidAmount)
+			.and_then(|amount_msat| {
+				let invoice = self.node.bolt11_payment().receive_for_hash(
+					amount_msat,
+					description,
+					DEFAULT_INVOICE_EXPIRY_SECS,
+					payment_hash,
+				)?;
+				Ok(invoice)
+			})
+			.and_then(|invoice| {
+				let key = hex_utils::to_string(&payment_hash.0);
+				self.payment_records
+					.update(&key, PaymentRecord::default, |record| record.hold_invoice = true)?;
+				Ok(invoice)
+			});
+		#[cfg(feature = "metrics")]
+		if let Err(e) = &res {
+			self.metrics.record_receive_failure(e);
+		}
+
+		let mut parameters = HashMap::new();
+		parameters.insert("amount_sats".to_string(), amount_sats.to_string());
+		parameters.insert("description".to_string(), description.to_string());
+		parameters.insert("payment_hash".to_string(), payment_hash.to_string());
+		self.audit(AuditOperation::Receive, None, parameters, &res);
+		res
+	}
+
+	/// List the payments to hold invoices that arrived and are waiting to be claimed or cancelled,
+	/// oldest first.
+	///
+	/// Returns the [`Event::PaymentClaimable`] of each, e.g. to decide on payments whose event was
+	/// emitted before a restart.
+	pub fn list_claimable(&self) -> Vec<Event> {
+		let mut payments = self.node.list_payments_with_filter(|payment| {
+			payment.direction == PaymentDirection::Inbound
+				&& payment.status == PaymentStatus::Pending
+		});
+		payments.sort_by_key(|payment| payment.latest_update_timestamp);
+		payments
+			.into_iter()
+			.filter_map(|payment| {
+				let record = self.payment_records.get(&hex_utils::to_string(&payment.id.0))?;
+				match record {
+					PaymentRecord {
+						hold_invoice: true,
+						claimable_amount_msat: Some(claimable_amount_msat),
+						claim_deadline,
+						..
+					} => Some(Event::PaymentClaimable {
+						payment_hash: payments::payment_hash(&payment.kind)?,
+						claimable_amount_msat,
+						claim_deadline,
+					}),
+					_ => None,
+				}
+			})
+			.collect()
+	}
+
+	/// Settle a payment to a hold invoice by revealing the preimage of its payment hash.
+	///
+	/// Fails with [`Error::PaymentNotClaimable`] if no payment to the hold invoice arrived yet,
+	/// or it was already claimed or cancelled.
+	pub fn claim(&self, preimage: PaymentPreimage) -> Result<(), Error> {
+		let payment_hash = PaymentHash(Sha256::hash(&preimage.0).to_byte_array());
+		let res = self.claim_inner(payment_hash, preimage);
+
+		let mut parameters = HashMap::new();
+		parameters.insert("payment_hash".to_string(), payment_hash.to_string());
+		self.audit(AuditOperation::Claim, None, parameters, &res);
+		res
+	}
+
+	fn claim_inner(
+		&self, payment_hash: PaymentHash, preimage: PaymentPreimage,
+	) -> Result<(), Error> {
+		let payment_id = PaymentId(payment_hash.0);
+		let record = self.payment_records.get(&hex_utils::to_string(&payment_id.0));
+		let claimable_amount_msat = match record {
+			Some(PaymentRecord { claimable_amount_msat: Some(amount_msat), .. }) => amount_msat,
+			_ => return Err(Error::PaymentNotClaimable),
+		};
+		if !matches!(self.node.payment(&payment_id), Some(p) if p.status == PaymentStatus::Pending)
+		{
+			return Err(Error::PaymentNotClaimable);
+		}
+		self.node.bolt11_payment().claim_for_hash(payment_hash, claimable_amount_msat, preimage)?;
+		Ok(())
+	}
+
+	/// Cancel a hold invoice, refunding the payment to it if one arrived.
+	///
+	/// Payments arriving later are refunded as well. Fails with [`Error::InvalidPaymentHash`] if
+	/// there is no hold invoice for the hash, and with [`Error::PaymentNotClaimable`] if its
+	/// payment was already claimed.
+	pub fn cancel(&self, payment_hash: PaymentHash) -> Result<(), Error> {
+		let res = self.cancel_inner(payment_hash);
+
+		let mut parameters = HashMap::new();
+		parameters.insert("payment_hash".to_string(), payment_hash.to_string());
+		self.audit(AuditOperation::Cancel, None, parameters, &res);
+		res
+	}
+
+	fn cancel_inner(&self, payment_hash: PaymentHash) -> Result<(), Error> {
+		let payment_id = PaymentId(payment_hash.0);
+		let record = self.payment_records.get(&hex_utils::to_string(&payment_id.0));
+		if !matches!(record, Some(PaymentRecord { hold_invoice: true, .. })) {
+			return Err(Error::InvalidPaymentHash);
+		}
+		if matches!(self.node.payment(&payment_id), Some(p) if p.status == PaymentStatus::Succeeded)
+		{
+			return Err(Error::PaymentNotClaimable);
+		}
+		self.node.bolt11_payment().fail_for_hash(payment_hash)?;
+		Ok(())
+	}
+
+	fn receive_inner(
+		&self, amount_sats: u64, description: &str, options: ReceiveOptions,
+	) -> Result<Bolt11Invoice, Error> {
+		let amount_msat = amount_sats.checked_mul(1000).ok_or(Error::InvalidAmount)?;
+		let tags = options.tags();
 
 		let inbound_liquidity_msat: u64 = self
 			.node
@@ -179,51 +629,529 @@ impl Romer {
 			.sum();
 
 		// TODO: probably some kind of factor required instead of straight comparison
-		let invoice = if inbound_liquidity_msat > amount_msat {
-			self.node.bolt11_payment().receive(
+		if inbound_liquidity_msat > amount_msat {
+			// We keep the preimage and claim the payment ourselves, so it can be refused if the
+			// invoice was settled internally before. LDK Node would claim it on its own otherwise.
+			// Every other payment is claimed as soon as it is claimable, see `ClaimAction::Claim`.
+			let preimage = PaymentPreimage(secp256k1::rand::random());
+			let payment_hash = PaymentHash(Sha256::hash(&preimage.0).to_byte_array());
+			let invoice = self.node.bolt11_payment().receive_for_hash(
 				amount_msat,
 				description,
 				DEFAULT_INVOICE_EXPIRY_SECS,
-			)?
-		} else {
-			self.node.bolt11_payment().receive_via_jit_channel(
+				payment_hash,
+			)?;
+			let key = hex_utils::to_string(&payment_hash.0);
+			self.payment_records.update(&key, PaymentRecord::default, |record| {
+				record.preimage = Some(preimage);
+				tags.apply(record);
+			})?;
+			return Ok(invoice);
+		}
+
+		let fee_limits = options.lsp_fee_limits.unwrap_or(self.config.lsp_fee_limits);
+		let invoice = self.receive_via_jit_channel(amount_msat, description, fee_limits)?;
+
+		// LDK Node replaces the amount with the one we claim, so keep the invoiced amount to know
+		// the fee the LSP took.
+		let key = hex_utils::to_string(&invoice.payment_hash().to_byte_array());
+		self.payment_records.update(&key, PaymentRecord::default, |record| {
+			record.jit_invoice_amount_msat = Some(amount_msat);
+			tags.apply(record);
+		})?;
+
+		Ok(invoice)
+	}
+
+	fn receive_via_jit_channel(
+		&self, amount_msat: u64, description: &str, fee_limits: LSPFeeLimits,
+	) -> Result<Bolt11Invoice, Error> {
+		let max_total_lsp_fee_limit_msat = config::max_total_opening_fee_msat(
+			&[&fee_limits, &self.active_lsp.fee_limits],
+			amount_msat,
+		);
+
+		// The LSP may never answer our request, so make sure we don't block the caller forever.
+		let node = Arc::clone(&self.node);
+		let description = description.to_string();
+		let res = self.jit_requests.run(LSP_JIT_REQUEST_TIMEOUT, move || {
+			node.bolt11_payment().receive_via_jit_channel(
 				amount_msat,
-				description,
+				&description,
 				DEFAULT_INVOICE_EXPIRY_SECS,
-				None,
-			)?
-		};
+				max_total_lsp_fee_limit_msat,
+			)
+		});
 
-		Ok(invoice)
+		match res {
+			Some(Ok(invoice)) => Ok(invoice),
+			Some(Err(
+				NodeError::ConnectionFailed
+				| NodeError::LiquidityRequestFailed
+				| NodeError::LiquiditySourceUnavailable,
+			))
+			| None => Err(Error::LspUnavailable),
+			Some(Err(NodeError::LiquidityFeeTooHigh)) => Err(Error::LspFeeTooHigh),
+			Some(Err(e)) => Err(e.into()),
+		}
 	}
 
-    /// Check if an invoice has been paid
-    pub fn invoice_paid(&self, invoice: &Bolt11Invoice) -> bool {
-        let payment_hash = PaymentHash(invoice.payment_hash().to_byte_array());
+	/// Check if an invoice has been paid
+	pub fn invoice_paid(&self, invoice: &Bolt11Invoice) -> bool {
+		let payment_hash = PaymentHash(invoice.payment_hash().to_byte_array());
 		let id = PaymentId(payment_hash.0);
-        self.node.payment(&id).map_or(false, |payment| {
-            matches!(payment.status, ldk_node::payment::PaymentStatus::Succeeded)
-        })
-    }
+		matches!(
+			self.get_payment(&id),
+			Some(payment) if payment.details.status == PaymentStatus::Succeeded
+		)
+	}
 
 	/// Send bitcoin over the lightning network by paying an invoice.
 	///
 	/// Returns the fee paid in millisatoshis in order to complete the payment.
 	pub fn send(&self, invoice: &str) -> Result<u64, Error> {
+		self.send_with_options(invoice, SendOptions::default())
+	}
+
+	/// Send bitcoin over the lightning network by paying an invoice, customizing the payment via
+	/// [`SendOptions`].
+	///
+	/// Returns the fee paid in millisatoshis in order to complete the payment. The applicable
+	/// routing fee limits are passed on to LDK's router, which never pays more than them or its
+	/// default budget of 1% plus 50 sats, including across retries. Fails with
+	/// [`Error::FeeLimitExceeded`] if there is no route within the limits but one within LDK's
+	/// default budget, and with [`Error::RouteNotFound`] if there is no route at all.
+	///
+	/// Fails with [`Error::DuplicatePayment`] if the invoice is already being paid or was paid
+	/// before, unless the same [`SendOptions::idempotency_key`] is given, in which case the
+	/// outcome of that payment is returned. See [`Romer::await_payment`] to wait on it otherwise.
+	///
+	/// Invoices issued by our own node, e.g. by another user of the same service, are settled
+	/// internally without a lightning hop and at no fee. Both sides are marked as succeeded and
+	/// [`Payment::internal`], and later payments to the invoice over lightning are refused. This
+	/// only works for invoices created via [`Romer::receive`] without a JIT channel, paying hold
+	/// or JIT channel invoices of our own node fails with [`Error::InternalSettlementUnsupported`].
+	pub fn send_with_options(&self, invoice: &str, options: SendOptions) -> Result<u64, Error> {
+		self.send_audited(None, invoice, options)
+	}
+
+	/// Pays an invoice, from the given sub-account if any, and records it in the audit log.
+	fn send_audited(
+		&self, account_id: Option<&str>, invoice: &str, options: SendOptions,
+	) -> Result<u64, Error> {
+		#[cfg(feature = "metrics")]
+		let started = Instant::now();
+		let actor_id = options.actor_id.clone();
+		let res = match account_id {
+			Some(account_id) => self.send_from_inner(account_id, invoice, options),
+			None => self.send_inner(invoice, options),
+		};
+		#[cfg(feature = "metrics")]
+		self.metrics.record_send(&res, started.elapsed());
+
+		let mut parameters = HashMap::new();
+		parameters.insert("invoice".to_string(), invoice.to_string());
+		if let Some(account_id) = account_id {
+			parameters.insert("account_id".to_string(), account_id.to_string());
+		}
+		if let Ok(fee_paid_msat) = &res {
+			parameters.insert("fee_paid_msat".to_string(), fee_paid_msat.to_string());
+		}
+		self.audit(AuditOperation::Send, actor_id, parameters, &res);
+		res
+	}
+
+	/// Send `amount_sats` to a Lightning Address, e.g. `name@domain.com`, with an optional
+	/// comment for the recipient.
+	///
+	/// Fetches an invoice from the recipient's LNURL-pay service and pays it like
+	/// [`Romer::send_with_options`], returning the fee paid in millisatoshis. Fails with
+	/// [`Error::InvalidAmount`] if the service doesn't accept the amount, and with
+	/// [`Error::LnurlInvoiceMismatch`] if the invoice isn't for the amount or doesn't commit to
+	/// the payment's description.
+	///
+	/// The service hands out a new invoice on every request, so retrying with the same
+	/// [`SendOptions::idempotency_key`] returns the outcome of the payment already made for it
+	/// instead of fetching another invoice.
+	pub fn send_to_lightning_address(
+		&self, address: &str, amount_sats: u64, comment: Option<String>, options: SendOptions,
+	) -> Result<u64, Error> {
+		let url = lnurl::lightning_address_url(address)?;
+		self.send_lnurl_pay_url(&url, amount_sats, comment, options)
+	}
+
+	/// Send `amount_sats` to a bech32-encoded LNURL-pay link, e.g. `lnurl1...`, with an optional
+	/// comment for the recipient.
+	///
+	/// Works like [`Romer::send_to_lightning_address`].
+	pub fn send_lnurl_pay(
+		&self, lnurl: &str, amount_sats: u64, comment: Option<String>, options: SendOptions,
+	) -> Result<u64, Error> {
+		let url = lnurl::decode_lnurl(lnurl)?;
+		self.send_lnurl_pay_url(&url, amount_sats, comment, options)
+	}
+
+	fn send_lnurl_pay_url(
+		&self, url: &str, amount_sats: u64, comment: Option<String>, options: SendOptions,
+	) -> Result<u64, Error> {
+		let amount_msat = amount_sats.checked_mul(1000).ok_or(Error::InvalidAmount)?;
+
+		if let Some(idempotency_key) = &options.idempotency_key {
+			let key = payments::idempotency_record_key(idempotency_key);
+			if let Some(payment_id) = self.idempotency_keys.get(&key) {
+				match self.get_payment(&payment_id) {
+					Some(Payment { internal: true, .. }) => return Ok(0),
+					Some(Payment {
+						details: PaymentDetails { kind: PaymentKind::Bolt11 { hash, .. }, .. },
+						..
+					}) => return self.wait_for_outcome(payment_id, hash),
+					// We never got to pay the invoice, so we are free to fetch another one.
+					_ => self.idempotency_keys.remove(&key)?,
+				}
+			}
+		}
+
+		let fetcher = Arc::clone(&*self.http_fetcher.read().unwrap());
+		let invoice = lnurl::fetch_invoice(&*fetcher, url, amount_msat, comment.as_deref())?;
+		self.send_with_options(&invoice.to_string(), options)
+	}
+
+	/// Set the [`HttpFetcher`] used to talk to LNURL services, replacing the default HTTP client.
+	pub fn set_http_fetcher(&self, fetcher: Box<dyn HttpFetcher>) {
+		*self.http_fetcher.write().unwrap() = Arc::from(fetcher);
+	}
+
+	fn send_inner(&self, invoice: &str, options: SendOptions) -> Result<u64, Error> {
 		let invoice = Bolt11Invoice::from_str(invoice).map_err(|_e| Error::InvalidBolt11Invoice)?;
+		let payee = invoice.recover_payee_pub_key();
+		if !self.destination_filter.allows_node(&payee) {
+			return Err(Error::DestinationNotAllowed);
+		}
+
+		let internal = payee == self.node.node_id();
 		let payment_hash = PaymentHash(invoice.payment_hash().to_byte_array());
-		let (sender, receiver) = mpsc::sync_channel::<Result<u64, PaymentFailureReason>>(1);
-		{
-			let mut pending_payments = self.pending_payments.lock().unwrap();
-			pending_payments.insert(payment_hash, sender);
+		let payment_id = self.outbound_payment_id(&invoice);
+
+		if let Some(idempotency_key) = &options.idempotency_key {
+			// The key is persisted before paying, so we never lose track of a payment we started.
+			let key = payments::idempotency_record_key(idempotency_key);
+			if self.idempotency_keys.get_or_insert(&key, payment_id)? != payment_id {
+				return Err(Error::IdempotencyKeyConflict);
+			}
+			if internal {
+				// Internal payments settle right away, so if we know it, it succeeded.
+				if self.get_payment(&payment_id).is_some() {
+					return Ok(0);
+				}
+			} else if self.node.payment(&payment_id).is_some() {
+				return self.wait_for_outcome(payment_id, payment_hash);
+			}
+		}
+
+		let amount_msat = invoice.amount_milli_satoshis().ok_or(Error::InvalidAmount)?;
+		let reserve_spend = || {
+			self.spend_tracker.reserve(
+				&self.config.spending_policy,
+				amount_msat,
+				Some(payment_id),
+				options.second_factor_confirmed,
+				unix_time_secs(),
+			)
+		};
+
+		if internal {
+			let spend_key = reserve_spend()?;
+			let res = self.settle_internally(&invoice, payment_id, amount_msat, options.tags());
+			if res.is_err() {
+				if let Err(_e) = self.spend_tracker.release(&spend_key) {
+					// TODO: log?
+				}
+			}
+			return res.map(|()| 0);
+		}
+
+		let fee_limits = options.routing_fee_limits.unwrap_or(self.config.routing_fee_limits);
+		let max_fee_msat = fee_limits.max_fee_msat(amount_msat);
+		let share_outcome = options.idempotency_key.is_some();
+		// Concurrent calls for the same invoice don't get to pay it twice, and share its outcome
+		// if they use the same idempotency key.
+		let res = self.payment_waiters.pay(payment_hash, share_outcome, || {
+			// Like the idempotency key, the tags are persisted before paying, so they aren't lost
+			// if we crash in between. A payment we made before keeps its tags, LDK refuses to pay
+			// it again.
+			if !matches!(self.node.payment(&payment_id), Some(p) if p.status != PaymentStatus::Failed)
+			{
+				self.tag_payment(&payment_id, options.tags())?;
+			}
+			let spend_key = reserve_spend()?;
+			let sending_parameters = max_fee_msat.map(|max_fee_msat| {
+				// Setting a limit replaces LDK's default budget, so keep that as the ceiling.
+				let max_fee_msat = max_fee_msat.min(limits::routing_fee_budget_msat(amount_msat));
+				SendingParameters {
+					max_total_routing_fee_msat: Some(Some(max_fee_msat)),
+					max_total_cltv_expiry_delta: None,
+					max_path_count: None,
+					max_channel_saturation_power_of_half: None,
+				}
+			});
+			let initiated = self.node.bolt11_payment().send(&invoice, sending_parameters);
+			if let Err(e) = initiated {
+				if let Err(_e) = self.spend_tracker.release(&spend_key) {
+					// TODO: log?
+				}
+				return Err(match e {
+					// LDK fails right away if there is no route within the fee limits.
+					NodeError::PaymentSendingFailed if invoice.is_expired() => {
+						Error::PaymentExpired
+					},
+					NodeError::PaymentSendingFailed => Error::RouteNotFound,
+					e => e.into(),
+				});
+			}
+			Ok(())
+		});
+		match res {
+			// We paid the invoice before, e.g. before a restart, so share that outcome.
+			Err(Error::DuplicatePayment) if share_outcome => {
+				self.wait_for_outcome(payment_id, payment_hash)
+			},
+			Err(Error::RouteNotFound) => {
+				Err(self.route_failure(&invoice, amount_msat, max_fee_msat))
+			},
+			res => res,
+		}
+	}
+
+	/// Tells a payment that found no route within our routing fee limits apart from one that
+	/// found no route at all.
+	fn route_failure(
+		&self, invoice: &Bolt11Invoice, amount_msat: u64, max_fee_msat: Option<u64>,
+	) -> Error {
+		match self.estimate_route_fee(invoice, amount_msat, max_fee_msat) {
+			Err(Error::FeeLimitExceeded) => Error::FeeLimitExceeded,
+			_ => Error::RouteNotFound,
+		}
+	}
+
+	/// The id our payment of `invoice` is tracked under.
+	fn outbound_payment_id(&self, invoice: &Bolt11Invoice) -> PaymentId {
+		let payment_hash = PaymentHash(invoice.payment_hash().to_byte_array());
+		if invoice.recover_payee_pub_key() == self.node.node_id() {
+			payments::internal_payment_id(&payment_hash)
+		} else {
+			PaymentId(payment_hash.0)
+		}
+	}
+
+	/// Settles an invoice of our own node by recording it as paid on both sides, as LDK can't pay
+	/// it over lightning.
+	fn settle_internally(
+		&self, invoice: &Bolt11Invoice, payment_id: PaymentId, amount_msat: u64, tags: PaymentTags,
+	) -> Result<(), Error> {
+		// Make sure the same invoice can't be settled twice.
+		let _lock = self.internal_settlement_lock.lock().unwrap();
+
+		let payment_hash = PaymentHash(invoice.payment_hash().to_byte_array());
+		let inbound_id = PaymentId(payment_hash.0);
+		let inbound = match self.get_payment(&inbound_id) {
+			Some(payment) if payment.details.direction == PaymentDirection::Inbound => payment,
+			_ => return Err(Error::InvalidBolt11Invoice),
+		};
+		let inbound_record =
+			self.payment_records.get(&hex_utils::to_string(&inbound_id.0)).unwrap_or_default();
+		// We can only refuse payments over lightning to invoices we claim ourselves. Hold
+		// invoices are claimed by whoever knows the preimage, and LDK Node claims payments to JIT
+		// invoices on its own.
+		let preimage = match inbound_record.preimage {
+			Some(preimage) => preimage,
+			None => return Err(Error::InternalSettlementUnsupported),
+		};
+		if inbound_record.claimable_amount_msat.is_some() {
+			// It was already paid over lightning.
+			return Err(Error::DuplicatePayment);
+		}
+		match inbound.details.status {
+			PaymentStatus::Pending if !invoice.is_expired() => {},
+			PaymentStatus::Succeeded => return Err(Error::DuplicatePayment),
+			_ => return Err(Error::InvalidBolt11Invoice),
+		}
+		let secret = match inbound.details.kind {
+			PaymentKind::Bolt11 { secret, .. } => secret,
+			_ => None,
+		};
+
+		// Refuse payments to the invoice over lightning from now on.
+		self.node.bolt11_payment().fail_for_hash(payment_hash)?;
+
+		let now = unix_time_secs();
+		let details = PaymentDetails {
+			id: payment_id,
+			kind: PaymentKind::Bolt11 { hash: payment_hash, preimage: Some(preimage), secret },
+			amount_msat: Some(amount_msat),
+			direction: PaymentDirection::Outbound,
+			status: PaymentStatus::Succeeded,
+			latest_update_timestamp: now,
+		};
+		self.payment_records.update(
+			&hex_utils::to_string(&payment_id.0),
+			PaymentRecord::default,
+			|record| {
+				record.untracked_details = Some(details);
+				record.fee_paid_msat = Some(0);
+				record.settled_internally_at = Some(now);
+				tags.apply(record);
+			},
+		)?;
+		self.payment_records.update(
+			&hex_utils::to_string(&inbound_id.0),
+			PaymentRecord::default,
+			|record| record.settled_internally_at = Some(now),
+		)?;
+
+		self.record_in_ledger(LedgerEntryKind::PaymentSent, amount_msat, false, payment_id);
+		self.record_in_ledger(LedgerEntryKind::PaymentReceived, amount_msat, true, inbound_id);
+		self.record_fiat_value(&payment_id, amount_msat);
+		self.record_fiat_value(&inbound_id, amount_msat);
+		Ok(())
+	}
+
+	/// List the outbound lightning payments that are still in flight, including those initiated
+	/// before a restart.
+	pub fn pending_outbound_payments(&self) -> Vec<Payment> {
+		self.list_payments_with_filter(|payment| {
+			payment.details.direction == PaymentDirection::Outbound
+				&& payment.details.status == PaymentStatus::Pending
+		})
+	}
+
+	/// Wait for the final outcome of an outbound lightning payment that was already initiated,
+	/// e.g. before a restart.
+	///
+	/// Returns the fee paid in millisatoshis once the payment succeeded, or right away if it
+	/// already has. Fails with [`Error::InvalidPaymentId`] if there is no outbound lightning
+	/// payment with the given id.
+	pub fn await_payment(&self, payment_id: &PaymentId) -> Result<u64, Error> {
+		let details = self.node.payment(payment_id).ok_or(Error::InvalidPaymentId)?;
+		let payment_hash = match payments::payment_hash(&details.kind) {
+			Some(payment_hash) if details.direction == PaymentDirection::Outbound => payment_hash,
+			_ => return Err(Error::InvalidPaymentId),
+		};
+		let res = self.wait_for_outcome(*payment_id, payment_hash);
+		#[cfg(feature = "metrics")]
+		self.metrics.record_awaited(&res);
+		res
+	}
+
+	/// Waits for the outcome of an outbound payment that was already initiated.
+	fn wait_for_outcome(
+		&self, payment_id: PaymentId, payment_hash: PaymentHash,
+	) -> Result<u64, Error> {
+		let waiter = self.payment_waiters.register(payment_hash);
+
+		// We record the outcome before notifying waiters, so if it isn't recorded yet, we will be
+		// notified.
+		let record = self.payment_records.get(&hex_utils::to_string(&payment_id.0));
+		let status = self.node.payment(&payment_id).map(|details| details.status);
+		let outcome = match (status, record) {
+			(
+				Some(PaymentStatus::Succeeded),
+				Some(PaymentRecord { fee_paid_msat: Some(fee_paid_msat), .. }),
+			) => Some(Ok(fee_paid_msat)),
+			(
+				Some(PaymentStatus::Failed),
+				Some(PaymentRecord { failure_reason: Some(reason), .. }),
+			) => Some(Err(reason.into())),
+			_ => None,
+		};
+		if let Some(outcome) = outcome {
+			return outcome;
 		}
-		let _payment_id = self.node.bolt11_payment().send(&invoice)?;
-		let fee_paid_msat = receiver.recv().unwrap()?;
+
+		let fee_paid_msat = waiter.wait()?;
 		Ok(fee_paid_msat)
 	}
 
+	/// Estimate the routing fee for paying an invoice, before committing to pay it.
+	///
+	/// Asks LDK's router for the cheapest few routes to the recipient that fit
+	/// [`Config::routing_fee_limits`] and LDK's default routing fee budget of 1% plus 50 sats,
+	/// based on the network graph and what LDK's scorer learned about channel liquidity from
+	/// earlier payments. These are the routes LDK tries when the invoice is paid.
+	///
+	/// If `probe` is set, we also send probes along the routes LDK would pay over, which teaches
+	/// LDK's scorer which channels have enough liquidity ahead of the actual payment. LDK Node
+	/// doesn't report the outcome of probes, so they only improve later estimates and payments.
+	///
+	/// Fails with [`Error::InvalidAmount`] if the invoice doesn't specify an amount, with
+	/// [`Error::FeeLimitExceeded`] if all routes cost more than our fee limits allow and with
+	/// [`Error::RouteNotFound`] if there is no route within LDK's budget.
+	pub fn estimate_fee(&self, invoice: &str, probe: bool) -> Result<FeeEstimate, Error> {
+		let invoice = Bolt11Invoice::from_str(invoice).map_err(|_e| Error::InvalidBolt11Invoice)?;
+		let amount_msat = invoice.amount_milli_satoshis().ok_or(Error::InvalidAmount)?;
+		let max_fee_msat = self.config.routing_fee_limits.max_fee_msat(amount_msat);
+		let estimate = self
+			.estimate_route_fee(&invoice, amount_msat, max_fee_msat)?
+			.ok_or(Error::RouteNotFound)?;
+		if probe {
+			self.route_estimator.probe(|| self.node.bolt11_payment().send_probes(&invoice))?;
+		}
+		Ok(estimate)
+	}
+
+	/// Estimates the fee for paying `invoice` within `max_fee_msat` and LDK's default budget.
+	///
+	/// Fails with [`Error::FeeLimitExceeded`] if there are routes within LDK's budget, but none
+	/// within `max_fee_msat`.
+	fn estimate_route_fee(
+		&self, invoice: &Bolt11Invoice, amount_msat: u64, max_fee_msat: Option<u64>,
+	) -> Result<Option<FeeEstimate>, Error> {
+		let payer = self.node.node_id();
+		let channels = self.node.list_channels();
+		let estimate = |fee_budget_msat| {
+			self.route_estimator.estimate(payer, &channels, invoice, fee_budget_msat)
+		};
+		let default_budget_msat = limits::routing_fee_budget_msat(amount_msat);
+		match max_fee_msat {
+			Some(max_fee_msat) if max_fee_msat < default_budget_msat => {
+				match estimate(max_fee_msat) {
+					Some(estimate) => Ok(Some(estimate)),
+					None if estimate(default_budget_msat).is_some() => Err(Error::FeeLimitExceeded),
+					None => Ok(None),
+				}
+			},
+			_ => Ok(estimate(default_budget_msat)),
+		}
+	}
+
 	/// Send bitcoin onchain to an address.
 	pub fn send_onchain(&self, address: &str, amount_sats: u64) -> Result<Txid, Error> {
+		self.send_onchain_with_options(address, amount_sats, SendOnchainOptions::default())
+	}
+
+	/// Send bitcoin onchain to an address, customizing the payment via [`SendOnchainOptions`].
+	///
+	/// The payment is listed as succeeded once the transaction has been broadcast.
+	pub fn send_onchain_with_options(
+		&self, address: &str, amount_sats: u64, options: SendOnchainOptions,
+	) -> Result<Txid, Error> {
+		let actor_id = options.actor_id.clone();
+		let res = self.send_onchain_inner(address, amount_sats, options);
+		#[cfg(feature = "metrics")]
+		self.metrics.record_onchain_send(&res);
+
+		let mut parameters = HashMap::new();
+		parameters.insert("address".to_string(), address.to_string());
+		parameters.insert("amount_sats".to_string(), amount_sats.to_string());
+		if let Ok(txid) = &res {
+			parameters.insert("txid".to_string(), txid.to_string());
+		}
+		self.audit(AuditOperation::SendOnchain, actor_id, parameters, &res);
+		res
+	}
+
+	fn send_onchain_inner(
+		&self, address: &str, amount_sats: u64, options: SendOnchainOptions,
+	) -> Result<Txid, Error> {
 		let address = Address::from_str(address).map_err(|_e| Error::InvalidBitcoinAddress)?;
 
 		if !address.is_valid_for_network(self.network) {
@@ -232,13 +1160,289 @@ impl Romer {
 
 		let address = address.assume_checked();
 
-		let txid = self.node.onchain_payment().send_to_address(&address, amount_sats)?;
+		if !self.destination_filter.allows_address(&address) {
+			return Err(Error::DestinationNotAllowed);
+		}
+
+		let amount_msat = amount_sats.checked_mul(1000).ok_or(Error::InvalidAmount)?;
+		let spend_key = self.spend_tracker.reserve(
+			&self.config.spending_policy,
+			amount_msat,
+			None,
+			options.second_factor_confirmed,
+			unix_time_secs(),
+		)?;
+		let txid = match self.node.onchain_payment().send_to_address(&address, amount_sats) {
+			Ok(txid) => txid,
+			Err(e) => {
+				if let Err(_e) = self.spend_tracker.release(&spend_key) {
+					// TODO: log?
+				}
+				return Err(e.into());
+			},
+		};
+
+		// LDK Node doesn't track on-chain payments, so we keep their details ourselves.
+		let details = PaymentDetails {
+			id: PaymentId(txid.to_byte_array()),
+			kind: PaymentKind::Onchain,
+			amount_msat: Some(amount_msat),
+			direction: PaymentDirection::Outbound,
+			status: PaymentStatus::Succeeded,
+			latest_update_timestamp: unix_time_secs(),
+		};
+		let payment_id = details.id;
+		let timestamp = details.latest_update_timestamp;
+		let key = payments::record_key(&details);
+		let tags = options.tags();
+		if let Err(_e) = self.payment_records.update(&key, PaymentRecord::default, |record| {
+			record.untracked_details = Some(details);
+			tags.apply(record);
+		}) {
+			// TODO: log?
+		}
+
+		self.record_fiat_value(&payment_id, amount_msat);
+
+		let ledger_res = self
+			.ledger
+			.record(
+				LedgerEntryKind::OnchainPaymentSent,
+				amount_msat,
+				false,
+				Some(payment_id),
+				timestamp,
+			)
+			.map(|()| self.ledger.expect_cost(LedgerEntryKind::OnchainFee, timestamp));
+		if let Err(_e) = ledger_res {
+			// TODO: log?
+		}
+
 		Ok(txid)
 	}
 
-	/// List all payments send or received.
-	pub fn list_payments(&self) -> Vec<PaymentDetails> {
-		self.node.list_payments()
+	/// Create a sub-account that payments and funds can be attributed to, e.g. for an end user.
+	///
+	/// Account ids may only contain letters, digits, `-` and `_`, and are at most 120 characters
+	/// long.
+	pub fn create_account(&self, account_id: &str) -> Result<Account, Error> {
+		let res = self.accounts.create(account_id, unix_time_secs());
+
+		let mut parameters = HashMap::new();
+		parameters.insert("account_id".to_string(), account_id.to_string());
+		self.audit(AuditOperation::CreateAccount, None, parameters, &res);
+		res
+	}
+
+	/// List all sub-accounts, oldest first.
+	pub fn list_accounts(&self) -> Vec<Account> {
+		self.accounts.list()
+	}
+
+	/// Receive a payment into a sub-account, see [`Romer::receive_with_options`].
+	pub fn receive_for(
+		&self, account_id: &str, amount_sats: u64, description: &str, options: ReceiveOptions,
+	) -> Result<Bolt11Invoice, Error> {
+		self.accounts.ensure_exists(account_id)?;
+		let invoice = self.receive_with_options(amount_sats, description, options)?;
+		let key = hex_utils::to_string(&invoice.payment_hash().to_byte_array());
+		self.payment_records.update(&key, PaymentRecord::default, |record| {
+			record.account_id = Some(account_id.to_string())
+		})?;
+		Ok(invoice)
+	}
+
+	/// Pay an invoice from a sub-account, see [`Romer::send_with_options`].
+	///
+	/// Fails with [`Error::InsufficientAccountBalance`] without paying if the account's balance
+	/// doesn't cover the amount plus LDK's default routing fee budget of 1% plus 50 sats, taking
+	/// payments still in flight into account. Only the fee actually paid is deducted.
+	pub fn send_from(
+		&self, account_id: &str, invoice: &str, options: SendOptions,
+	) -> Result<u64, Error> {
+		self.send_audited(Some(account_id), invoice, options)
+	}
+
+	fn send_from_inner(
+		&self, account_id: &str, invoice: &str, options: SendOptions,
+	) -> Result<u64, Error> {
+		let parsed = Bolt11Invoice::from_str(invoice).map_err(|_e| Error::InvalidBolt11Invoice)?;
+		let amount_msat = parsed.amount_milli_satoshis().ok_or(Error::InvalidAmount)?;
+		let payment_id = self.outbound_payment_id(&parsed);
+
+		// Sending again with an idempotency key returns the outcome of the payment we already
+		// deducted, so there is nothing to reserve.
+		if self.is_attributed(&payment_id, account_id) {
+			return self.send_inner(invoice, options);
+		}
+
+		let reservation = self.accounts.reserve(
+			account_id,
+			amount_msat,
+			|| self.attributed_payments(),
+			|| self.attribute_payment(&payment_id, account_id),
+		)?;
+		let res = self.send_inner(invoice, options);
+		drop(reservation);
+
+		if res.is_err() && self.get_payment(&payment_id).is_none() {
+			// The payment was never initiated, so don't attribute it if it is paid another way.
+			let key = hex_utils::to_string(&payment_id.0);
+			if let Err(_e) = self
+				.payment_records
+				.update(&key, PaymentRecord::default, |record| record.account_id = None)
+			{
+				// TODO: log?
+			}
+		}
+		res
+	}
+
+	/// Get the balance of a sub-account, derived from the payments attributed to it and transfers.
+	pub fn account_balance(&self, account_id: &str) -> Result<AccountBalance, Error> {
+		self.accounts.ensure_exists(account_id)?;
+		Ok(self.accounts.balance(account_id, &self.attributed_payments()))
+	}
+
+	/// Move funds from one sub-account to another, without a lightning payment.
+	///
+	/// Fails with [`Error::InsufficientAccountBalance`] if `from_account_id` can't cover the
+	/// amount, taking payments still in flight into account.
+	pub fn transfer_between_accounts(
+		&self, from_account_id: &str, to_account_id: &str, amount_sats: u64,
+		options: TransferOptions,
+	) -> Result<AccountTransfer, Error> {
+		let mut parameters = HashMap::new();
+		parameters.insert("from_account_id".to_string(), from_account_id.to_string());
+		parameters.insert("to_account_id".to_string(), to_account_id.to_string());
+		parameters.insert("amount_sats".to_string(), amount_sats.to_string());
+		if let Some(memo) = &options.memo {
+			parameters.insert("memo".to_string(), memo.clone());
+		}
+		if let Some(idempotency_key) = &options.idempotency_key {
+			parameters.insert("idempotency_key".to_string(), idempotency_key.clone());
+		}
+		let actor_id = options.actor_id.clone();
+
+		let res =
+			amount_sats.checked_mul(1000).ok_or(Error::InvalidAmount).and_then(|amount_msat| {
+				self.accounts.transfer(
+					from_account_id,
+					to_account_id,
+					amount_msat,
+					options,
+					unix_time_secs(),
+					|| self.attributed_payments(),
+				)
+			});
+		if let Ok(transfer) = &res {
+			parameters.insert("sequence".to_string(), transfer.sequence.to_string());
+		}
+		self.audit(AuditOperation::TransferBetweenAccounts, actor_id, parameters, &res);
+		res
+	}
+
+	/// List the transfers from or to a sub-account, in order.
+	pub fn account_transfers(&self, account_id: &str) -> Result<Vec<AccountTransfer>, Error> {
+		self.accounts.ensure_exists(account_id)?;
+		Ok(self.accounts.transfers(account_id))
+	}
+
+	/// Compare the sum of all sub-account balances with what the node actually holds.
+	///
+	/// The node's balances are the source of truth. Anything not attributed to an account, like
+	/// payments made without one and channel opening or on-chain fees, shows up as unattributed.
+	pub fn reconcile_accounts(&self) -> AccountReconciliation {
+		let balances = self.node.list_balances();
+		let node_balance_msat =
+			(balances.total_onchain_balance_sats + balances.total_lightning_balance_sats) * 1000;
+		self.accounts.reconcile(&self.attributed_payments(), node_balance_msat)
+	}
+
+	/// List all payments send or received, along with any labels and metadata attached to them.
+	pub fn list_payments(&self) -> Vec<Payment> {
+		self.list_payments_with_filter(|_| true)
+	}
+
+	/// Get a payment by its id.
+	pub fn get_payment(&self, payment_id: &PaymentId) -> Option<Payment> {
+		match self.node.payment(payment_id) {
+			Some(details) => Some(self.payment_from_details(details)),
+			None => {
+				let record = self.payment_records.get(&hex_utils::to_string(&payment_id.0))?;
+				let details = record.untracked_details.clone()?;
+				Some(Payment::new(details, record))
+			},
+		}
+	}
+
+	/// Get a payment by its payment hash.
+	pub fn get_payment_by_hash(&self, payment_hash: &PaymentHash) -> Option<Payment> {
+		let has_hash = |details: &PaymentDetails| {
+			payments::payment_hash(&details.kind).as_ref() == Some(payment_hash)
+		};
+
+		// Lightning payments are usually identified by their payment hash, so try that first.
+		let details = match self.node.payment(&PaymentId(payment_hash.0)) {
+			Some(details) if has_hash(&details) => Some(details),
+			_ => self.node.list_payments_with_filter(|details| has_hash(details)).pop(),
+		};
+		details.map(|details| self.payment_from_details(details))
+	}
+
+	/// Get the payment made to or received for the given invoice.
+	pub fn get_payment_for_invoice(&self, invoice: &Bolt11Invoice) -> Option<Payment> {
+		self.get_payment_by_hash(&PaymentHash(invoice.payment_hash().to_byte_array()))
+	}
+
+	/// List the payments matching `filter`, most recently updated first, one page at a time.
+	///
+	/// Pass the [`PaymentPage::next_cursor`] of a page in [`Page::cursor`] to fetch the next one.
+	/// Note that a payment which is updated while paging moves to the front of the list.
+	pub fn list_payments_filtered(
+		&self, filter: PaymentFilter, page: Page,
+	) -> Result<PaymentPage, Error> {
+		let payments = self.list_payments_with_filter(|payment| filter.matches(payment));
+		payments::paginate(payments, &page)
+	}
+
+	/// Export the payments last updated within `range`, oldest first, for accounting.
+	///
+	/// Lightning and on-chain payments are listed together with the columns `timestamp`,
+	/// `direction`, `kind`, `amount_msat`, `fee_msat`, `status`, `reference` (the payment hash or
+	/// txid) and `label`.
+	///
+	/// LDK Node doesn't track the on-chain payments we receive, so they are listed as the
+	/// [`LedgerEntryKind::OnchainDeposit`]s of [`Romer::ledger`] instead, without a txid and at
+	/// the time the ledger noticed them once the on-chain wallet synced.
+	pub fn export_payments(&self, format: ExportFormat, range: TimeRange) -> String {
+		self.reconcile_ledger();
+		let payments = self.list_payments_with_filter(|payment| {
+			range.contains(payment.details.latest_update_timestamp)
+		});
+		let deposits = self
+			.ledger
+			.entries(&range)
+			.into_iter()
+			.filter(|entry| entry.kind == LedgerEntryKind::OnchainDeposit)
+			.collect();
+		export::export(payments, deposits, format)
+	}
+
+	fn list_payments_with_filter<F: Fn(&Payment) -> bool>(&self, f: F) -> Vec<Payment> {
+		let mut payments: Vec<Payment> = self
+			.node
+			.list_payments()
+			.into_iter()
+			.map(|details| self.payment_from_details(details))
+			.filter(|payment| f(payment))
+			.collect();
+		let onchain_payments = self.payment_records.values().into_iter().filter_map(|record| {
+			let details = record.untracked_details.clone()?;
+			Some(Payment::new(details, record))
+		});
+		payments.extend(onchain_payments.filter(|payment| f(payment)));
+		payments
 	}
 
 	/// Get balance information.
@@ -251,9 +1455,9 @@ impl Romer {
 		let inbound_capacity_lightning_sats: u64 =
 			channels.iter().map(|channel| channel.inbound_capacity_msat / 1000).sum();
 
-		// TODO: need to take into account min/max htlc sizes.
-		//       should maybe add the "maybe claimable" portion of the lightning balance
-		//       such a mess, what do we want to do here?
+		// These are naive sums, see `max_sendable_lightning` and `max_receivable_without_jit` for
+		// what can actually be sent and received, and `lightning_balance_breakdown` for where the
+		// lightning balance is.
 
 		Balances {
 			total_onchain_balance_sats: balance_details.total_onchain_balance_sats,
@@ -265,18 +1469,111 @@ impl Romer {
 		}
 	}
 
+	/// Set the source of exchange rates used to value balances and payments in fiat.
+	pub fn set_exchange_rate_provider(&self, provider: Box<dyn ExchangeRateProvider>) {
+		*self.exchange_rate_provider.write().unwrap() = Some(Arc::from(provider));
+		self.audit(AuditOperation::ExchangeRateProviderSet, None, HashMap::new(), &Ok(()));
+	}
+
+	/// Get balance information, valued in a fiat currency, e.g. `"USD"`.
+	///
+	/// Fails with [`Error::ExchangeRateUnavailable`] if no exchange rate provider is set or it has
+	/// no rate for `currency`.
+	pub fn fiat_balance(&self, currency: &str) -> Result<FiatBalances, Error> {
+		let btc_price = self.btc_price(currency)?;
+		Ok(FiatBalances::new(&self.balance(), currency.to_string(), btc_price))
+	}
+
+	/// List the ledger entries recorded within `range`, in order.
+	///
+	/// Every change to our balance is recorded as a double-entry movement between our
+	/// [`LedgerAccount::Wallet`] and another account, along with the running balance. Costs LDK
+	/// Node doesn't report, like on-chain and channel closing fees, are derived from our balance
+	/// once the on-chain wallet has synced, so they may show up with a delay. The same goes for
+	/// the [`LedgerEntryKind::OpeningBalance`] we started out with.
+	pub fn ledger(&self, range: TimeRange) -> Result<Vec<LedgerEntry>, Error> {
+		self.ledger.reconcile(&self.balance_snapshot(), unix_time_secs())?;
+		Ok(self.ledger.entries(&range))
+	}
+
+	/// List the audit log entries recorded within `range`, in order.
+	///
+	/// Payments rejected by [`Config::destination_policy`] are always recorded here, all other
+	/// operations only if [`Config::audit_log`] is enabled.
+	pub fn audit_log(&self, range: TimeRange) -> Vec<AuditEntry> {
+		self.audit_log.entries(&range)
+	}
+
+	/// Check that no audit log entry was changed, removed or inserted since it was recorded.
+	///
+	/// Returns the number of entries verified, or fails with [`Error::AuditLogTampered`]. Removal
+	/// of the most recent entries while the node was stopped can only be detected by comparing
+	/// against an [`AuditEntry::hash`] kept elsewhere.
+	pub fn verify_audit_log(&self) -> Result<u64, Error> {
+		self.audit_log.verify()
+	}
+
+	/// Get the largest amount we can currently send over lightning.
+	///
+	/// Accounts for the channel reserve, in-flight HTLCs, per-channel HTLC limits, the commitment
+	/// fee buffer and LDK's default routing fee budget of 1% plus 50 sats. Without multi-path
+	/// payments, an amount is limited to what fits through a single channel.
+	pub fn max_sendable_lightning(&self) -> MaxAmounts {
+		limits::max_sendable(&self.node.list_channels())
+	}
+
+	/// Get the largest amount we can currently receive over lightning without the LSP opening a
+	/// JIT channel.
+	///
+	/// Accounts for our peers' reserves, per-channel HTLC limits and a buffer for the commitment
+	/// fees our peers pay in channels they funded. Without multi-path payments, an amount is
+	/// limited to what fits through a single channel.
+	pub fn max_receivable_without_jit(&self) -> MaxAmounts {
+		limits::max_receivable(&self.node.list_channels())
+	}
+
+	/// Get a breakdown of where the funds making up our lightning balance currently are.
+	///
+	/// Unlike [`Balances::total_lightning_balance_sats`], this separates what is spendable now
+	/// from in-flight HTLCs, pending channel opens, funds awaiting on-chain claims after a
+	/// channel closed, and channel reserves.
+	pub fn lightning_balance_breakdown(&self) -> LightningBalanceBreakdown {
+		balance::breakdown(&self.node.list_channels(), &self.node.list_balances())
+	}
+
+	/// Render payment, balance and sync health metrics in the Prometheus text format.
+	///
+	/// Counters and histograms cover the lifetime of this process, while balance and sync lag
+	/// gauges are sampled on every call.
+	#[cfg(feature = "metrics")]
+	pub fn render_metrics(&self) -> String {
+		self.metrics.render(&self.balance(), &self.status(), unix_time_secs())
+	}
+
 	/// Get status about the node.
 	///
 	/// Useful for debugging payment issues.
 	pub fn status(&self) -> Status {
 		let ldk_status = self.node.status();
-		let usable_channels = self.node.list_channels().into_iter().find(|c| c.is_usable).is_some();
-		let connected = self
-			.node
-			.list_peers()
-			.into_iter()
-			.find(|p| p.is_connected && p.node_id == self.services.lsp_node_id)
-			.is_some();
+		let channels = self.node.list_channels();
+		let peers = self.node.list_peers();
+		let usable_channels = channels.iter().any(|c| c.is_usable);
+		let active_lsp = self.active_lsp.node_id;
+
+		let lsps: Vec<LspStatus> = self
+			.services
+			.lsps
+			.iter()
+			.map(|lsp| LspStatus {
+				node_id: lsp.node_id,
+				active: lsp.node_id == active_lsp,
+				connected: peers.iter().any(|p| p.is_connected && p.node_id == lsp.node_id),
+				usable_channels: channels
+					.iter()
+					.any(|c| c.is_usable && c.counterparty_node_id == lsp.node_id),
+			})
+			.collect();
+		let connected = lsps.iter().any(|lsp| lsp.active && lsp.connected);
 
 		Status {
 			node_id: self.node.node_id().to_string(),
@@ -284,47 +1581,455 @@ impl Romer {
 			usable_channels,
 			best_block_height: ldk_status.current_best_block.height,
 			best_block_hash: ldk_status.current_best_block.block_hash,
-			latest_wallet_sync_timestamp: ldk_status.latest_wallet_sync_timestamp,
+			latest_wallet_sync_timestamp: ldk_status.latest_lightning_wallet_sync_timestamp,
 			latest_onchain_wallet_sync_timestamp: ldk_status.latest_onchain_wallet_sync_timestamp,
 			latest_fee_rate_cache_update_timestamp: ldk_status
 				.latest_fee_rate_cache_update_timestamp,
 			latest_rgs_snapshot_timestamp: ldk_status.latest_rgs_snapshot_timestamp,
+			lsps,
 		}
 	}
 
-	fn handle_events(&self) {
+	/// Returns the next event in the event queue, if currently available.
+	///
+	/// Events are removed from the queue once returned.
+	pub fn next_event(&self) -> Option<Event> {
+		self.event_queue.next_event()
+	}
+
+	/// Returns the next event in the event queue.
+	///
+	/// Will block the current thread until the next event is available.
+	pub fn wait_next_event(&self) -> Event {
+		self.event_queue.wait_next_event()
+	}
+
+	/// Check whether the node is ready to send and receive payments.
+	///
+	/// Useful to gate payment actions in a UI right after [`Romer::new`].
+	pub fn is_ready(&self) -> Readiness {
+		Readiness::from_status(&self.status())
+	}
+
+	/// Block until the node is ready to receive payments.
+	///
+	/// A new wallet is ready once connected to the LSP, as the LSP opens a JIT channel to us when
+	/// we receive our first payment. Use [`Romer::wait_until_ready_to_send`] to also wait for a
+	/// usable channel.
+	///
+	/// Returns [`Error::NotReady`] if the node still isn't ready once `timeout` has elapsed, in
+	/// which case [`Romer::is_ready`] reports the missing precondition.
+	pub fn wait_until_ready(&self, timeout: Duration) -> Result<(), Error> {
+		self.wait_for_readiness(timeout, Readiness::can_receive)
+	}
+
+	/// Block until the node is ready to send payments, i.e. has a usable channel.
+	///
+	/// Returns [`Error::NotReady`] if the node still isn't ready once `timeout` has elapsed, in
+	/// which case [`Romer::is_ready`] reports the missing precondition.
+	pub fn wait_until_ready_to_send(&self, timeout: Duration) -> Result<(), Error> {
+		self.wait_for_readiness(timeout, Readiness::can_send)
+	}
+
+	fn wait_for_readiness(
+		&self, timeout: Duration, ready: fn(&Readiness) -> bool,
+	) -> Result<(), Error> {
+		let deadline = Instant::now() + timeout;
 		loop {
-			let event = self.node.wait_next_event();
+			if ready(&self.is_ready()) {
+				return Ok(());
+			}
+
+			let now = Instant::now();
+			if now >= deadline {
+				return Err(Error::NotReady);
+			}
+			std::thread::sleep(READINESS_POLL_INTERVAL.min(deadline - now));
+		}
+	}
+
+	fn payment_from_details(&self, details: PaymentDetails) -> Payment {
+		let record = self.payment_records.get(&payments::record_key(&details)).unwrap_or_default();
+		Payment::new(details, record)
+	}
+
+	fn balance_snapshot(&self) -> BalanceSnapshot {
+		let balances = self.node.list_balances();
+		let balance_sats =
+			balances.total_onchain_balance_sats + balances.total_lightning_balance_sats;
+		let payments_in_flight = !self
+			.node
+			.list_payments_with_filter(|payment| {
+				payment.direction == PaymentDirection::Outbound
+					&& payment.status == PaymentStatus::Pending
+			})
+			.is_empty();
+
+		BalanceSnapshot {
+			balance_msat: (balance_sats * 1000) as i64,
+			// Every lightning balance is rounded down to whole sats.
+			tolerance_msat: (balances.lightning_balances.len() as u64 + 1) * 1000,
+			onchain_synced_at: self.node.status().latest_onchain_wallet_sync_timestamp,
+			sweeps_pending: !balances.pending_balances_from_channel_closures.is_empty(),
+			payments_in_flight,
+		}
+	}
+
+	fn btc_price(&self, currency: &str) -> Result<f64, Error> {
+		let provider = self.exchange_rate_provider.read().unwrap().clone();
+		match provider {
+			Some(provider) => provider.btc_price(currency.to_string()),
+			None => Err(Error::ExchangeRateUnavailable),
+		}
+	}
+
+	/// Records the fiat value of a payment as it settles, in the currency it was requested in or
+	/// the reporting currency.
+	fn record_fiat_value(&self, payment_id: &PaymentId, amount_msat: u64) {
+		let key = hex_utils::to_string(&payment_id.0);
+		let record = self.payment_records.get(&key);
+		// The value at the time the payment settled is kept, even if we see it settle again.
+		if matches!(&record, Some(record) if record.fiat_value.is_some()) {
+			return;
+		}
+		let currency = match record.and_then(|record| record.fiat_currency) {
+			Some(currency) => currency,
+			None => match &self.config.reporting_currency {
+				Some(currency) => currency.clone(),
+				None => return,
+			},
+		};
+		let btc_price = match self.btc_price(&currency) {
+			Ok(btc_price) => btc_price,
+			Err(_e) => {
+				// TODO: log?
+				return;
+			},
+		};
+
+		let fiat_value = FiatValue::new(amount_msat, currency, btc_price);
+		if let Err(_e) = self.payment_records.update(&key, PaymentRecord::default, |record| {
+			if record.fiat_value.is_none() {
+				record.fiat_value = Some(fiat_value);
+			}
+		}) {
+			// TODO: log?
+		}
+	}
+
+	fn reconcile_ledger(&self) {
+		if let Err(_e) = self.ledger.reconcile(&self.balance_snapshot(), unix_time_secs()) {
+			// TODO: log?
+		}
+	}
+
+	fn record_in_ledger(
+		&self, kind: LedgerEntryKind, amount_msat: u64, inbound: bool, payment_id: PaymentId,
+	) {
+		let res =
+			self.ledger.record(kind, amount_msat, inbound, Some(payment_id), unix_time_secs());
+		if let Err(_e) = res {
+			// TODO: log?
+		}
+	}
+
+	fn attributed_payments(&self) -> Vec<Payment> {
+		self.list_payments_with_filter(|payment| payment.account_id.is_some())
+	}
+
+	/// Whether the payment was initiated from the given sub-account and didn't fail.
+	fn is_attributed(&self, payment_id: &PaymentId, account_id: &str) -> bool {
+		let record = self.payment_records.get(&hex_utils::to_string(&payment_id.0));
+		matches!(record, Some(record) if record.account_id.as_deref() == Some(account_id))
+			&& matches!(self.get_payment(payment_id), Some(p) if p.details.status != PaymentStatus::Failed)
+	}
+
+	/// Attributes a payment we're about to send to a sub-account.
+	fn attribute_payment(&self, payment_id: &PaymentId, account_id: &str) -> Result<(), Error> {
+		if matches!(self.get_payment(payment_id), Some(p) if p.details.status != PaymentStatus::Failed)
+		{
+			return Err(Error::DuplicatePayment);
+		}
+		let key = hex_utils::to_string(&payment_id.0);
+		self.payment_records.update(&key, PaymentRecord::default, |record| {
+			record.account_id = Some(account_id.to_string())
+		})?;
+		Ok(())
+	}
+
+	/// Records an operation in the audit log if enabled, or regardless if it was rejected by the
+	/// destination policy.
+	fn audit<T>(
+		&self, operation: AuditOperation, actor_id: Option<String>,
+		parameters: HashMap<String, String>, res: &Result<T, Error>,
+	) {
+		let error = res.as_ref().err();
+		if !self.config.audit_log && !matches!(error, Some(Error::DestinationNotAllowed)) {
+			return;
+		}
+		let res = self.audit_log.record(operation, actor_id, parameters, error, unix_time_secs());
+		if let Err(_e) = res {
+			// TODO: log?
+		}
+	}
+
+	fn tag_payment(&self, payment_id: &PaymentId, tags: PaymentTags) -> Result<(), Error> {
+		if tags.is_empty() {
+			return Ok(());
+		}
+		let key = hex_utils::to_string(&payment_id.0);
+		self.payment_records.update(&key, PaymentRecord::default, |record| tags.apply(record))?;
+		Ok(())
+	}
+
+	fn handle_events(&self) {
+		while !self.shutdown.is_stopped() {
+			let event = match self.node.next_event() {
+				Some(event) => event,
+				None => {
+					self.shutdown.wait(EVENT_POLL_INTERVAL);
+					continue;
+				},
+			};
 
 			match event {
-				ldk_node::Event::PaymentSuccessful { payment_hash, fee_paid_msat, .. } => {
-					let maybe_pending_payment = {
-						let mut pending_payments = self.pending_payments.lock().unwrap();
-						pending_payments.remove(&payment_hash)
-					};
+				ldk_node::Event::PaymentSuccessful { payment_id, payment_hash, fee_paid_msat } => {
+					let payment_id = payment_id.unwrap_or(PaymentId(payment_hash.0));
+					if let Err(_e) = self.payment_records.update(
+						&hex_utils::to_string(&payment_id.0),
+						PaymentRecord::default,
+						|record| record.fee_paid_msat = fee_paid_msat,
+					) {
+						// TODO: log?
+					}
 
-					if let Some(pending_payment) = maybe_pending_payment {
-						if let Err(_e) = pending_payment.send(Ok(fee_paid_msat.unwrap())) {
-							// TODO: log?
-						}
+					if let Some(amount_msat) =
+						self.node.payment(&payment_id).and_then(|details| details.amount_msat)
+					{
+						self.record_in_ledger(
+							LedgerEntryKind::PaymentSent,
+							amount_msat,
+							false,
+							payment_id,
+						);
+						self.record_fiat_value(&payment_id, amount_msat);
 					}
+					self.record_in_ledger(
+						LedgerEntryKind::RoutingFee,
+						fee_paid_msat.unwrap_or(0),
+						false,
+						payment_id,
+					);
+
+					self.payment_waiters.notify(&payment_hash, Ok(fee_paid_msat.unwrap_or(0)));
 				},
-				ldk_node::Event::PaymentFailed { payment_hash, reason, .. } => {
-					let maybe_pending_payment = {
-						let mut pending_payments = self.pending_payments.lock().unwrap();
-						pending_payments.remove(&payment_hash)
-					};
+				ldk_node::Event::PaymentReceived { payment_id, payment_hash, amount_msat } => {
+					let payment_id = payment_id.unwrap_or(PaymentId(payment_hash.0));
+
+					// The LSP takes its fee for a JIT channel out of the payment itself.
+					let lsp_fee_msat = self
+						.payment_records
+						.get(&hex_utils::to_string(&payment_id.0))
+						.and_then(|record| record.jit_invoice_amount_msat)
+						.map_or(0, |invoice_amount_msat| {
+							invoice_amount_msat.saturating_sub(amount_msat)
+						});
+					self.record_in_ledger(
+						LedgerEntryKind::PaymentReceived,
+						amount_msat + lsp_fee_msat,
+						true,
+						payment_id,
+					);
+					self.record_in_ledger(LedgerEntryKind::LspFee, lsp_fee_msat, false, payment_id);
+					self.record_fiat_value(&payment_id, amount_msat);
+
+					#[cfg(feature = "metrics")]
+					self.metrics.record_received();
+				},
+				// We only pay BOLT11 invoices, so the payment hash is always known.
+				ldk_node::Event::PaymentFailed {
+					payment_id,
+					payment_hash: Some(payment_hash),
+					reason,
+				} => {
+					let payment_id = payment_id.unwrap_or(PaymentId(payment_hash.0));
+					let reason = reason.unwrap_or(PaymentFailureReason::UnexpectedError);
+					if let Err(_e) = self.payment_records.update(
+						&hex_utils::to_string(&payment_id.0),
+						PaymentRecord::default,
+						|record| record.failure_reason = Some(reason),
+					) {
+						// TODO: log?
+					}
+					if let Err(_e) = self.spend_tracker.release_payment(&payment_id) {
+						// TODO: log?
+					}
 
-					if let Some(pending_payment) = maybe_pending_payment {
-						if let Err(_e) = pending_payment.send(Err(reason.unwrap())) {
-							// TODO: log?
-						}
+					self.payment_waiters.notify(&payment_hash, Err(reason.into()));
+				},
+				ldk_node::Event::PaymentClaimable {
+					payment_id,
+					payment_hash,
+					claimable_amount_msat,
+					claim_deadline,
+				} => {
+					// Don't race with settling the invoice internally.
+					let _lock = self.internal_settlement_lock.lock().unwrap();
+					let key = hex_utils::to_string(&payment_id.0);
+					let record = self.payment_records.get(&key).unwrap_or_default();
+					let cancelled = matches!(
+						self.node.payment(&payment_id),
+						Some(payment) if payment.status == PaymentStatus::Failed
+					);
+					match record.claim_action(cancelled) {
+						ClaimAction::Refund => {
+							if let Err(_e) = self.node.bolt11_payment().fail_for_hash(payment_hash)
+							{
+								// TODO: log?
+							}
+						},
+						ClaimAction::Claim(preimage) => {
+							if let Err(_e) = self.payment_records.update(
+								&key,
+								PaymentRecord::default,
+								|record| record.claimable_amount_msat = Some(claimable_amount_msat),
+							) {
+								// TODO: log?
+							}
+							if let Err(_e) = self.node.bolt11_payment().claim_for_hash(
+								payment_hash,
+								claimable_amount_msat,
+								preimage,
+							) {
+								// TODO: log?
+							}
+						},
+						ClaimAction::Hold => {
+							if let Err(_e) = self.payment_records.update(
+								&hex_utils::to_string(&payment_id.0),
+								PaymentRecord::default,
+								|record| {
+									record.claimable_amount_msat = Some(claimable_amount_msat);
+									record.claim_deadline = claim_deadline;
+								},
+							) {
+								// TODO: log?
+							}
+							self.event_queue.add_event(Event::PaymentClaimable {
+								payment_hash,
+								claimable_amount_msat,
+								claim_deadline,
+							});
+						},
 					}
 				},
-                _ => {},
+				ldk_node::Event::ChannelPending { channel_id, counterparty_node_id, .. } => {
+					let mut parameters = HashMap::new();
+					parameters.insert("channel_id".to_string(), channel_id.to_string());
+					parameters.insert(
+						"counterparty_node_id".to_string(),
+						counterparty_node_id.to_string(),
+					);
+					self.audit(AuditOperation::ChannelOpened, None, parameters, &Ok(()));
+
+					let opened_by_us = self
+						.node
+						.list_channels()
+						.iter()
+						.any(|channel| channel.channel_id == channel_id && channel.is_outbound);
+					if opened_by_us {
+						self.ledger.expect_cost(LedgerEntryKind::ChannelOpenFee, unix_time_secs());
+					}
+					self.reconcile_ledger();
+				},
+				ldk_node::Event::ChannelClosed { channel_id, reason, .. } => {
+					let mut parameters = HashMap::new();
+					parameters.insert("channel_id".to_string(), channel_id.to_string());
+					if let Some(reason) = &reason {
+						parameters.insert("reason".to_string(), reason.to_string());
+					}
+					self.audit(AuditOperation::ChannelClosed, None, parameters, &Ok(()));
+
+					let kind = match reason {
+						Some(
+							ClosureReason::LegacyCooperativeClosure
+							| ClosureReason::CounterpartyInitiatedCooperativeClosure
+							| ClosureReason::LocallyInitiatedCooperativeClosure,
+						) => LedgerEntryKind::ChannelCloseFee,
+						_ => LedgerEntryKind::ForceCloseCost,
+					};
+					self.ledger.expect_cost(kind, unix_time_secs());
+					self.reconcile_ledger();
+				},
+				_ => {},
 			}
 
 			self.node.event_handled();
 		}
 	}
 }
+
+/// Builds a node on top of `kv_store` that sources JIT channels from `lsp`.
+fn build_node(
+	network: Network, services: &ServiceConfig, lsp: &LspConfig, kv_store: Arc<SqliteStore>,
+) -> Result<Node, Error> {
+	let mut builder = Builder::new();
+	builder.set_network(network);
+	builder.set_chain_source_esplora(services.esplora_url.clone(), None);
+	builder.set_gossip_source_rgs(services.rgs_url.clone());
+	builder.set_liquidity_source_lsps2(lsp.address.clone(), lsp.node_id, lsp.token.clone());
+	Ok(builder.build_with_store(kv_store)?)
+}
+
+fn unix_time_secs() -> u64 {
+	SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use ldk_node::bitcoin::hashes::Hash;
+
+	fn synced_status(connected: bool, usable_channels: bool) -> Status {
+		Status {
+			node_id: String::new(),
+			connected,
+			usable_channels,
+			best_block_height: 0,
+			best_block_hash: BlockHash::all_zeros(),
+			latest_wallet_sync_timestamp: Some(1),
+			latest_onchain_wallet_sync_timestamp: Some(1),
+			latest_fee_rate_cache_update_timestamp: Some(1),
+			latest_rgs_snapshot_timestamp: Some(1),
+			lsps: Vec::new(),
+		}
+	}
+
+	#[test]
+	fn a_new_wallet_without_a_channel_can_receive() {
+		let readiness = Readiness::from_status(&synced_status(true, false));
+		assert_eq!(readiness, Readiness::AwaitingUsableChannel);
+		assert!(readiness.can_receive());
+		assert!(!readiness.can_send());
+	}
+
+	#[test]
+	fn a_usable_channel_is_needed_to_send() {
+		let readiness = Readiness::from_status(&synced_status(true, true));
+		assert_eq!(readiness, Readiness::Ready);
+		assert!(readiness.can_receive());
+		assert!(readiness.can_send());
+	}
+
+	#[test]
+	fn nothing_can_be_done_without_the_lsp() {
+		let readiness = Readiness::from_status(&synced_status(false, false));
+		assert_eq!(readiness, Readiness::AwaitingLspConnection);
+		assert!(!readiness.can_receive());
+		assert!(!readiness.can_send());
+
+		let mut status = synced_status(true, true);
+		status.latest_onchain_wallet_sync_timestamp = None;
+		assert!(!Readiness::from_status(&status).can_receive());
+	}
+}
@@ -0,0 +1,174 @@
+// This file is Copyright its original authors, visible in version contror
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+use ldk_node::ChannelDetails;
+
+/// The flat part of the routing fee budget LDK allows a payment by default.
+const ROUTING_FEE_BUDGET_BASE_MSAT: u64 = 50_000;
+
+/// The proportional part of the routing fee budget LDK allows a payment by default, in percent.
+const ROUTING_FEE_BUDGET_PERCENT: u64 = 1;
+
+/// The weight an HTLC output adds to the commitment transaction.
+const COMMITMENT_TX_WEIGHT_PER_HTLC: u64 = 172;
+
+/// How many times the current fee rate the funder of a channel has to be able to pay for, as a
+/// buffer against fee spikes.
+const FEE_SPIKE_BUFFER_MULTIPLE: u64 = 2;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+/// The largest amount that can be sent or received over lightning.
+pub struct MaxAmounts {
+	/// The largest amount that fits through a single channel.
+	///
+	/// This is the limit if the invoice doesn't support multi-path payments.
+	pub single_path_msat: u64,
+	/// The largest amount that can be split across all channels.
+	///
+	/// This is the limit if the invoice supports multi-path payments.
+	pub multi_path_msat: u64,
+}
+
+impl MaxAmounts {
+	fn from_channel_limits(limits: impl Iterator<Item = u64>) -> Self {
+		limits.fold(MaxAmounts::default(), |max, limit| MaxAmounts {
+			single_path_msat: max.single_path_msat.max(limit),
+			multi_path_msat: max.multi_path_msat + limit,
+		})
+	}
+}
+
+/// The most we can send over our usable channels, leaving room for routing fees.
+pub(crate) fn max_sendable(channels: &[ChannelDetails]) -> MaxAmounts {
+	MaxAmounts::from_channel_limits(
+		channels
+			.iter()
+			.filter(|c| c.is_usable)
+			.map(|c| sendable_msat(c.next_outbound_htlc_limit_msat)),
+	)
+}
+
+/// The most we can receive over our usable channels without the LSP opening a new one.
+pub(crate) fn max_receivable(channels: &[ChannelDetails]) -> MaxAmounts {
+	MaxAmounts::from_channel_limits(channels.iter().filter(|c| c.is_usable).map(receivable_msat))
+}
+
+/// The most LDK is willing to pay in routing fees for a payment of `amount_msat` by default.
+pub(crate) fn routing_fee_budget_msat(amount_msat: u64) -> u64 {
+	ROUTING_FEE_BUDGET_BASE_MSAT + amount_msat * ROUTING_FEE_BUDGET_PERCENT / 100
+}
+
+/// The largest amount whose routing fee still fits into `limit_msat`, given LDK's default routing
+/// fee budget.
+///
+/// LDK already accounts for the channel reserve, in-flight HTLCs, our peer's HTLC limits and the
+/// commitment transaction fee buffer in the channel's next outbound HTLC limit.
+fn sendable_msat(limit_msat: u64) -> u64 {
+	limit_msat.saturating_sub(ROUTING_FEE_BUDGET_BASE_MSAT) * 100
+		/ (100 + ROUTING_FEE_BUDGET_PERCENT)
+}
+
+fn receivable_msat(channel: &ChannelDetails) -> u64 {
+	let mut limit_msat = channel.inbound_capacity_msat;
+	if let Some(max_msat) = channel.inbound_htlc_maximum_msat {
+		limit_msat = limit_msat.min(max_msat);
+	}
+	if let Some(max_msat) = channel.counterparty_outbound_htlc_maximum_msat {
+		limit_msat = limit_msat.min(max_msat);
+	}
+	if !channel.is_outbound {
+		// Our peer funded the channel, so has to pay for the larger commitment transaction.
+		limit_msat = limit_msat.saturating_sub(commitment_fee_buffer_msat(channel));
+	}
+	if limit_msat < channel.inbound_htlc_minimum_msat {
+		return 0;
+	}
+	limit_msat
+}
+
+/// The fee the funder of the channel has to be able to pay for an additional HTLC output.
+fn commitment_fee_buffer_msat(channel: &ChannelDetails) -> u64 {
+	// Fee rates are per 1,000 weight units, so the fee in sats per weight unit is the fee in msats.
+	COMMITMENT_TX_WEIGHT_PER_HTLC
+		* FEE_SPIKE_BUFFER_MULTIPLE
+		* channel.feerate_sat_per_1000_weight as u64
+}
+
+#[cfg(test)]
+mod test {
+	use ldk_node::bitcoin::secp256k1::PublicKey;
+	use ldk_node::config::ChannelConfig;
+	use ldk_node::lightning::ln::types::ChannelId;
+	use ldk_node::UserChannelId;
+
+	use super::*;
+
+	fn channel(
+		is_usable: bool, is_outbound: bool, next_outbound_htlc_limit_msat: u64,
+		inbound_capacity_msat: u64,
+	) -> ChannelDetails {
+		ChannelDetails {
+			channel_id: ChannelId([1; 32]),
+			counterparty_node_id: PublicKey::from_slice(&[2; 33]).unwrap(),
+			funding_txo: None,
+			channel_value_sats: 1_000_000,
+			unspendable_punishment_reserve: Some(10_000),
+			user_channel_id: UserChannelId(0),
+			outbound_capacity_msat: next_outbound_htlc_limit_msat,
+			inbound_capacity_msat,
+			confirmations_required: None,
+			confirmations: None,
+			is_outbound,
+			feerate_sat_per_1000_weight: 1_000,
+			is_channel_ready: true,
+			is_usable,
+			is_announced: false,
+			cltv_expiry_delta: None,
+			counterparty_unspendable_punishment_reserve: 10_000,
+			counterparty_outbound_htlc_minimum_msat: None,
+			counterparty_outbound_htlc_maximum_msat: None,
+			counterparty_forwarding_info_fee_base_msat: None,
+			counterparty_forwarding_info_fee_proportional_millionths: None,
+			counterparty_forwarding_info_cltv_expiry_delta: None,
+			next_outbound_htlc_limit_msat,
+			next_outbound_htlc_minimum_msat: 1,
+			force_close_spend_delay: None,
+			inbound_htlc_minimum_msat: 1_000,
+			inbound_htlc_maximum_msat: None,
+			config: ChannelConfig::default(),
+		}
+	}
+
+	#[test]
+	fn leaves_room_for_routing_fees() {
+		let channels = vec![
+			channel(true, true, 10_150_000, 0),
+			channel(true, true, 5_100_000, 0),
+			channel(false, true, 90_000_000, 0),
+		];
+		let max = max_sendable(&channels);
+		assert_eq!(max.single_path_msat, 10_000_000);
+		assert_eq!(max.multi_path_msat, 15_000_000);
+
+		assert_eq!(max_sendable(&[channel(true, true, 40_000, 0)]), MaxAmounts::default());
+	}
+
+	#[test]
+	fn respects_inbound_htlc_limits() {
+		let mut capped = channel(true, true, 0, 5_000_000);
+		capped.inbound_htlc_maximum_msat = Some(2_000_000);
+		let lsp_funded = channel(true, false, 0, 3_000_000);
+		let fee_buffer_msat = commitment_fee_buffer_msat(&lsp_funded);
+		assert_eq!(fee_buffer_msat, 344_000);
+		let dust = channel(true, false, 0, fee_buffer_msat + 500);
+
+		let max = max_receivable(&[capped, lsp_funded, dust]);
+		assert_eq!(max.single_path_msat, 3_000_000 - fee_buffer_msat);
+		assert_eq!(max.multi_path_msat, 5_000_000 - fee_buffer_msat);
+	}
+}
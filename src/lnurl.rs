@@ -0,0 +1,318 @@
+// This file is Copyright its original authors, visible in version contror
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+use std::str::FromStr;
+use std::time::Duration;
+
+use bech32::FromBase32;
+use ldk_node::bitcoin::hashes::sha256::Hash as Sha256;
+use ldk_node::bitcoin::hashes::Hash;
+use ldk_node::lightning_invoice::{Bolt11Invoice, Bolt11InvoiceDescription};
+use serde_json::Value;
+
+use crate::error::Error;
+
+/// How long we wait for an LNURL service to answer a request.
+const HTTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Fetches the body of HTTP GET requests, to talk to LNURL services.
+///
+/// Implement it to route requests through your own HTTP stack or proxy, or to stub LNURL services
+/// in tests.
+pub trait HttpFetcher: Send + Sync {
+	/// Returns the body of a successful GET request to `url`.
+	///
+	/// Should fail with [`Error::LnurlRequestFailed`] if the request fails.
+	fn get(&self, url: String) -> Result<String, Error>;
+}
+
+/// The [`HttpFetcher`] used unless another one is set.
+pub(crate) struct DefaultHttpFetcher {
+	client: reqwest::blocking::Client,
+}
+
+impl DefaultHttpFetcher {
+	pub(crate) fn new() -> Self {
+		let client = reqwest::blocking::Client::builder()
+			.timeout(HTTP_TIMEOUT)
+			.build()
+			.expect("the default client configuration is valid");
+		Self { client }
+	}
+}
+
+impl HttpFetcher for DefaultHttpFetcher {
+	fn get(&self, url: String) -> Result<String, Error> {
+		self.client
+			.get(url)
+			.send()
+			.and_then(|response| response.error_for_status())
+			.and_then(|response| response.text())
+			.map_err(|_e| Error::LnurlRequestFailed)
+	}
+}
+
+/// Returns the URL of the LNURL-pay endpoint of a Lightning Address, e.g. `name@domain.com`.
+pub(crate) fn lightning_address_url(address: &str) -> Result<String, Error> {
+	let address = address.trim();
+	let address = strip_prefix_ignore_case(address, "lightning:").unwrap_or(address);
+	let (name, domain) = address.split_once('@').ok_or(Error::InvalidLightningAddress)?;
+	let is_valid_name = |name: &str| {
+		!name.is_empty()
+			&& name.chars().all(|c| {
+				c.is_ascii_lowercase() || c.is_ascii_digit() || matches!(c, '-' | '_' | '.' | '+')
+			})
+	};
+	let is_valid_domain = |domain: &str| {
+		domain.contains('.')
+			&& domain.split('.').all(|label| {
+				!label.is_empty() && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+			})
+	};
+	let name = name.to_ascii_lowercase();
+	let domain = domain.to_ascii_lowercase();
+	if !is_valid_name(&name) || !is_valid_domain(&domain) {
+		return Err(Error::InvalidLightningAddress);
+	}
+	// Onion services are reached over plain HTTP.
+	let scheme = if domain.ends_with(".onion") { "http" } else { "https" };
+	Ok(format!("{}://{}/.well-known/lnurlp/{}", scheme, domain, name))
+}
+
+/// Decodes a bech32-encoded LNURL, e.g. `lnurl1...`, into the URL it encodes.
+pub(crate) fn decode_lnurl(lnurl: &str) -> Result<String, Error> {
+	let lnurl = lnurl.trim();
+	let lnurl = strip_prefix_ignore_case(lnurl, "lightning:").unwrap_or(lnurl);
+	let (hrp, data, _variant) = bech32::decode(lnurl).map_err(|_e| Error::InvalidLnurl)?;
+	if hrp != "lnurl" {
+		return Err(Error::InvalidLnurl);
+	}
+	let bytes = Vec::<u8>::from_base32(&data).map_err(|_e| Error::InvalidLnurl)?;
+	let url = String::from_utf8(bytes).map_err(|_e| Error::InvalidLnurl)?;
+	if !is_secure_url(&url) {
+		return Err(Error::InvalidLnurl);
+	}
+	Ok(url)
+}
+
+/// Requests an invoice for `amount_msat` from the LNURL-pay endpoint at `url`.
+///
+/// Checks that the amount is within what the service accepts, and that the invoice is for the
+/// amount and commits to the metadata the service described the payment with.
+pub(crate) fn fetch_invoice(
+	fetcher: &dyn HttpFetcher, url: &str, amount_msat: u64, comment: Option<&str>,
+) -> Result<Bolt11Invoice, Error> {
+	let pay_request = get_json(fetcher, url)?;
+	if pay_request["tag"].as_str() != Some("payRequest") {
+		return Err(Error::InvalidLnurl);
+	}
+	let callback = pay_request["callback"].as_str().ok_or(Error::InvalidLnurl)?;
+	let min_sendable_msat = pay_request["minSendable"].as_u64().ok_or(Error::InvalidLnurl)?;
+	let max_sendable_msat = pay_request["maxSendable"].as_u64().ok_or(Error::InvalidLnurl)?;
+	let metadata = pay_request["metadata"].as_str().ok_or(Error::InvalidLnurl)?;
+	if !is_secure_url(callback) {
+		return Err(Error::InvalidLnurl);
+	}
+	if amount_msat < min_sendable_msat || amount_msat > max_sendable_msat {
+		return Err(Error::InvalidAmount);
+	}
+
+	let mut callback_url = format!(
+		"{}{}amount={}",
+		callback,
+		if callback.contains('?') { '&' } else { '?' },
+		amount_msat
+	);
+	if let Some(comment) = comment.filter(|comment| !comment.is_empty()) {
+		let comment_allowed = pay_request["commentAllowed"].as_u64().unwrap_or(0);
+		if comment.chars().count() as u64 > comment_allowed {
+			return Err(Error::LnurlCommentTooLong);
+		}
+		callback_url.push_str("&comment=");
+		callback_url.push_str(&percent_encode(comment));
+	}
+
+	let response = get_json(fetcher, &callback_url)?;
+	let invoice = response["pr"].as_str().ok_or(Error::InvalidLnurl)?;
+	let invoice = Bolt11Invoice::from_str(invoice).map_err(|_e| Error::InvalidBolt11Invoice)?;
+	let description_hash = Sha256::hash(metadata.as_bytes());
+	let commits_to_metadata = matches!(
+		invoice.description(),
+		Bolt11InvoiceDescription::Hash(hash) if hash.0 == description_hash
+	);
+	if !commits_to_metadata || invoice.amount_milli_satoshis() != Some(amount_msat) {
+		return Err(Error::LnurlInvoiceMismatch);
+	}
+	Ok(invoice)
+}
+
+/// Fetches a JSON response, failing if the service reports an error.
+fn get_json(fetcher: &dyn HttpFetcher, url: &str) -> Result<Value, Error> {
+	let body = fetcher.get(url.to_string())?;
+	let json: Value = serde_json::from_str(&body).map_err(|_e| Error::InvalidLnurl)?;
+	if json["status"].as_str() == Some("ERROR") {
+		return Err(Error::LnurlRequestFailed);
+	}
+	Ok(json)
+}
+
+/// LNURL services have to be reached over HTTPS, unless they are onion services.
+fn is_secure_url(url: &str) -> bool {
+	if strip_prefix_ignore_case(url, "https://").is_some() {
+		return true;
+	}
+	match strip_prefix_ignore_case(url, "http://") {
+		Some(rest) => {
+			let host = rest.split(['/', '?']).next().unwrap_or("");
+			let host = host.split(':').next().unwrap_or("");
+			host.to_ascii_lowercase().ends_with(".onion")
+		},
+		None => false,
+	}
+}
+
+fn strip_prefix_ignore_case<'a>(s: &'a str, prefix: &str) -> Option<&'a str> {
+	match s.get(..prefix.len()) {
+		Some(start) if start.eq_ignore_ascii_case(prefix) => Some(&s[prefix.len()..]),
+		_ => None,
+	}
+}
+
+/// Percent-encodes everything but unreserved characters, for use in a query parameter.
+fn percent_encode(s: &str) -> String {
+	let mut encoded = String::with_capacity(s.len());
+	for byte in s.bytes() {
+		if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'_' | b'.' | b'~') {
+			encoded.push(byte as char);
+		} else {
+			encoded.push_str(&format!("%{:02X}", byte));
+		}
+	}
+	encoded
+}
+
+#[cfg(test)]
+mod test {
+	use std::collections::HashMap;
+	use std::sync::Mutex;
+
+	use bech32::{ToBase32, Variant};
+	use ldk_node::bitcoin::secp256k1::{Secp256k1, SecretKey};
+	use ldk_node::lightning::ln::{PaymentHash, PaymentSecret};
+	use ldk_node::lightning_invoice::{Currency, InvoiceBuilder};
+
+	use super::*;
+
+	const METADATA: &str =
+		"[[\"text/plain\",\"Coffee\"],[\"text/identifier\",\"bob@example.com\"]]";
+
+	/// Stands in for an LNURL service, answering requests with canned responses.
+	struct StubServer {
+		responses: HashMap<String, String>,
+		requests: Mutex<Vec<String>>,
+	}
+
+	impl HttpFetcher for StubServer {
+		fn get(&self, url: String) -> Result<String, Error> {
+			self.requests.lock().unwrap().push(url.clone());
+			self.responses.get(&url).cloned().ok_or(Error::LnurlRequestFailed)
+		}
+	}
+
+	fn invoice(amount_msat: u64, metadata: &str) -> String {
+		let secp = Secp256k1::new();
+		let key = SecretKey::from_slice(&[42; 32]).unwrap();
+		InvoiceBuilder::new(Currency::Regtest)
+			.description_hash(Sha256::hash(metadata.as_bytes()))
+			.payment_hash(Sha256::from_slice(&PaymentHash([1; 32]).0).unwrap())
+			.payment_secret(PaymentSecret([2; 32]))
+			.current_timestamp()
+			.min_final_cltv_expiry_delta(144)
+			.amount_milli_satoshis(amount_msat)
+			.build_signed(|hash| secp.sign_ecdsa_recoverable(hash, &key))
+			.unwrap()
+			.to_string()
+	}
+
+	fn server(callback_response: String) -> StubServer {
+		let pay_request = serde_json::json!({
+			"tag": "payRequest",
+			"callback": "https://example.com/lnurlp/bob/callback?id=7",
+			"minSendable": 1_000,
+			"maxSendable": 1_000_000,
+			"metadata": METADATA,
+			"commentAllowed": 10,
+		});
+		let mut responses = HashMap::new();
+		responses.insert(
+			"https://example.com/.well-known/lnurlp/bob".to_string(),
+			pay_request.to_string(),
+		);
+		responses.insert(
+			"https://example.com/lnurlp/bob/callback?id=7&amount=50000&comment=thanks%21"
+				.to_string(),
+			callback_response,
+		);
+		StubServer { responses, requests: Mutex::new(Vec::new()) }
+	}
+
+	#[test]
+	fn resolves_lightning_addresses_and_lnurls() {
+		let url = "https://example.com/.well-known/lnurlp/bob";
+		assert_eq!(lightning_address_url("Bob@Example.com").unwrap(), url);
+		assert_eq!(lightning_address_url("lightning:bob@example.com").unwrap(), url);
+		assert_eq!(
+			lightning_address_url("bob@abcdef.onion").unwrap(),
+			"http://abcdef.onion/.well-known/lnurlp/bob"
+		);
+		for address in ["bob", "@example.com", "bob@localhost", "b b@example.com", "bob@a..com"] {
+			assert_eq!(lightning_address_url(address), Err(Error::InvalidLightningAddress));
+		}
+
+		let encode = |url: &str| {
+			bech32::encode("lnurl", url.as_bytes().to_base32(), Variant::Bech32).unwrap()
+		};
+		assert_eq!(decode_lnurl(&encode(url).to_uppercase()).unwrap(), url);
+		assert_eq!(decode_lnurl(&format!("lightning:{}", encode(url))).unwrap(), url);
+		assert_eq!(decode_lnurl(&encode("http://example.com/pay")), Err(Error::InvalidLnurl));
+		assert_eq!(decode_lnurl("bob@example.com"), Err(Error::InvalidLnurl));
+	}
+
+	#[test]
+	fn fetches_and_validates_invoices() {
+		let url = lightning_address_url("bob@example.com").unwrap();
+		let callback_response = |invoice: String| serde_json::json!({ "pr": invoice }).to_string();
+
+		let stub = server(callback_response(invoice(50_000, METADATA)));
+		let fetched = fetch_invoice(&stub, &url, 50_000, Some("thanks!")).unwrap();
+		assert_eq!(fetched.amount_milli_satoshis(), Some(50_000));
+		assert_eq!(stub.requests.lock().unwrap().len(), 2);
+
+		assert_eq!(fetch_invoice(&stub, &url, 999, None), Err(Error::InvalidAmount));
+		assert_eq!(fetch_invoice(&stub, &url, 1_000_001, None), Err(Error::InvalidAmount));
+		assert_eq!(
+			fetch_invoice(&stub, &url, 50_000, Some("thanks a lot!")),
+			Err(Error::LnurlCommentTooLong)
+		);
+
+		// The service has to commit to the metadata it showed us and the amount we asked for.
+		for invoice in [invoice(50_000, "[]"), invoice(40_000, METADATA)] {
+			let stub = server(callback_response(invoice));
+			assert_eq!(
+				fetch_invoice(&stub, &url, 50_000, Some("thanks!")),
+				Err(Error::LnurlInvoiceMismatch)
+			);
+		}
+
+		let stub = server(serde_json::json!({ "status": "ERROR", "reason": "nope" }).to_string());
+		assert_eq!(
+			fetch_invoice(&stub, &url, 50_000, Some("thanks!")),
+			Err(Error::LnurlRequestFailed)
+		);
+	}
+}
@@ -0,0 +1,351 @@
+// This file is Copyright its original authors, visible in version contror
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::error::Error;
+use crate::{Balances, Status};
+
+/// Upper bounds of the `send` latency histogram buckets, in seconds.
+const SEND_LATENCY_BUCKETS_SECS: &[f64] = &[0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0];
+
+/// Upper bounds of the `send` fee histogram buckets, in millisatoshis.
+const SEND_FEE_BUCKETS_MSAT: &[f64] =
+	&[0.0, 1_000.0, 10_000.0, 50_000.0, 100_000.0, 500_000.0, 1_000_000.0, 5_000_000.0];
+
+struct Histogram {
+	bounds: &'static [f64],
+	counts: Vec<u64>,
+	sum: f64,
+	count: u64,
+}
+
+impl Histogram {
+	fn new(bounds: &'static [f64]) -> Self {
+		Self { bounds, counts: vec![0; bounds.len()], sum: 0.0, count: 0 }
+	}
+
+	fn observe(&mut self, value: f64) {
+		for (bound, count) in self.bounds.iter().zip(self.counts.iter_mut()) {
+			if value <= *bound {
+				*count += 1;
+			}
+		}
+		self.sum += value;
+		self.count += 1;
+	}
+
+	fn render(&self, out: &mut String, name: &str, help: &str) {
+		writeln!(out, "# HELP {} {}", name, help).unwrap();
+		writeln!(out, "# TYPE {} histogram", name).unwrap();
+		for (bound, count) in self.bounds.iter().zip(self.counts.iter()) {
+			writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, count).unwrap();
+		}
+		writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, self.count).unwrap();
+		writeln!(out, "{}_sum {}", name, self.sum).unwrap();
+		writeln!(out, "{}_count {}", name, self.count).unwrap();
+	}
+}
+
+struct MetricsInner {
+	sends: u64,
+	onchain_sends: u64,
+	receives: u64,
+	failures: BTreeMap<(&'static str, &'static str), u64>,
+	awaited: BTreeMap<&'static str, u64>,
+	send_latency: Histogram,
+	send_fee: Histogram,
+}
+
+/// Collects counters and histograms about the payments made through [`Romer`].
+///
+/// Balance and sync gauges are sampled when rendering rather than tracked here.
+///
+/// [`Romer`]: crate::Romer
+pub(crate) struct Metrics {
+	inner: Mutex<MetricsInner>,
+}
+
+impl Metrics {
+	pub(crate) fn new() -> Self {
+		let inner = MetricsInner {
+			sends: 0,
+			onchain_sends: 0,
+			receives: 0,
+			failures: BTreeMap::new(),
+			awaited: BTreeMap::new(),
+			send_latency: Histogram::new(SEND_LATENCY_BUCKETS_SECS),
+			send_fee: Histogram::new(SEND_FEE_BUCKETS_MSAT),
+		};
+		Self { inner: Mutex::new(inner) }
+	}
+
+	pub(crate) fn record_send(&self, result: &Result<u64, Error>, latency: Duration) {
+		let mut inner = self.inner.lock().unwrap();
+		match result {
+			Ok(fee_paid_msat) => {
+				inner.sends += 1;
+				inner.send_latency.observe(latency.as_secs_f64());
+				inner.send_fee.observe(*fee_paid_msat as f64);
+			},
+			Err(e) => {
+				*inner.failures.entry(("outbound", error_label(e))).or_insert(0) += 1;
+			},
+		}
+	}
+
+	pub(crate) fn record_onchain_send<T>(&self, result: &Result<T, Error>) {
+		let mut inner = self.inner.lock().unwrap();
+		match result {
+			Ok(_) => inner.onchain_sends += 1,
+			Err(e) => *inner.failures.entry(("onchain_outbound", error_label(e))).or_insert(0) += 1,
+		}
+	}
+
+	/// Records the outcome [`Romer::await_payment`] returned, apart from the sends themselves, so
+	/// payments we also sent in this process aren't counted twice.
+	///
+	/// [`Romer::await_payment`]: crate::Romer::await_payment
+	pub(crate) fn record_awaited(&self, result: &Result<u64, Error>) {
+		let outcome = match result {
+			Ok(_) => "Succeeded",
+			Err(e) => error_label(e),
+		};
+		*self.inner.lock().unwrap().awaited.entry(outcome).or_insert(0) += 1;
+	}
+
+	pub(crate) fn record_receive_failure(&self, error: &Error) {
+		let mut inner = self.inner.lock().unwrap();
+		*inner.failures.entry(("inbound", error_label(error))).or_insert(0) += 1;
+	}
+
+	pub(crate) fn record_received(&self) {
+		self.inner.lock().unwrap().receives += 1;
+	}
+
+	/// Renders all metrics in the Prometheus text exposition format.
+	pub(crate) fn render(&self, balances: &Balances, status: &Status, now_secs: u64) -> String {
+		let inner = self.inner.lock().unwrap();
+		let mut out = String::new();
+
+		render_counter(&mut out, "romer_payments_sent_total", "Successful outbound payments.");
+		writeln!(out, "romer_payments_sent_total {}", inner.sends).unwrap();
+		render_counter(
+			&mut out,
+			"romer_onchain_payments_sent_total",
+			"Broadcast onchain payments.",
+		);
+		writeln!(out, "romer_onchain_payments_sent_total {}", inner.onchain_sends).unwrap();
+		render_counter(&mut out, "romer_payments_received_total", "Received payments.");
+		writeln!(out, "romer_payments_received_total {}", inner.receives).unwrap();
+		render_counter(&mut out, "romer_payment_failures_total", "Failed payments by reason.");
+		for ((direction, reason), count) in inner.failures.iter() {
+			writeln!(
+				out,
+				"romer_payment_failures_total{{direction=\"{}\",reason=\"{}\"}} {}",
+				direction, reason, count
+			)
+			.unwrap();
+		}
+		render_counter(
+			&mut out,
+			"romer_awaited_payments_total",
+			"Outcomes returned by await_payment.",
+		);
+		for (outcome, count) in inner.awaited.iter() {
+			writeln!(out, "romer_awaited_payments_total{{outcome=\"{}\"}} {}", outcome, count)
+				.unwrap();
+		}
+
+		inner.send_latency.render(
+			&mut out,
+			"romer_send_latency_seconds",
+			"Time taken by successful sends.",
+		);
+		inner.send_fee.render(&mut out, "romer_send_fee_msat", "Fees paid by successful sends.");
+
+		let balance_gauges = [
+			("spendable_onchain", balances.spendable_onchain_balance_sats),
+			("total_onchain", balances.total_onchain_balance_sats),
+			("anchor_channels_reserve", balances.total_anchor_channels_reserve_sats),
+			("total_lightning", balances.total_lightning_balance_sats),
+			("outbound_capacity_lightning", balances.outbound_capacity_lightning_sats),
+			("inbound_capacity_lightning", balances.inbound_capacity_lightning_sats),
+		];
+		render_gauge(&mut out, "romer_balance_sats", "Current balances in sats.");
+		for (kind, value) in balance_gauges.iter() {
+			writeln!(out, "romer_balance_sats{{kind=\"{}\"}} {}", kind, value).unwrap();
+		}
+
+		let sync_timestamps = [
+			("lightning_wallet", status.latest_wallet_sync_timestamp),
+			("onchain_wallet", status.latest_onchain_wallet_sync_timestamp),
+			("fee_rate_cache", status.latest_fee_rate_cache_update_timestamp),
+			("rgs_snapshot", status.latest_rgs_snapshot_timestamp),
+		];
+		render_gauge(&mut out, "romer_sync_lag_seconds", "Seconds since the last sync.");
+		for (source, timestamp) in sync_timestamps.iter() {
+			if let Some(timestamp) = timestamp {
+				let lag = now_secs.saturating_sub(*timestamp);
+				writeln!(out, "romer_sync_lag_seconds{{source=\"{}\"}} {}", source, lag).unwrap();
+			}
+		}
+
+		render_gauge(&mut out, "romer_best_block_height", "Latest synced block height.");
+		writeln!(out, "romer_best_block_height {}", status.best_block_height).unwrap();
+		render_gauge(&mut out, "romer_lsp_connected", "Whether we are connected to the LSP.");
+		writeln!(out, "romer_lsp_connected {}", status.connected as u8).unwrap();
+
+		out
+	}
+}
+
+/// The label we count failures with, which stays the same across releases unlike the error's
+/// `Debug` output.
+fn error_label(error: &Error) -> &'static str {
+	match error {
+		Error::InvalidAPIToken => "InvalidAPIToken",
+		Error::NetworkNotSupported => "NetworkNotSupported",
+		Error::InvalidBolt11Invoice => "InvalidBolt11Invoice",
+		Error::InvalidBitcoinAddress => "InvalidBitcoinAddress",
+		Error::InvalidOfferId => "InvalidOfferId",
+		Error::InvalidPaymentId => "InvalidPaymentId",
+		Error::InvalidChannelId => "InvalidChannelId",
+		Error::InvalidPublicKey => "InvalidPublicKey",
+		Error::InvalidSocketAddress => "InvalidSocketAddress",
+		Error::InvalidCursor => "InvalidCursor",
+		Error::InvalidPaymentHash => "InvalidPaymentHash",
+		Error::InvalidPaymentPreimage => "InvalidPaymentPreimage",
+		Error::InvalidPaymentSecret => "InvalidPaymentSecret",
+		Error::InvalidAmount => "InvalidAmount",
+		Error::IdempotencyKeyConflict => "IdempotencyKeyConflict",
+		Error::PaymentLimitExceeded => "PaymentLimitExceeded",
+		Error::DailyLimitExceeded => "DailyLimitExceeded",
+		Error::WeeklyLimitExceeded => "WeeklyLimitExceeded",
+		Error::SecondFactorRequired => "SecondFactorRequired",
+		Error::DestinationNotAllowed => "DestinationNotAllowed",
+		Error::AuditLogTampered => "AuditLogTampered",
+		Error::InvalidAccountId => "InvalidAccountId",
+		Error::AccountAlreadyExists => "AccountAlreadyExists",
+		Error::UnknownAccount => "UnknownAccount",
+		Error::InsufficientAccountBalance => "InsufficientAccountBalance",
+		Error::PaymentNotClaimable => "PaymentNotClaimable",
+		Error::InvalidLightningAddress => "InvalidLightningAddress",
+		Error::InvalidLnurl => "InvalidLnurl",
+		Error::LnurlRequestFailed => "LnurlRequestFailed",
+		Error::LnurlCommentTooLong => "LnurlCommentTooLong",
+		Error::LnurlInvoiceMismatch => "LnurlInvoiceMismatch",
+		Error::InvalidConfig => "InvalidConfig",
+		Error::FailedToBuildNode => "FailedToBuildNode",
+		Error::PersistenceFailed => "PersistenceFailed",
+		Error::LDKNodeError => "LDKNodeError",
+		Error::DuplicatePayment => "DuplicatePayment",
+		Error::RecipientRejected => "RecipientRejected",
+		Error::RetriesExhausted => "RetriesExhausted",
+		Error::PaymentExpired => "PaymentExpired",
+		Error::RouteNotFound => "RouteNotFound",
+		Error::FeeLimitExceeded => "FeeLimitExceeded",
+		Error::NotReady => "NotReady",
+		Error::Stopped => "Stopped",
+		Error::LspUnavailable => "LspUnavailable",
+		Error::LspFeeTooHigh => "LspFeeTooHigh",
+		Error::InternalSettlementUnsupported => "InternalSettlementUnsupported",
+		Error::ExchangeRateUnavailable => "ExchangeRateUnavailable",
+		Error::UnexpectedError => "UnexpectedError",
+	}
+}
+
+fn render_counter(out: &mut String, name: &str, help: &str) {
+	writeln!(out, "# HELP {} {}", name, help).unwrap();
+	writeln!(out, "# TYPE {} counter", name).unwrap();
+}
+
+fn render_gauge(out: &mut String, name: &str, help: &str) {
+	writeln!(out, "# HELP {} {}", name, help).unwrap();
+	writeln!(out, "# TYPE {} gauge", name).unwrap();
+}
+
+#[cfg(test)]
+mod test {
+	use ldk_node::bitcoin::hashes::Hash;
+	use ldk_node::bitcoin::BlockHash;
+
+	use super::*;
+
+	fn balances() -> Balances {
+		Balances {
+			spendable_onchain_balance_sats: 1_000,
+			total_onchain_balance_sats: 2_000,
+			total_anchor_channels_reserve_sats: 0,
+			total_lightning_balance_sats: 50_000,
+			outbound_capacity_lightning_sats: 40_000,
+			inbound_capacity_lightning_sats: 60_000,
+		}
+	}
+
+	fn status() -> Status {
+		Status {
+			node_id: String::new(),
+			connected: true,
+			usable_channels: true,
+			best_block_height: 800_000,
+			best_block_hash: BlockHash::all_zeros(),
+			latest_wallet_sync_timestamp: Some(990),
+			latest_onchain_wallet_sync_timestamp: None,
+			latest_fee_rate_cache_update_timestamp: Some(1_000),
+			latest_rgs_snapshot_timestamp: Some(400),
+			lsps: Vec::new(),
+		}
+	}
+
+	#[test]
+	fn renders_counters_and_histograms() {
+		let metrics = Metrics::new();
+		metrics.record_send(&Ok(2_000), Duration::from_millis(300));
+		metrics.record_send(&Err(Error::RouteNotFound), Duration::from_millis(100));
+		metrics.record_receive_failure(&Error::LDKNodeError);
+		metrics.record_received();
+		metrics.record_onchain_send(&Ok(()));
+		metrics.record_onchain_send::<()>(&Err(Error::InvalidBitcoinAddress));
+		metrics.record_awaited(&Ok(2_000));
+		metrics.record_awaited(&Err(Error::PaymentExpired));
+
+		let out = metrics.render(&balances(), &status(), 1_000);
+		assert!(out.contains("romer_payments_sent_total 1\n"));
+		assert!(out.contains("romer_payments_received_total 1\n"));
+		assert!(out.contains(
+			"romer_payment_failures_total{direction=\"outbound\",reason=\"RouteNotFound\"} 1\n"
+		));
+		assert!(out.contains(
+			"romer_payment_failures_total{direction=\"inbound\",reason=\"LDKNodeError\"} 1\n"
+		));
+		assert!(out.contains("romer_onchain_payments_sent_total 1\n"));
+		assert!(out.contains(
+			"romer_payment_failures_total{direction=\"onchain_outbound\",reason=\"InvalidBitcoinAddress\"} 1\n"
+		));
+		assert!(out.contains("romer_awaited_payments_total{outcome=\"Succeeded\"} 1\n"));
+		assert!(out.contains("romer_awaited_payments_total{outcome=\"PaymentExpired\"} 1\n"));
+		assert!(out.contains("romer_send_latency_seconds_bucket{le=\"0.25\"} 0\n"));
+		assert!(out.contains("romer_send_latency_seconds_bucket{le=\"0.5\"} 1\n"));
+		assert!(out.contains("romer_send_fee_msat_bucket{le=\"+Inf\"} 1\n"));
+		assert!(out.contains("romer_send_fee_msat_sum 2000\n"));
+	}
+
+	#[test]
+	fn renders_balance_and_sync_gauges() {
+		let out = Metrics::new().render(&balances(), &status(), 1_000);
+		assert!(out.contains("romer_balance_sats{kind=\"total_lightning\"} 50000\n"));
+		assert!(out.contains("romer_sync_lag_seconds{source=\"lightning_wallet\"} 10\n"));
+		assert!(out.contains("romer_sync_lag_seconds{source=\"rgs_snapshot\"} 600\n"));
+		assert!(!out.contains("source=\"onchain_wallet\""));
+		assert!(out.contains("romer_best_block_height 800000\n"));
+	}
+}
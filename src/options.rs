@@ -0,0 +1,156 @@
+// This file is Copyright its original authors, visible in version contror
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+use std::collections::HashMap;
+
+use ldk_node::payment::LSPFeeLimits;
+
+use crate::config::RoutingFeeLimits;
+use crate::payments::PaymentTags;
+
+#[derive(Debug, Clone, Default)]
+/// Per-call options for [`Romer::receive_with_options`].
+///
+/// [`Romer::receive_with_options`]: crate::Romer::receive_with_options
+pub struct ReceiveOptions {
+	/// The most we allow the LSP to charge if a JIT channel is needed to receive the payment.
+	///
+	/// Replaces [`Config::lsp_fee_limits`] for this call if set.
+	///
+	/// [`Config::lsp_fee_limits`]: crate::Config::lsp_fee_limits
+	pub lsp_fee_limits: Option<LSPFeeLimits>,
+	/// Who initiated the operation, e.g. a user or service id, recorded in the audit log.
+	///
+	/// See [`Config::audit_log`].
+	///
+	/// [`Config::audit_log`]: crate::Config::audit_log
+	pub actor_id: Option<String>,
+	/// A label to attach to the payment.
+	pub label: Option<String>,
+	/// Free-form metadata to attach to the payment.
+	pub metadata: HashMap<String, String>,
+	/// A reference to the payment in another system, e.g. an order id.
+	pub external_ref: Option<String>,
+}
+
+impl ReceiveOptions {
+	pub(crate) fn tags(&self) -> PaymentTags {
+		PaymentTags {
+			label: self.label.clone(),
+			metadata: self.metadata.clone(),
+			external_ref: self.external_ref.clone(),
+		}
+	}
+}
+
+#[derive(Debug, Clone, Default)]
+/// Per-call options for [`Romer::send_with_options`].
+///
+/// [`Romer::send_with_options`]: crate::Romer::send_with_options
+pub struct SendOptions {
+	/// The most we allow to pay in routing fees for this payment.
+	///
+	/// Replaces [`Config::routing_fee_limits`] for this call if set.
+	///
+	/// [`Config::routing_fee_limits`]: crate::Config::routing_fee_limits
+	pub routing_fee_limits: Option<RoutingFeeLimits>,
+	/// A key identifying this payment request, e.g. a request id.
+	///
+	/// If a payment was already sent with the same key, even before a restart, its outcome is
+	/// returned instead of paying again, waiting for it if it is still pending. Fails with
+	/// [`Error::IdempotencyKeyConflict`] if the key was used to pay a different invoice.
+	///
+	/// [`Error::IdempotencyKeyConflict`]: crate::RomerError::IdempotencyKeyConflict
+	pub idempotency_key: Option<String>,
+	/// Who initiated the operation, e.g. a user or service id, recorded in the audit log.
+	///
+	/// See [`Config::audit_log`].
+	///
+	/// [`Config::audit_log`]: crate::Config::audit_log
+	pub actor_id: Option<String>,
+	/// Whether the user confirmed this payment with a second factor.
+	///
+	/// Required for payments above [`SpendingPolicy::confirmation_threshold_msat`].
+	///
+	/// [`SpendingPolicy::confirmation_threshold_msat`]: crate::SpendingPolicy::confirmation_threshold_msat
+	pub second_factor_confirmed: bool,
+	/// A label to attach to the payment.
+	pub label: Option<String>,
+	/// Free-form metadata to attach to the payment.
+	pub metadata: HashMap<String, String>,
+	/// A reference to the payment in another system, e.g. an order id.
+	pub external_ref: Option<String>,
+}
+
+impl SendOptions {
+	pub(crate) fn tags(&self) -> PaymentTags {
+		PaymentTags {
+			label: self.label.clone(),
+			metadata: self.metadata.clone(),
+			external_ref: self.external_ref.clone(),
+		}
+	}
+}
+
+#[derive(Debug, Clone, Default)]
+/// Per-call options for [`Romer::transfer_between_accounts`].
+///
+/// [`Romer::transfer_between_accounts`]: crate::Romer::transfer_between_accounts
+pub struct TransferOptions {
+	/// A free-form note to keep with the transfer.
+	pub memo: Option<String>,
+	/// A key identifying this transfer request, e.g. a request id.
+	///
+	/// If a transfer was already made with the same key, even before a restart, it is returned
+	/// instead of moving the funds again. Fails with [`Error::IdempotencyKeyConflict`] if the key
+	/// was used for a transfer between other accounts or of another amount.
+	///
+	/// [`Error::IdempotencyKeyConflict`]: crate::RomerError::IdempotencyKeyConflict
+	pub idempotency_key: Option<String>,
+	/// Who initiated the operation, e.g. a user or service id, recorded in the audit log.
+	///
+	/// See [`Config::audit_log`].
+	///
+	/// [`Config::audit_log`]: crate::Config::audit_log
+	pub actor_id: Option<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+/// Per-call options for [`Romer::send_onchain_with_options`].
+///
+/// [`Romer::send_onchain_with_options`]: crate::Romer::send_onchain_with_options
+pub struct SendOnchainOptions {
+	/// Whether the user confirmed this payment with a second factor.
+	///
+	/// Required for payments above [`SpendingPolicy::confirmation_threshold_msat`].
+	///
+	/// [`SpendingPolicy::confirmation_threshold_msat`]: crate::SpendingPolicy::confirmation_threshold_msat
+	pub second_factor_confirmed: bool,
+	/// Who initiated the operation, e.g. a user or service id, recorded in the audit log.
+	///
+	/// See [`Config::audit_log`].
+	///
+	/// [`Config::audit_log`]: crate::Config::audit_log
+	pub actor_id: Option<String>,
+	/// A label to attach to the payment.
+	pub label: Option<String>,
+	/// Free-form metadata to attach to the payment.
+	pub metadata: HashMap<String, String>,
+	/// A reference to the payment in another system, e.g. an order id.
+	pub external_ref: Option<String>,
+}
+
+impl SendOnchainOptions {
+	pub(crate) fn tags(&self) -> PaymentTags {
+		PaymentTags {
+			label: self.label.clone(),
+			metadata: self.metadata.clone(),
+			external_ref: self.external_ref.clone(),
+		}
+	}
+}
@@ -0,0 +1,596 @@
+// This file is Copyright its original authors, visible in version contror
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+use std::cmp::Reverse;
+use std::collections::HashMap;
+
+use ldk_node::bitcoin::hashes::sha256::Hash as Sha256;
+use ldk_node::bitcoin::hashes::Hash;
+use ldk_node::lightning::events::PaymentFailureReason;
+use ldk_node::lightning::impl_writeable_tlv_based;
+use ldk_node::lightning::ln::channelmanager::PaymentId;
+use ldk_node::lightning::ln::{PaymentHash, PaymentPreimage};
+use ldk_node::payment::{PaymentDetails, PaymentDirection, PaymentKind, PaymentStatus};
+
+use crate::error::Error;
+use crate::fiat::FiatValue;
+use crate::hex_utils;
+
+/// The largest number of payments returned in a single [`PaymentPage`].
+pub const MAX_PAGE_SIZE: u32 = 1000;
+
+/// A payment along with the information Romer tracks on top of LDK Node.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Payment {
+	/// The payment as tracked by LDK Node.
+	pub details: PaymentDetails,
+	/// The fee paid to route an outbound payment, once it succeeded.
+	pub fee_paid_msat: Option<u64>,
+	/// The label given when the payment was made or requested.
+	pub label: Option<String>,
+	/// The free-form metadata given when the payment was made or requested.
+	pub metadata: HashMap<String, String>,
+	/// The external reference given when the payment was made or requested, e.g. an order id.
+	pub external_ref: Option<String>,
+	/// The value of the payment in fiat when it settled, if an exchange rate was available.
+	pub fiat_value: Option<FiatValue>,
+	/// The sub-account the payment is attributed to, see [`Romer::receive_for`] and
+	/// [`Romer::send_from`].
+	///
+	/// [`Romer::receive_for`]: crate::Romer::receive_for
+	/// [`Romer::send_from`]: crate::Romer::send_from
+	pub account_id: Option<String>,
+	/// Whether the payment was an invoice of our own node paid via [`Romer::send`], which is
+	/// settled internally without a lightning hop or fees.
+	///
+	/// [`Romer::send`]: crate::Romer::send
+	pub internal: bool,
+}
+
+impl Payment {
+	pub(crate) fn new(mut details: PaymentDetails, record: PaymentRecord) -> Self {
+		// LDK Node considers an invoice we paid internally failed, as we refuse to be paid for it
+		// over lightning afterwards.
+		if let Some(settled_at) = record.settled_internally_at {
+			details.status = PaymentStatus::Succeeded;
+			details.latest_update_timestamp = settled_at;
+		}
+		// LDK Node doesn't know the preimages of invoices we claim ourselves.
+		if let PaymentKind::Bolt11 { preimage: preimage @ None, .. } = &mut details.kind {
+			*preimage = record.preimage;
+		}
+		Self {
+			details,
+			internal: record.settled_internally_at.is_some(),
+			fee_paid_msat: record.fee_paid_msat,
+			label: record.label,
+			metadata: record.metadata,
+			external_ref: record.external_ref,
+			fiat_value: record.fiat_value,
+			account_id: record.account_id,
+		}
+	}
+}
+
+/// The information Romer persists about a payment, keyed by payment id.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct PaymentRecord {
+	pub(crate) fee_paid_msat: Option<u64>,
+	pub(crate) label: Option<String>,
+	pub(crate) external_ref: Option<String>,
+	pub(crate) metadata: HashMap<String, String>,
+	/// The details of a payment LDK Node doesn't track itself, i.e. on-chain payments and the
+	/// sending side of internal payments.
+	pub(crate) untracked_details: Option<PaymentDetails>,
+	/// The amount of an invoice to be paid via a JIT channel, before the LSP takes its fee.
+	pub(crate) jit_invoice_amount_msat: Option<u64>,
+	/// The currency an invoice was requested in, to value the payment in at settlement.
+	pub(crate) fiat_currency: Option<String>,
+	pub(crate) fiat_value: Option<FiatValue>,
+	/// Why an outbound payment failed, to report the same outcome if it is sent again.
+	pub(crate) failure_reason: Option<PaymentFailureReason>,
+	pub(crate) account_id: Option<String>,
+	/// When the payment was settled between two parties on this node, without a lightning hop.
+	pub(crate) settled_internally_at: Option<u64>,
+	/// Whether the invoice was created via [`Romer::receive_hold`], so has to be claimed manually.
+	///
+	/// [`Romer::receive_hold`]: crate::Romer::receive_hold
+	pub(crate) hold_invoice: bool,
+	/// The amount that can be claimed for an invoice we claim ourselves, once it was paid.
+	pub(crate) claimable_amount_msat: Option<u64>,
+	/// The block height at which LDK fails a payment to a hold invoice back if not claimed.
+	pub(crate) claim_deadline: Option<u32>,
+	/// The preimage of an invoice created via [`Romer::receive`] without a JIT channel, which we
+	/// claim ourselves so we can refuse payments to invoices that were settled internally.
+	///
+	/// [`Romer::receive`]: crate::Romer::receive
+	pub(crate) preimage: Option<PaymentPreimage>,
+}
+
+impl_writeable_tlv_based!(PaymentRecord, {
+	(0, fee_paid_msat, option),
+	(2, label, option),
+	(4, external_ref, option),
+	(6, metadata, (default_value, HashMap::new())),
+	(8, untracked_details, option),
+	(10, jit_invoice_amount_msat, option),
+	(12, fiat_currency, option),
+	(14, fiat_value, option),
+	(16, failure_reason, upgradable_option),
+	(18, account_id, option),
+	(20, settled_internally_at, option),
+	(22, hold_invoice, (default_value, false)),
+	(24, claimable_amount_msat, option),
+	(26, claim_deadline, option),
+	(28, preimage, option),
+});
+
+/// The user context attached to a payment when it is made or requested.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct PaymentTags {
+	pub(crate) label: Option<String>,
+	pub(crate) metadata: HashMap<String, String>,
+	pub(crate) external_ref: Option<String>,
+}
+
+impl PaymentTags {
+	pub(crate) fn is_empty(&self) -> bool {
+		self.label.is_none() && self.metadata.is_empty() && self.external_ref.is_none()
+	}
+
+	pub(crate) fn apply(self, record: &mut PaymentRecord) {
+		record.label = self.label;
+		record.metadata = self.metadata;
+		record.external_ref = self.external_ref;
+	}
+}
+
+impl PaymentRecord {
+	/// What to do with a payment that arrived for the invoice of this record, given whether the
+	/// invoice was cancelled.
+	pub(crate) fn claim_action(&self, cancelled: bool) -> ClaimAction {
+		if cancelled || self.settled_internally_at.is_some() {
+			ClaimAction::Refund
+		} else if let Some(preimage) = self.preimage {
+			ClaimAction::Claim(preimage)
+		} else {
+			ClaimAction::Hold
+		}
+	}
+}
+
+/// What we do with a payment that arrived for one of our invoices and waits to be claimed.
+#[derive(Debug, PartialEq)]
+pub(crate) enum ClaimAction {
+	/// Fail it back, as the hold invoice was cancelled or the invoice was paid internally before.
+	Refund,
+	/// Claim it right away, as the invoice was created via [`Romer::receive`].
+	///
+	/// [`Romer::receive`]: crate::Romer::receive
+	Claim(PaymentPreimage),
+	/// Leave it to the user to claim, as it pays a hold invoice.
+	Hold,
+}
+
+/// The key we store the payment a caller-supplied idempotency key was used for under.
+///
+/// Idempotency keys are hashed as they may contain characters the store doesn't allow in keys.
+pub(crate) fn idempotency_record_key(idempotency_key: &str) -> String {
+	hex_utils::to_string(&Sha256::hash(idempotency_key.as_bytes()).to_byte_array())
+}
+
+/// The id of the sending side of an internal payment, which can't share the id of the receiving
+/// side as LDK Node identifies that by the payment hash.
+pub(crate) fn internal_payment_id(payment_hash: &PaymentHash) -> PaymentId {
+	let mut preimage = b"romer-internal-payment".to_vec();
+	preimage.extend_from_slice(&payment_hash.0);
+	PaymentId(Sha256::hash(&preimage).to_byte_array())
+}
+
+pub(crate) fn record_key(details: &PaymentDetails) -> String {
+	hex_utils::to_string(&details.id.0)
+}
+
+/// Returns the payment hash of the given payment, if it has one.
+pub(crate) fn payment_hash(kind: &PaymentKind) -> Option<PaymentHash> {
+	match kind {
+		PaymentKind::Onchain => None,
+		PaymentKind::Bolt11 { hash, .. } => Some(*hash),
+		PaymentKind::Bolt11Jit { hash, .. } => Some(*hash),
+		PaymentKind::Bolt12Offer { hash, .. } => *hash,
+		PaymentKind::Bolt12Refund { hash, .. } => *hash,
+		PaymentKind::Spontaneous { hash, .. } => Some(*hash),
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// The kind of a payment, without any of its details.
+pub enum PaymentKindFilter {
+	/// An on-chain payment.
+	Onchain,
+	/// A BOLT 11 payment.
+	Bolt11,
+	/// A BOLT 11 payment received via a JIT channel.
+	Bolt11Jit,
+	/// A BOLT 12 offer payment.
+	Bolt12Offer,
+	/// A BOLT 12 refund payment.
+	Bolt12Refund,
+	/// A spontaneous ("keysend") payment.
+	Spontaneous,
+}
+
+impl PaymentKindFilter {
+	fn matches(&self, kind: &PaymentKind) -> bool {
+		matches!(
+			(self, kind),
+			(PaymentKindFilter::Onchain, PaymentKind::Onchain)
+				| (PaymentKindFilter::Bolt11, PaymentKind::Bolt11 { .. })
+				| (PaymentKindFilter::Bolt11Jit, PaymentKind::Bolt11Jit { .. })
+				| (PaymentKindFilter::Bolt12Offer, PaymentKind::Bolt12Offer { .. })
+				| (PaymentKindFilter::Bolt12Refund, PaymentKind::Bolt12Refund { .. })
+				| (PaymentKindFilter::Spontaneous, PaymentKind::Spontaneous { .. })
+		)
+	}
+}
+
+#[derive(Debug, Clone, Default)]
+/// Restricts which payments are returned by [`Romer::list_payments_filtered`].
+///
+/// Unset fields match every payment.
+///
+/// [`Romer::list_payments_filtered`]: crate::Romer::list_payments_filtered
+pub struct PaymentFilter {
+	/// Only include payments in this direction.
+	pub direction: Option<PaymentDirection>,
+	/// Only include payments with this status.
+	pub status: Option<PaymentStatus>,
+	/// Only include payments of this kind.
+	pub kind: Option<PaymentKindFilter>,
+	/// Only include payments last updated at or after this UNIX timestamp, in seconds.
+	pub from_timestamp: Option<u64>,
+	/// Only include payments last updated before this UNIX timestamp, in seconds.
+	pub to_timestamp: Option<u64>,
+	/// Only include payments of at least this amount.
+	pub min_amount_msat: Option<u64>,
+	/// Only include payments of at most this amount.
+	pub max_amount_msat: Option<u64>,
+	/// Only include payments with exactly this external reference.
+	pub external_ref: Option<String>,
+	/// Only include payments attributed to this sub-account.
+	pub account_id: Option<String>,
+	/// Only include payments whose label, external reference or metadata values contain this
+	/// text, ignoring case.
+	pub search: Option<String>,
+}
+
+impl PaymentFilter {
+	pub(crate) fn matches(&self, payment: &Payment) -> bool {
+		if !self.matches_details(&payment.details) {
+			return false;
+		}
+		if matches!(&self.external_ref, Some(external_ref) if payment.external_ref.as_ref() != Some(external_ref))
+		{
+			return false;
+		}
+		if matches!(&self.account_id, Some(account_id) if payment.account_id.as_ref() != Some(account_id))
+		{
+			return false;
+		}
+		if let Some(search) = &self.search {
+			let search = search.to_lowercase();
+			let contains = |text: &String| text.to_lowercase().contains(&search);
+			let found = payment.label.iter().any(contains)
+				|| payment.external_ref.iter().any(contains)
+				|| payment.metadata.values().any(contains);
+			if !found {
+				return false;
+			}
+		}
+		true
+	}
+
+	fn matches_details(&self, payment: &PaymentDetails) -> bool {
+		if matches!(self.direction, Some(direction) if direction != payment.direction) {
+			return false;
+		}
+		if matches!(self.status, Some(status) if status != payment.status) {
+			return false;
+		}
+		if matches!(self.kind, Some(kind) if !kind.matches(&payment.kind)) {
+			return false;
+		}
+		if matches!(self.from_timestamp, Some(from) if payment.latest_update_timestamp < from) {
+			return false;
+		}
+		if matches!(self.to_timestamp, Some(to) if payment.latest_update_timestamp >= to) {
+			return false;
+		}
+		if self.min_amount_msat.is_some() || self.max_amount_msat.is_some() {
+			let amount_msat = match payment.amount_msat {
+				Some(amount_msat) => amount_msat,
+				None => return false,
+			};
+			if matches!(self.min_amount_msat, Some(min) if amount_msat < min) {
+				return false;
+			}
+			if matches!(self.max_amount_msat, Some(max) if amount_msat > max) {
+				return false;
+			}
+		}
+		true
+	}
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+/// A span of time, as UNIX timestamps in seconds.
+///
+/// Unset bounds are open-ended.
+pub struct TimeRange {
+	/// The start of the range, inclusive.
+	pub from_timestamp: Option<u64>,
+	/// The end of the range, exclusive.
+	pub to_timestamp: Option<u64>,
+}
+
+impl TimeRange {
+	pub(crate) fn contains(&self, timestamp: u64) -> bool {
+		!matches!(self.from_timestamp, Some(from) if timestamp < from)
+			&& !matches!(self.to_timestamp, Some(to) if timestamp >= to)
+	}
+}
+
+#[derive(Debug, Clone)]
+/// Which page of results to return from [`Romer::list_payments_filtered`].
+///
+/// [`Romer::list_payments_filtered`]: crate::Romer::list_payments_filtered
+pub struct Page {
+	/// The [`PaymentPage::next_cursor`] of the previous page, or `None` for the first page.
+	pub cursor: Option<String>,
+	/// How many payments to return at most, capped at [`MAX_PAGE_SIZE`].
+	pub limit: u32,
+}
+
+impl Default for Page {
+	fn default() -> Self {
+		Self { cursor: None, limit: 50 }
+	}
+}
+
+/// A page of payments, most recently updated first.
+pub struct PaymentPage {
+	/// The payments on this page.
+	pub payments: Vec<Payment>,
+	/// The cursor to fetch the next page with, or `None` if this is the last page.
+	pub next_cursor: Option<String>,
+}
+
+type SortKey = (Reverse<u64>, Reverse<[u8; 32]>);
+
+fn sort_key(payment: &Payment) -> SortKey {
+	(Reverse(payment.details.latest_update_timestamp), Reverse(payment.details.id.0))
+}
+
+fn encode_cursor(payment: &Payment) -> String {
+	let details = &payment.details;
+	format!("{}-{}", details.latest_update_timestamp, hex_utils::to_string(&details.id.0))
+}
+
+fn decode_cursor(cursor: &str) -> Result<SortKey, Error> {
+	let mut parts = cursor.splitn(2, '-');
+	let timestamp = parts.next().and_then(|t| t.parse::<u64>().ok());
+	let id = parts.next().and_then(hex_utils::to_vec).and_then(|id| id.try_into().ok());
+	match (timestamp, id) {
+		(Some(timestamp), Some(id)) => Ok((Reverse(timestamp), Reverse(id))),
+		_ => Err(Error::InvalidCursor),
+	}
+}
+
+/// Sorts the given payments by most recently updated and returns the requested page.
+///
+/// Ties on the update timestamp are broken by payment id so pages never overlap.
+pub(crate) fn paginate(mut payments: Vec<Payment>, page: &Page) -> Result<PaymentPage, Error> {
+	let limit = page.limit.clamp(1, MAX_PAGE_SIZE) as usize;
+
+	payments.sort_unstable_by_key(sort_key);
+	if let Some(cursor) = &page.cursor {
+		let after = decode_cursor(cursor)?;
+		payments.retain(|payment| sort_key(payment) > after);
+	}
+
+	let next_cursor =
+		if payments.len() > limit { Some(encode_cursor(&payments[limit - 1])) } else { None };
+	payments.truncate(limit);
+
+	Ok(PaymentPage { payments, next_cursor })
+}
+
+#[cfg(test)]
+mod test {
+	use ldk_node::lightning::util::ser::{Readable, Writeable};
+
+	use super::*;
+
+	fn payment(
+		id: u8, timestamp: u64, direction: PaymentDirection, amount_msat: Option<u64>,
+	) -> Payment {
+		let details = PaymentDetails {
+			id: PaymentId([id; 32]),
+			kind: PaymentKind::Bolt11 { hash: PaymentHash([id; 32]), preimage: None, secret: None },
+			amount_msat,
+			direction,
+			status: PaymentStatus::Succeeded,
+			latest_update_timestamp: timestamp,
+		};
+		Payment::new(details, PaymentRecord::default())
+	}
+
+	fn ids(page: &PaymentPage) -> Vec<u8> {
+		page.payments.iter().map(|p| p.details.id.0[0]).collect()
+	}
+
+	#[test]
+	fn filters_by_direction_kind_time_and_amount() {
+		let inbound = payment(1, 100, PaymentDirection::Inbound, Some(5_000));
+		let outbound = payment(2, 200, PaymentDirection::Outbound, Some(50_000));
+
+		let filter =
+			PaymentFilter { direction: Some(PaymentDirection::Inbound), ..Default::default() };
+		assert!(filter.matches(&inbound));
+		assert!(!filter.matches(&outbound));
+
+		let filter =
+			PaymentFilter { kind: Some(PaymentKindFilter::Bolt11Jit), ..Default::default() };
+		assert!(!filter.matches(&inbound));
+
+		let filter = PaymentFilter {
+			from_timestamp: Some(100),
+			to_timestamp: Some(200),
+			..Default::default()
+		};
+		assert!(filter.matches(&inbound));
+		assert!(!filter.matches(&outbound));
+
+		let filter = PaymentFilter { min_amount_msat: Some(10_000), ..Default::default() };
+		assert!(!filter.matches(&inbound));
+		assert!(filter.matches(&outbound));
+		assert!(!filter.matches(&payment(3, 300, PaymentDirection::Inbound, None)));
+	}
+
+	#[test]
+	fn searches_labels_and_metadata() {
+		let mut tagged = payment(1, 100, PaymentDirection::Inbound, None);
+		tagged.label = Some("Alpaca Socks".to_string());
+		tagged.external_ref = Some("order-42".to_string());
+		tagged.metadata.insert("customer".to_string(), "satoshi".to_string());
+		let untagged = payment(2, 100, PaymentDirection::Inbound, None);
+
+		let filter = PaymentFilter { search: Some("alpaca".to_string()), ..Default::default() };
+		assert!(filter.matches(&tagged));
+		assert!(!filter.matches(&untagged));
+
+		let filter = PaymentFilter { search: Some("SATOSHI".to_string()), ..Default::default() };
+		assert!(filter.matches(&tagged));
+
+		let filter =
+			PaymentFilter { external_ref: Some("order-42".to_string()), ..Default::default() };
+		assert!(filter.matches(&tagged));
+		assert!(!filter.matches(&untagged));
+
+		let filter =
+			PaymentFilter { external_ref: Some("order".to_string()), ..Default::default() };
+		assert!(!filter.matches(&tagged));
+	}
+
+	#[test]
+	fn internal_payments_are_settled() {
+		let pending = PaymentDetails {
+			status: PaymentStatus::Pending,
+			..payment(1, 100, PaymentDirection::Inbound, Some(1_000)).details
+		};
+		let preimage = PaymentPreimage([7; 32]);
+		let record = PaymentRecord {
+			settled_internally_at: Some(200),
+			preimage: Some(preimage),
+			..Default::default()
+		};
+		let settled = Payment::new(pending.clone(), record);
+		assert!(settled.internal);
+		assert_eq!(settled.details.status, PaymentStatus::Succeeded);
+		assert_eq!(settled.details.latest_update_timestamp, 200);
+		assert!(matches!(
+			settled.details.kind,
+			PaymentKind::Bolt11 { preimage: Some(p), .. } if p == preimage
+		));
+		assert!(!Payment::new(pending, PaymentRecord::default()).internal);
+
+		// The sending side never shares the id of the receiving side.
+		let payment_hash = PaymentHash([1; 32]);
+		assert_ne!(internal_payment_id(&payment_hash), PaymentId(payment_hash.0));
+		assert_ne!(internal_payment_id(&payment_hash), internal_payment_id(&PaymentHash([2; 32])));
+	}
+
+	#[test]
+	fn round_trips_payment_records() {
+		let mut metadata = HashMap::new();
+		metadata.insert("customer".to_string(), "satoshi".to_string());
+		let record = PaymentRecord {
+			fee_paid_msat: Some(1_000),
+			label: Some("socks".to_string()),
+			external_ref: None,
+			metadata,
+			untracked_details: Some(
+				payment(1, 100, PaymentDirection::Outbound, Some(1_000)).details,
+			),
+			jit_invoice_amount_msat: Some(2_000),
+			fiat_currency: Some("USD".to_string()),
+			fiat_value: Some(FiatValue::new(1_000, "USD".to_string(), 60_000.0)),
+			failure_reason: Some(PaymentFailureReason::RouteNotFound),
+			account_id: Some("alice".to_string()),
+			settled_internally_at: None,
+			hold_invoice: true,
+			claimable_amount_msat: Some(1_000),
+			claim_deadline: Some(800_000),
+			preimage: Some(PaymentPreimage([3; 32])),
+		};
+		let read = PaymentRecord::read(&mut &record.encode()[..]).unwrap();
+		assert_eq!(read, record);
+	}
+
+	#[test]
+	fn pages_through_payments_newest_first() {
+		let payments = vec![
+			payment(1, 100, PaymentDirection::Inbound, None),
+			payment(2, 300, PaymentDirection::Inbound, None),
+			payment(3, 200, PaymentDirection::Inbound, None),
+			payment(4, 200, PaymentDirection::Inbound, None),
+			payment(5, 50, PaymentDirection::Inbound, None),
+		];
+
+		let first = paginate(payments.clone(), &Page { cursor: None, limit: 2 }).unwrap();
+		assert_eq!(ids(&first), vec![2, 4]);
+
+		let second =
+			paginate(payments.clone(), &Page { cursor: first.next_cursor, limit: 2 }).unwrap();
+		assert_eq!(ids(&second), vec![3, 1]);
+
+		let last = paginate(payments, &Page { cursor: second.next_cursor, limit: 2 }).unwrap();
+		assert_eq!(ids(&last), vec![5]);
+		assert!(last.next_cursor.is_none());
+	}
+
+	#[test]
+	fn rejects_invalid_cursor() {
+		let page = Page { cursor: Some("garbage".to_string()), limit: 10 };
+		assert!(matches!(paginate(Vec::new(), &page), Err(Error::InvalidCursor)));
+	}
+
+	#[test]
+	fn claims_payments_to_received_invoices_right_away() {
+		// What `Romer::receive` stores for an invoice it doesn't need a JIT channel for.
+		let preimage = PaymentPreimage([7; 32]);
+		let record = PaymentRecord { preimage: Some(preimage), ..Default::default() };
+		assert_eq!(record.claim_action(false), ClaimAction::Claim(preimage));
+
+		let record = PaymentRecord { hold_invoice: true, ..Default::default() };
+		assert_eq!(record.claim_action(false), ClaimAction::Hold);
+	}
+
+	#[test]
+	fn refunds_payments_to_cancelled_or_internally_settled_invoices() {
+		let record = PaymentRecord { hold_invoice: true, ..Default::default() };
+		assert_eq!(record.claim_action(true), ClaimAction::Refund);
+
+		let record = PaymentRecord {
+			preimage: Some(PaymentPreimage([7; 32])),
+			settled_internally_at: Some(100),
+			..Default::default()
+		};
+		assert_eq!(record.claim_action(false), ClaimAction::Refund);
+	}
+}
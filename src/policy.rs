@@ -0,0 +1,326 @@
+// This file is Copyright its original authors, visible in version contror
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use ldk_node::bitcoin::secp256k1::PublicKey;
+use ldk_node::bitcoin::{Address, Network, ScriptBuf};
+use ldk_node::io::sqlite_store::SqliteStore;
+use ldk_node::lightning::impl_writeable_tlv_based;
+use ldk_node::lightning::ln::channelmanager::PaymentId;
+
+use crate::error::Error;
+use crate::store::{self, PersistedMap};
+
+const DAY_SECS: u64 = 24 * 60 * 60;
+const WEEK_SECS: u64 = 7 * DAY_SECS;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+/// Limits on how much can be sent from the wallet.
+///
+/// Applies to lightning and on-chain payments alike and counts the amount sent, without fees.
+pub struct SpendingPolicy {
+	/// The most a single payment may send.
+	pub max_payment_msat: Option<u64>,
+	/// The most that may be sent within any 24 hours.
+	pub daily_limit_msat: Option<u64>,
+	/// The most that may be sent within any 7 days.
+	pub weekly_limit_msat: Option<u64>,
+	/// Payments larger than this require the user to have confirmed them with a second factor.
+	///
+	/// See [`SendOptions::second_factor_confirmed`].
+	///
+	/// [`SendOptions::second_factor_confirmed`]: crate::SendOptions::second_factor_confirmed
+	pub confirmation_threshold_msat: Option<u64>,
+}
+
+impl SpendingPolicy {
+	fn check(
+		&self, amount_msat: u64, second_factor_confirmed: bool, spent_today_msat: u64,
+		spent_this_week_msat: u64,
+	) -> Result<(), Error> {
+		let exceeds = |limit_msat: Option<u64>, spent_msat: u64| {
+			matches!(
+				limit_msat,
+				Some(limit_msat) if spent_msat.saturating_add(amount_msat) > limit_msat
+			)
+		};
+		if exceeds(self.max_payment_msat, 0) {
+			return Err(Error::PaymentLimitExceeded);
+		}
+		if exceeds(self.daily_limit_msat, spent_today_msat) {
+			return Err(Error::DailyLimitExceeded);
+		}
+		if exceeds(self.weekly_limit_msat, spent_this_week_msat) {
+			return Err(Error::WeeklyLimitExceeded);
+		}
+		if exceeds(self.confirmation_threshold_msat, 0) && !second_factor_confirmed {
+			return Err(Error::SecondFactorRequired);
+		}
+		Ok(())
+	}
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+/// Restrictions on where funds can be sent.
+pub enum DestinationPolicy {
+	/// Funds can be sent anywhere.
+	#[default]
+	Unrestricted,
+	/// Funds can only be sent to the listed destinations.
+	Allowlist {
+		/// The on-chain addresses funds can be sent to.
+		addresses: Vec<String>,
+		/// The lightning nodes whose invoices can be paid.
+		node_ids: Vec<PublicKey>,
+	},
+	/// Funds can be sent anywhere but the listed destinations.
+	Denylist {
+		/// The on-chain addresses funds can't be sent to.
+		addresses: Vec<String>,
+		/// The lightning nodes whose invoices can't be paid.
+		node_ids: Vec<PublicKey>,
+	},
+}
+
+/// A [`DestinationPolicy`] with its addresses parsed, to check payments against.
+pub(crate) struct DestinationFilter {
+	policy: DestinationPolicy,
+	/// The scripts the policy's addresses pay to.
+	scripts: Vec<ScriptBuf>,
+}
+
+impl DestinationFilter {
+	/// Fails with [`Error::InvalidConfig`] if any of the policy's addresses is invalid or belongs
+	/// to another network.
+	pub(crate) fn new(policy: &DestinationPolicy, network: Network) -> Result<Self, Error> {
+		let addresses = match policy {
+			DestinationPolicy::Unrestricted => &[][..],
+			DestinationPolicy::Allowlist { addresses, .. }
+			| DestinationPolicy::Denylist { addresses, .. } => &addresses[..],
+		};
+		let scripts = addresses
+			.iter()
+			.map(|address| {
+				let address = Address::from_str(address).map_err(|_e| Error::InvalidConfig)?;
+				let address =
+					address.require_network(network).map_err(|_e| Error::InvalidConfig)?;
+				Ok(address.script_pubkey())
+			})
+			.collect::<Result<Vec<_>, Error>>()?;
+		Ok(Self { policy: policy.clone(), scripts })
+	}
+
+	/// Whether invoices issued by `node_id` can be paid.
+	pub(crate) fn allows_node(&self, node_id: &PublicKey) -> bool {
+		match &self.policy {
+			DestinationPolicy::Unrestricted => true,
+			DestinationPolicy::Allowlist { node_ids, .. } => node_ids.contains(node_id),
+			DestinationPolicy::Denylist { node_ids, .. } => !node_ids.contains(node_id),
+		}
+	}
+
+	/// Whether funds can be sent to `address`.
+	///
+	/// Addresses are compared by the script they pay to, so differences in case don't matter.
+	pub(crate) fn allows_address(&self, address: &Address) -> bool {
+		let listed = self.scripts.contains(&address.script_pubkey());
+		match self.policy {
+			DestinationPolicy::Unrestricted => true,
+			DestinationPolicy::Allowlist { .. } => listed,
+			DestinationPolicy::Denylist { .. } => !listed,
+		}
+	}
+}
+
+/// An amount we sent, or are in the process of sending.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Spend {
+	timestamp: u64,
+	amount_msat: u64,
+	/// The lightning payment the amount is sent with, to release it if the payment fails.
+	payment_id: Option<PaymentId>,
+}
+
+impl_writeable_tlv_based!(Spend, {
+	(0, timestamp, required),
+	(2, amount_msat, required),
+	(4, payment_id, option),
+});
+
+/// The persisted record of recent spends that [`SpendingPolicy`] limits are enforced against.
+///
+/// Spends are recorded before a payment is initiated, so concurrent payments can't jointly
+/// exceed a limit, and released again if the payment fails.
+pub(crate) struct SpendTracker {
+	spends: PersistedMap<Spend>,
+	/// The key of the next spend, also serializing checking limits with recording a spend.
+	next_sequence: Mutex<u64>,
+}
+
+impl SpendTracker {
+	pub(crate) fn load(kv_store: Arc<SqliteStore>) -> Result<Self, Error> {
+		let spends: PersistedMap<Spend> =
+			PersistedMap::load(kv_store, store::SPENDING_SECONDARY_NAMESPACE)?;
+		let next_sequence = spends
+			.entries()
+			.iter()
+			.filter_map(|(key, _)| key.parse::<u64>().ok())
+			.max()
+			.map_or(0, |sequence| sequence + 1);
+		Ok(Self { spends, next_sequence: Mutex::new(next_sequence) })
+	}
+
+	/// Checks that sending `amount_msat` is within `policy` and records the spend if so.
+	///
+	/// Returns the key to [`Self::release`] the spend with if the payment can't be initiated.
+	pub(crate) fn reserve(
+		&self, policy: &SpendingPolicy, amount_msat: u64, payment_id: Option<PaymentId>,
+		second_factor_confirmed: bool, now: u64,
+	) -> Result<String, Error> {
+		let mut next_sequence = self.next_sequence.lock().unwrap();
+
+		let mut spent_today_msat = 0;
+		let mut spent_this_week_msat = 0;
+		for (key, spend) in self.spends.entries() {
+			let age_secs = now.saturating_sub(spend.timestamp);
+			if age_secs >= WEEK_SECS {
+				// Older spends don't count towards any limit anymore.
+				self.spends.remove(&key)?;
+				continue;
+			}
+			spent_this_week_msat += spend.amount_msat;
+			if age_secs < DAY_SECS {
+				spent_today_msat += spend.amount_msat;
+			}
+		}
+		policy.check(
+			amount_msat,
+			second_factor_confirmed,
+			spent_today_msat,
+			spent_this_week_msat,
+		)?;
+
+		let key = format!("{:020}", *next_sequence);
+		self.spends.insert(&key, Spend { timestamp: now, amount_msat, payment_id })?;
+		*next_sequence += 1;
+		Ok(key)
+	}
+
+	/// Releases a spend recorded by [`Self::reserve`], e.g. because sending failed.
+	pub(crate) fn release(&self, key: &str) -> Result<(), Error> {
+		self.spends.remove(key)
+	}
+
+	/// Releases the spend of a lightning payment that failed.
+	pub(crate) fn release_payment(&self, payment_id: &PaymentId) -> Result<(), Error> {
+		for (key, spend) in self.spends.entries() {
+			if spend.payment_id.as_ref() == Some(payment_id) {
+				self.spends.remove(&key)?;
+			}
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	const NOW: u64 = 1_700_000_000;
+
+	#[test]
+	fn enforces_limits_in_order() {
+		let policy = SpendingPolicy {
+			max_payment_msat: Some(100_000),
+			daily_limit_msat: Some(150_000),
+			weekly_limit_msat: Some(200_000),
+			confirmation_threshold_msat: Some(50_000),
+		};
+		assert_eq!(policy.check(100_001, true, 0, 0), Err(Error::PaymentLimitExceeded));
+		assert_eq!(policy.check(100_000, true, 50_001, 50_001), Err(Error::DailyLimitExceeded));
+		assert_eq!(policy.check(100_000, true, 0, 100_001), Err(Error::WeeklyLimitExceeded));
+		assert_eq!(policy.check(50_001, false, 0, 0), Err(Error::SecondFactorRequired));
+		assert_eq!(policy.check(50_000, false, 100_000, 150_000), Ok(()));
+		let uncapped = SpendingPolicy { max_payment_msat: None, ..policy };
+		assert_eq!(uncapped.check(u64::MAX, true, 1, 1), Err(Error::DailyLimitExceeded));
+		assert_eq!(SpendingPolicy::default().check(u64::MAX / 2, false, 0, 0), Ok(()));
+	}
+
+	#[test]
+	fn checks_destinations() {
+		let address = |s: &str| Address::from_str(s).unwrap().assume_checked();
+		let listed = "bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq";
+		let other = "1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN2";
+		let node_id = PublicKey::from_slice(&[2; 33]).unwrap();
+
+		let filter = |policy| DestinationFilter::new(&policy, Network::Bitcoin).unwrap();
+		let allowlist = filter(DestinationPolicy::Allowlist {
+			addresses: vec![listed.to_uppercase()],
+			node_ids: vec![node_id],
+		});
+		assert!(allowlist.allows_address(&address(listed)));
+		assert!(!allowlist.allows_address(&address(other)));
+		assert!(allowlist.allows_node(&node_id));
+
+		let denylist = filter(DestinationPolicy::Denylist {
+			addresses: vec![listed.to_string()],
+			node_ids: vec![],
+		});
+		assert!(!denylist.allows_address(&address(listed)));
+		assert!(denylist.allows_address(&address(other)));
+		assert!(denylist.allows_node(&node_id));
+		assert!(filter(DestinationPolicy::Unrestricted).allows_address(&address(listed)));
+
+		let invalid = |address: &str| DestinationPolicy::Denylist {
+			addresses: vec![address.to_string()],
+			node_ids: vec![],
+		};
+		let res = DestinationFilter::new(&invalid("not an address"), Network::Bitcoin);
+		assert!(matches!(res, Err(Error::InvalidConfig)));
+		let res = DestinationFilter::new(&invalid(listed), Network::Signet);
+		assert!(matches!(res, Err(Error::InvalidConfig)));
+	}
+
+	#[test]
+	fn tracks_spends_across_restarts() {
+		let kv_store = store::test_store("spending");
+		let policy = SpendingPolicy {
+			daily_limit_msat: Some(100_000),
+			weekly_limit_msat: Some(200_000),
+			..SpendingPolicy::default()
+		};
+
+		let tracker = SpendTracker::load(Arc::clone(&kv_store)).unwrap();
+		tracker.reserve(&policy, 60_000, None, false, NOW - 6 * DAY_SECS).unwrap();
+		tracker.reserve(&policy, 60_000, None, false, NOW - 2 * DAY_SECS).unwrap();
+		let payment_id = PaymentId([1; 32]);
+		tracker.reserve(&policy, 60_000, Some(payment_id), false, NOW).unwrap();
+		assert_eq!(
+			tracker.reserve(&policy, 60_000, None, false, NOW),
+			Err(Error::DailyLimitExceeded)
+		);
+
+		// A failed payment no longer counts.
+		tracker.release_payment(&payment_id).unwrap();
+		let key = tracker.reserve(&policy, 30_000, None, false, NOW).unwrap();
+
+		let tracker = SpendTracker::load(kv_store).unwrap();
+		let later = NOW + DAY_SECS / 2;
+		assert_eq!(
+			tracker.reserve(&policy, 60_000, None, false, later),
+			Err(Error::WeeklyLimitExceeded)
+		);
+		tracker.release(&key).unwrap();
+		tracker.reserve(&policy, 60_000, None, false, later).unwrap();
+
+		// The oldest spend drops out of the weekly window.
+		assert!(tracker.reserve(&policy, 40_000, None, false, NOW + DAY_SECS).is_ok());
+	}
+}
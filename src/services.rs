@@ -6,13 +6,17 @@
 // <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
 // You may not use this file except in accordance with one or both of these
 // licenses.
+use std::net::{TcpStream, ToSocketAddrs};
 use std::str::FromStr;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
 
 use ldk_node::{
 	bitcoin::{secp256k1::PublicKey, Network},
 	lightning::ln::msgs::SocketAddress,
 };
 
+use crate::config::{no_fee_limits, Config, LspConfig};
 use crate::error::Error;
 
 const SIGNET_ESPLORA_URL: &str = "https://staging.e.r.cequals.xyz";
@@ -29,30 +33,148 @@ const MAINNET_LSP_NODE_ID: &str =
 const MAINNET_LSP_IP_PORT: SocketAddress =
 	SocketAddress::TcpIpV4 { addr: [3, 226, 165, 222], port: 9735 };
 
+/// How long we wait for the LSPs to be reachable when picking one at startup.
+const LSP_REACHABILITY_TIMEOUT: Duration = Duration::from_secs(5);
+
 #[derive(Clone)]
 pub struct ServiceConfig {
 	pub esplora_url: String,
 	pub rgs_url: String,
-	pub lsp_node_id: PublicKey,
-	pub lsp_ip_port: SocketAddress,
+	pub lsps: Vec<LspConfig>,
 }
 
 impl ServiceConfig {
-	pub fn new(network: Network) -> Result<Self, Error> {
-		match network {
-			Network::Signet => Ok(Self {
-				esplora_url: SIGNET_ESPLORA_URL.to_string(),
-				rgs_url: SIGNET_RGS_URL.to_string(),
-				lsp_node_id: PublicKey::from_str(SIGNET_LSP_NODE_ID).expect("valid public key"),
-				lsp_ip_port: SIGNET_LSP_IP_PORT,
-			}),
-			Network::Bitcoin => Ok(Self {
-				esplora_url: MAINNET_ESPLORA_URL.to_string(),
-				rgs_url: MAINNET_RGS_URL.to_string(),
-				lsp_node_id: PublicKey::from_str(MAINNET_LSP_NODE_ID).expect("valid public key"),
-				lsp_ip_port: MAINNET_LSP_IP_PORT,
-			}),
+	pub fn new(network: Network, api_token: &str, config: &Config) -> Result<Self, Error> {
+		let (esplora_url, rgs_url, lsp_node_id, lsp_ip_port) = match network {
+			Network::Signet => {
+				(SIGNET_ESPLORA_URL, SIGNET_RGS_URL, SIGNET_LSP_NODE_ID, SIGNET_LSP_IP_PORT)
+			},
+			Network::Bitcoin => {
+				(MAINNET_ESPLORA_URL, MAINNET_RGS_URL, MAINNET_LSP_NODE_ID, MAINNET_LSP_IP_PORT)
+			},
 			_ => return Err(Error::NetworkNotSupported),
+		};
+
+		let lsps = if config.lsps.is_empty() {
+			vec![LspConfig {
+				node_id: PublicKey::from_str(lsp_node_id).expect("valid public key"),
+				address: lsp_ip_port,
+				token: Some(api_token.to_string()),
+				fee_limits: no_fee_limits(),
+			}]
+		} else {
+			config.lsps.clone()
+		};
+
+		Ok(Self { esplora_url: esplora_url.to_string(), rgs_url: rgs_url.to_string(), lsps })
+	}
+
+	/// Picks the highest-ranked LSP that is reachable, falling back to the first one.
+	///
+	/// All LSPs are checked in parallel, and LSPs that don't turn out to be reachable within
+	/// [`LSP_REACHABILITY_TIMEOUT`] are considered unreachable.
+	pub fn select_lsp(&self, is_reachable: fn(&LspConfig) -> bool) -> &LspConfig {
+		let (sender, receiver) = mpsc::channel();
+		for (index, lsp) in self.lsps.iter().enumerate() {
+			let sender = sender.clone();
+			let lsp = lsp.clone();
+			std::thread::spawn(move || {
+				let _ = sender.send((index, is_reachable(&lsp)));
+			});
+		}
+
+		let deadline = Instant::now() + LSP_REACHABILITY_TIMEOUT;
+		let mut reachable: Vec<Option<bool>> = vec![None; self.lsps.len()];
+		loop {
+			// Once every LSP ranked above one is known to be unreachable, we can stop waiting.
+			match reachable.iter().position(|status| *status != Some(false)) {
+				Some(index) if reachable[index] == Some(true) => return &self.lsps[index],
+				Some(_) => {},
+				None => return &self.lsps[0],
+			}
+
+			match receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+				Ok((index, is_reachable)) => reachable[index] = Some(is_reachable),
+				Err(_) => {
+					let index = reachable.iter().position(|status| *status == Some(true));
+					return &self.lsps[index.unwrap_or(0)];
+				},
+			}
+		}
+	}
+}
+
+/// Checks whether we can open a TCP connection to the given LSP.
+pub fn is_reachable(lsp: &LspConfig) -> bool {
+	let addrs = match lsp.address.to_socket_addrs() {
+		Ok(addrs) => addrs,
+		Err(_) => return false,
+	};
+	addrs
+		.into_iter()
+		.any(|addr| TcpStream::connect_timeout(&addr, LSP_REACHABILITY_TIMEOUT).is_ok())
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	fn lsp(node_id: &str, port: u16) -> LspConfig {
+		LspConfig {
+			node_id: PublicKey::from_str(node_id).unwrap(),
+			address: SocketAddress::TcpIpV4 { addr: [127, 0, 0, 1], port },
+			token: None,
+			fee_limits: no_fee_limits(),
 		}
 	}
+
+	#[test]
+	fn defaults_to_cequals_lsp() {
+		let services = ServiceConfig::new(Network::Signet, "Stoken", &Config::default()).unwrap();
+		assert_eq!(services.lsps.len(), 1);
+		assert_eq!(services.lsps[0].node_id.to_string(), SIGNET_LSP_NODE_ID);
+		assert_eq!(services.lsps[0].token, Some("Stoken".to_string()));
+	}
+
+	#[test]
+	fn unsupported_network() {
+		let res = ServiceConfig::new(Network::Regtest, "Rtoken", &Config::default());
+		assert!(matches!(res, Err(Error::NetworkNotSupported)));
+	}
+
+	#[test]
+	fn selects_first_reachable_lsp() {
+		let config = Config {
+			lsps: vec![lsp(SIGNET_LSP_NODE_ID, 1), lsp(MAINNET_LSP_NODE_ID, 2)],
+			..Config::default()
+		};
+		let services = ServiceConfig::new(Network::Signet, "Stoken", &config).unwrap();
+
+		let selected = services.select_lsp(|lsp| lsp.node_id.to_string() == MAINNET_LSP_NODE_ID);
+		assert_eq!(selected.node_id.to_string(), MAINNET_LSP_NODE_ID);
+
+		let selected = services.select_lsp(|_| false);
+		assert_eq!(selected.node_id.to_string(), SIGNET_LSP_NODE_ID);
+	}
+
+	#[test]
+	fn doesnt_wait_for_unresponsive_lsps() {
+		let config = Config {
+			lsps: vec![lsp(SIGNET_LSP_NODE_ID, 1), lsp(MAINNET_LSP_NODE_ID, 2)],
+			..Config::default()
+		};
+		let services = ServiceConfig::new(Network::Signet, "Stoken", &config).unwrap();
+
+		// The preferred LSP hangs, so we settle for the reachable one once we stop waiting.
+		let started = Instant::now();
+		let selected = services.select_lsp(|lsp| {
+			if lsp.node_id.to_string() == SIGNET_LSP_NODE_ID {
+				std::thread::sleep(LSP_REACHABILITY_TIMEOUT * 2);
+				return true;
+			}
+			true
+		});
+		assert_eq!(selected.node_id.to_string(), MAINNET_LSP_NODE_ID);
+		assert!(started.elapsed() < LSP_REACHABILITY_TIMEOUT * 2);
+	}
 }
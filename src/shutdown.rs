@@ -0,0 +1,65 @@
+// This file is Copyright its original authors, visible in version contror
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+/// Tells our background threads to exit once [`Romer::stop`] is called.
+///
+/// [`Romer::stop`]: crate::Romer::stop
+pub(crate) struct Shutdown {
+	stopped: Mutex<bool>,
+	condvar: Condvar,
+}
+
+impl Shutdown {
+	pub(crate) fn new() -> Self {
+		Self { stopped: Mutex::new(false), condvar: Condvar::new() }
+	}
+
+	pub(crate) fn stop(&self) {
+		*self.stopped.lock().unwrap() = true;
+		self.condvar.notify_all();
+	}
+
+	pub(crate) fn is_stopped(&self) -> bool {
+		*self.stopped.lock().unwrap()
+	}
+
+	/// Sleeps for `timeout` unless we stop before, returning whether we stopped.
+	pub(crate) fn wait(&self, timeout: Duration) -> bool {
+		let stopped = self.stopped.lock().unwrap();
+		let (stopped, _) =
+			self.condvar.wait_timeout_while(stopped, timeout, |stopped| !*stopped).unwrap();
+		*stopped
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use std::sync::Arc;
+	use std::time::Instant;
+
+	use super::*;
+
+	#[test]
+	fn wakes_waiters_on_stop() {
+		let shutdown = Arc::new(Shutdown::new());
+		assert!(!shutdown.wait(Duration::from_millis(10)));
+
+		let started = Instant::now();
+		let waiter = {
+			let shutdown = Arc::clone(&shutdown);
+			std::thread::spawn(move || shutdown.wait(Duration::from_secs(60)))
+		};
+		shutdown.stop();
+		assert!(waiter.join().unwrap());
+		assert!(started.elapsed() < Duration::from_secs(60));
+		assert!(shutdown.is_stopped());
+	}
+}
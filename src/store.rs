@@ -0,0 +1,157 @@
+// This file is Copyright its original authors, visible in version contror
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use ldk_node::{
+	io::sqlite_store::SqliteStore,
+	lightning::util::{
+		persist::KVStore,
+		ser::{Readable, Writeable},
+	},
+};
+
+use crate::error::Error;
+
+/// All of Romer's own data lives under this namespace, next to the data of LDK Node.
+pub(crate) const ROMER_PRIMARY_NAMESPACE: &str = "romer";
+
+/// Additional payment information, keyed by payment id.
+pub(crate) const PAYMENT_RECORDS_SECONDARY_NAMESPACE: &str = "payment_records";
+
+/// The payment id each caller-supplied idempotency key was used for, keyed by the key's hash.
+pub(crate) const IDEMPOTENCY_KEYS_SECONDARY_NAMESPACE: &str = "idempotency_keys";
+
+/// Recent amounts sent, keyed by sequence number, to enforce the spending policy.
+pub(crate) const SPENDING_SECONDARY_NAMESPACE: &str = "spending";
+
+/// Sub-accounts, keyed by account id.
+pub(crate) const ACCOUNTS_SECONDARY_NAMESPACE: &str = "accounts";
+
+/// Transfers between sub-accounts, keyed by transfer sequence number.
+pub(crate) const ACCOUNT_TRANSFERS_SECONDARY_NAMESPACE: &str = "account_transfers";
+
+/// The audit log of wallet operations, keyed by entry sequence number.
+pub(crate) const AUDIT_LOG_SECONDARY_NAMESPACE: &str = "audit_log";
+
+/// The double-entry ledger, keyed by entry sequence number.
+pub(crate) const LEDGER_SECONDARY_NAMESPACE: &str = "ledger";
+
+/// A map of records that is persisted to the node's key-value store.
+///
+/// All records are loaded into memory on startup, and every change is written through before it
+/// is applied to the in-memory copy.
+pub(crate) struct PersistedMap<V: Writeable + Readable + Clone> {
+	kv_store: Arc<SqliteStore>,
+	secondary_namespace: &'static str,
+	entries: Mutex<HashMap<String, V>>,
+}
+
+impl<V: Writeable + Readable + Clone> PersistedMap<V> {
+	pub(crate) fn load(
+		kv_store: Arc<SqliteStore>, secondary_namespace: &'static str,
+	) -> Result<Self, Error> {
+		let mut entries = HashMap::new();
+		let keys = kv_store
+			.list(ROMER_PRIMARY_NAMESPACE, secondary_namespace)
+			.map_err(|_e| Error::PersistenceFailed)?;
+		for key in keys {
+			let bytes = kv_store
+				.read(ROMER_PRIMARY_NAMESPACE, secondary_namespace, &key)
+				.map_err(|_e| Error::PersistenceFailed)?;
+			let value = V::read(&mut &bytes[..]).map_err(|_e| Error::PersistenceFailed)?;
+			entries.insert(key, value);
+		}
+
+		Ok(Self { kv_store, secondary_namespace, entries: Mutex::new(entries) })
+	}
+
+	pub(crate) fn get(&self, key: &str) -> Option<V> {
+		self.entries.lock().unwrap().get(key).cloned()
+	}
+
+	pub(crate) fn values(&self) -> Vec<V> {
+		self.entries.lock().unwrap().values().cloned().collect()
+	}
+
+	pub(crate) fn entries(&self) -> Vec<(String, V)> {
+		let entries = self.entries.lock().unwrap();
+		entries.iter().map(|(key, value)| (key.clone(), value.clone())).collect()
+	}
+
+	pub(crate) fn insert(&self, key: &str, value: V) -> Result<(), Error> {
+		let mut entries = self.entries.lock().unwrap();
+		self.write(key, &value)?;
+		entries.insert(key.to_string(), value);
+		Ok(())
+	}
+
+	/// Returns the record stored under `key`, storing `value` first if there is none.
+	pub(crate) fn get_or_insert(&self, key: &str, value: V) -> Result<V, Error> {
+		let mut entries = self.entries.lock().unwrap();
+		if let Some(existing) = entries.get(key) {
+			return Ok(existing.clone());
+		}
+		self.write(key, &value)?;
+		entries.insert(key.to_string(), value.clone());
+		Ok(value)
+	}
+
+	/// Applies `f` to the record stored under `key`, starting from `default` if there is none.
+	pub(crate) fn update<D: FnOnce() -> V, F: FnOnce(&mut V)>(
+		&self, key: &str, default: D, f: F,
+	) -> Result<V, Error> {
+		let mut entries = self.entries.lock().unwrap();
+		let mut value = entries.get(key).cloned().unwrap_or_else(default);
+		f(&mut value);
+		self.write(key, &value)?;
+		entries.insert(key.to_string(), value.clone());
+		Ok(value)
+	}
+
+	pub(crate) fn remove(&self, key: &str) -> Result<(), Error> {
+		let mut entries = self.entries.lock().unwrap();
+		self.kv_store
+			.remove(ROMER_PRIMARY_NAMESPACE, self.secondary_namespace, key, false)
+			.map_err(|_e| Error::PersistenceFailed)?;
+		entries.remove(key);
+		Ok(())
+	}
+
+	fn write(&self, key: &str, value: &V) -> Result<(), Error> {
+		self.kv_store
+			.write(ROMER_PRIMARY_NAMESPACE, self.secondary_namespace, key, &value.encode())
+			.map_err(|_e| Error::PersistenceFailed)
+	}
+}
+
+#[cfg(test)]
+pub(crate) fn test_store(name: &str) -> Arc<SqliteStore> {
+	let dir = std::env::temp_dir().join(format!("romer-test-{}-{}", name, std::process::id()));
+	let _ = std::fs::remove_dir_all(&dir);
+	std::fs::create_dir_all(&dir).unwrap();
+	Arc::new(SqliteStore::new(dir, None, None).unwrap())
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn keeps_first_inserted_value_across_restarts() {
+		let kv_store = test_store("get-or-insert");
+		let map: PersistedMap<u64> = PersistedMap::load(Arc::clone(&kv_store), "test").unwrap();
+		assert_eq!(map.get_or_insert("key", 1), Ok(1));
+		assert_eq!(map.get_or_insert("key", 2), Ok(1));
+
+		let map: PersistedMap<u64> = PersistedMap::load(kv_store, "test").unwrap();
+		assert_eq!(map.get("key"), Some(1));
+		assert_eq!(map.get_or_insert("key", 3), Ok(1));
+	}
+}
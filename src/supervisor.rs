@@ -0,0 +1,148 @@
+// This file is Copyright its original authors, visible in version contror
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use ldk_node::{bitcoin::secp256k1::PublicKey, lightning::ln::msgs::SocketAddress, Node};
+
+use crate::event::{Event, EventQueue};
+use crate::shutdown::Shutdown;
+
+/// How often we check on the state of our LSP connections.
+const CONNECTION_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// How long we wait before the first reconnection attempt.
+const MIN_RECONNECT_BACKOFF: Duration = Duration::from_secs(1);
+/// The upper bound on how long we wait between reconnection attempts.
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Exponential backoff between reconnection attempts.
+struct Backoff {
+	delay: Duration,
+}
+
+impl Backoff {
+	fn new() -> Self {
+		Self { delay: MIN_RECONNECT_BACKOFF }
+	}
+
+	/// Returns how long to wait after a failed attempt and doubles the delay for the next one.
+	fn failure(&mut self) -> Duration {
+		let delay = self.delay;
+		self.delay = (self.delay * 2).min(MAX_RECONNECT_BACKOFF);
+		delay
+	}
+
+	fn reset(&mut self) {
+		self.delay = MIN_RECONNECT_BACKOFF;
+	}
+}
+
+struct LspConnection {
+	node_id: PublicKey,
+	address: SocketAddress,
+	connected: bool,
+	backoff: Backoff,
+	next_attempt: Instant,
+}
+
+/// Watches our peer connections with the configured LSPs and reconnects whenever they drop.
+pub(crate) struct LspSupervisor {
+	node: Arc<Node>,
+	lsps: Vec<(PublicKey, SocketAddress)>,
+	event_queue: Arc<EventQueue>,
+	shutdown: Arc<Shutdown>,
+}
+
+impl LspSupervisor {
+	pub(crate) fn new(
+		node: Arc<Node>, lsps: Vec<(PublicKey, SocketAddress)>, event_queue: Arc<EventQueue>,
+		shutdown: Arc<Shutdown>,
+	) -> Self {
+		Self { node, lsps, event_queue, shutdown }
+	}
+
+	pub(crate) fn run(&self) {
+		let now = Instant::now();
+		let mut connections: Vec<LspConnection> = self
+			.lsps
+			.iter()
+			.map(|(node_id, address)| LspConnection {
+				node_id: *node_id,
+				address: address.clone(),
+				connected: false,
+				backoff: Backoff::new(),
+				next_attempt: now,
+			})
+			.collect();
+
+		while !self.shutdown.is_stopped() {
+			let peers = self.node.list_peers();
+			for connection in connections.iter_mut() {
+				let is_connected =
+					peers.iter().any(|p| p.is_connected && p.node_id == connection.node_id);
+				self.check_connection(connection, is_connected);
+			}
+
+			self.shutdown.wait(CONNECTION_CHECK_INTERVAL);
+		}
+	}
+
+	fn check_connection(&self, connection: &mut LspConnection, is_connected: bool) {
+		if is_connected != connection.connected {
+			connection.connected = is_connected;
+			let node_id = connection.node_id;
+			self.event_queue.add_event(if is_connected {
+				Event::LspConnected { node_id }
+			} else {
+				Event::LspDisconnected { node_id }
+			});
+		}
+
+		if connection.connected {
+			connection.backoff.reset();
+		} else if Instant::now() >= connection.next_attempt {
+			match self.node.connect(connection.node_id, connection.address.clone(), false) {
+				Ok(()) => {
+					connection.connected = true;
+					connection.backoff.reset();
+					self.event_queue.add_event(Event::LspConnected { node_id: connection.node_id });
+				},
+				Err(_e) => {
+					connection.next_attempt = Instant::now() + connection.backoff.failure();
+				},
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn backoff_doubles_up_to_max() {
+		let mut backoff = Backoff::new();
+		assert_eq!(backoff.failure(), Duration::from_secs(1));
+		assert_eq!(backoff.failure(), Duration::from_secs(2));
+		assert_eq!(backoff.failure(), Duration::from_secs(4));
+		for _ in 0..10 {
+			backoff.failure();
+		}
+		assert_eq!(backoff.failure(), MAX_RECONNECT_BACKOFF);
+	}
+
+	#[test]
+	fn backoff_resets() {
+		let mut backoff = Backoff::new();
+		backoff.failure();
+		backoff.failure();
+		backoff.reset();
+		assert_eq!(backoff.failure(), MIN_RECONNECT_BACKOFF);
+	}
+}
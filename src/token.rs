@@ -11,7 +11,7 @@ use ldk_node::bitcoin::Network;
 use crate::error::Error;
 
 pub(crate) fn network_from_token(token: &str) -> Result<Network, Error> {
-	if token.len() == 0 {
+	if token.is_empty() {
 		return Err(Error::InvalidAPIToken);
 	}
 
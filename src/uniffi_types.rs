@@ -1,49 +1,23 @@
-use std::fmt::Write;
 use std::str::FromStr;
 
 use ldk_node::bitcoin::hashes::sha256::Hash as Sha256;
 use ldk_node::bitcoin::hashes::Hash;
+pub use ldk_node::bitcoin::secp256k1::PublicKey;
 pub use ldk_node::bitcoin::{Address, BlockHash, Txid};
 use ldk_node::lightning::ln::channelmanager::PaymentId;
+pub use ldk_node::lightning::ln::msgs::SocketAddress;
+pub use ldk_node::lightning::ln::types::ChannelId;
 pub use ldk_node::lightning::ln::{PaymentHash, PaymentPreimage, PaymentSecret};
 pub use ldk_node::lightning::offers::offer::OfferId;
+pub use ldk_node::lightning::util::string::UntrustedString;
 pub use ldk_node::lightning_invoice::Bolt11Invoice;
 use ldk_node::lightning_invoice::SignedRawBolt11Invoice;
 pub use ldk_node::payment::{LSPFeeLimits, PaymentDirection, PaymentKind, PaymentStatus};
 
 use crate::error::Error;
+use crate::hex_utils::{to_string, to_vec};
 use crate::UniffiCustomTypeConverter;
 
-pub fn to_vec(hex: &str) -> Option<Vec<u8>> {
-	let mut out = Vec::with_capacity(hex.len() / 2);
-
-	let mut b = 0;
-	for (idx, c) in hex.as_bytes().iter().enumerate() {
-		b <<= 4;
-		match *c {
-			b'A'..=b'F' => b |= c - b'A' + 10,
-			b'a'..=b'f' => b |= c - b'a' + 10,
-			b'0'..=b'9' => b |= c - b'0',
-			_ => return None,
-		}
-		if (idx & 1) == 1 {
-			out.push(b);
-			b = 0;
-		}
-	}
-
-	Some(out)
-}
-
-#[inline]
-pub fn to_string(value: &[u8]) -> String {
-	let mut res = String::with_capacity(2 * value.len());
-	for v in value {
-		write!(&mut res, "{:02x}", v).expect("Unable to write");
-	}
-	res
-}
-
 impl UniffiCustomTypeConverter for Address {
 	type Builtin = String;
 
@@ -187,3 +161,71 @@ impl UniffiCustomTypeConverter for BlockHash {
 		obj.to_string()
 	}
 }
+
+impl UniffiCustomTypeConverter for PublicKey {
+	type Builtin = String;
+
+	fn into_custom(val: Self::Builtin) -> uniffi::Result<Self> {
+		if let Ok(key) = PublicKey::from_str(&val) {
+			return Ok(key);
+		}
+
+		Err(Error::InvalidPublicKey.into())
+	}
+
+	fn from_custom(obj: Self) -> Self::Builtin {
+		obj.to_string()
+	}
+}
+
+impl UniffiCustomTypeConverter for SocketAddress {
+	type Builtin = String;
+
+	fn into_custom(val: Self::Builtin) -> uniffi::Result<Self> {
+		if let Ok(addr) = SocketAddress::from_str(&val) {
+			return Ok(addr);
+		}
+
+		Err(Error::InvalidSocketAddress.into())
+	}
+
+	fn from_custom(obj: Self) -> Self::Builtin {
+		obj.to_string()
+	}
+}
+
+impl UniffiCustomTypeConverter for ChannelId {
+	type Builtin = String;
+
+	fn into_custom(val: Self::Builtin) -> uniffi::Result<Self> {
+		if let Some(bytes_vec) = to_vec(&val) {
+			if let Ok(bytes) = bytes_vec.try_into() {
+				return Ok(ChannelId(bytes));
+			}
+		}
+
+		Err(Error::InvalidChannelId.into())
+	}
+
+	fn from_custom(obj: Self) -> Self::Builtin {
+		to_string(&obj.0)
+	}
+}
+
+impl From<uniffi::UnexpectedUniFFICallbackError> for Error {
+	fn from(_e: uniffi::UnexpectedUniFFICallbackError) -> Self {
+		Error::ExchangeRateUnavailable
+	}
+}
+
+impl UniffiCustomTypeConverter for UntrustedString {
+	type Builtin = String;
+
+	fn into_custom(val: Self::Builtin) -> uniffi::Result<Self> {
+		Ok(UntrustedString(val))
+	}
+
+	fn from_custom(obj: Self) -> Self::Builtin {
+		obj.to_string()
+	}
+}
@@ -0,0 +1,335 @@
+// This file is Copyright its original authors, visible in version contror
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+use std::collections::{HashMap, HashSet};
+use std::sync::{mpsc, Mutex};
+
+use ldk_node::lightning::ln::PaymentHash;
+
+use crate::error::Error;
+
+/// The fee paid for a successful outbound payment, or why it failed.
+pub(crate) type PaymentOutcome = Result<u64, Error>;
+
+/// Callers blocked on the outcome of outbound payments, keyed by payment hash.
+///
+/// Any number of callers can wait on the same payment, and all of them are notified of its
+/// outcome. Once closed, everyone waiting is woken with [`Error::Stopped`], as no outcome will
+/// arrive anymore.
+pub(crate) struct PaymentWaiters {
+	inner: Mutex<WaitersInner>,
+}
+
+struct WaitersInner {
+	next_id: u64,
+	senders: HashMap<PaymentHash, Vec<(u64, mpsc::SyncSender<PaymentOutcome>)>>,
+	/// Payments initiated via [`PaymentWaiters::pay`] whose outcome we don't know yet.
+	in_flight: HashSet<PaymentHash>,
+	closed: bool,
+}
+
+impl PaymentWaiters {
+	pub(crate) fn new() -> Self {
+		let inner = WaitersInner {
+			next_id: 0,
+			senders: HashMap::new(),
+			in_flight: HashSet::new(),
+			closed: false,
+		};
+		Self { inner: Mutex::new(inner) }
+	}
+
+	/// Starts waiting on the outcome of the payment with the given hash.
+	///
+	/// Must be called before the payment could settle, so the outcome isn't missed.
+	pub(crate) fn register(&self, payment_hash: PaymentHash) -> Waiter<'_> {
+		let mut inner = self.inner.lock().unwrap();
+		self.register_locked(&mut inner, payment_hash)
+	}
+
+	fn register_locked(&self, inner: &mut WaitersInner, payment_hash: PaymentHash) -> Waiter<'_> {
+		let (sender, receiver) = mpsc::sync_channel(1);
+		let id = inner.next_id;
+		inner.next_id += 1;
+		if inner.closed {
+			// The channel has room, as we just created it.
+			let _ = sender.try_send(Err(Error::Stopped));
+		} else {
+			inner.senders.entry(payment_hash).or_default().push((id, sender));
+		}
+		Waiter { waiters: self, payment_hash, id, receiver }
+	}
+
+	/// Pays the payment with the given hash via `initiate` and waits for its outcome, unless we
+	/// are already paying it.
+	///
+	/// While a payment is in flight, other calls for it fail with [`Error::DuplicatePayment`],
+	/// or wait for its outcome if `share_outcome` is set. If `initiate` fails, its error is the
+	/// outcome.
+	pub(crate) fn pay<F>(
+		&self, payment_hash: PaymentHash, share_outcome: bool, initiate: F,
+	) -> PaymentOutcome
+	where
+		F: FnOnce() -> Result<(), Error>,
+	{
+		let waiter = {
+			let mut inner = self.inner.lock().unwrap();
+			if inner.closed {
+				return Err(Error::Stopped);
+			}
+			if inner.in_flight.contains(&payment_hash) {
+				if !share_outcome {
+					return Err(Error::DuplicatePayment);
+				}
+				let waiter = self.register_locked(&mut inner, payment_hash);
+				drop(inner);
+				return waiter.wait();
+			}
+			inner.in_flight.insert(payment_hash);
+			self.register_locked(&mut inner, payment_hash)
+		};
+
+		if let Err(e) = initiate() {
+			self.notify(&payment_hash, Err(e.clone()));
+			return Err(e);
+		}
+		waiter.wait()
+	}
+
+	/// Notifies everyone waiting on the payment with the given hash of its outcome.
+	pub(crate) fn notify(&self, payment_hash: &PaymentHash, outcome: PaymentOutcome) {
+		let senders = {
+			let mut inner = self.inner.lock().unwrap();
+			inner.in_flight.remove(payment_hash);
+			inner.senders.remove(payment_hash).unwrap_or_default()
+		};
+		for (_, sender) in senders {
+			// Every sender is used once, so the channel has room unless the waiter gave up.
+			let _ = sender.try_send(outcome.clone());
+		}
+	}
+
+	/// Wakes everyone waiting with [`Error::Stopped`], and lets later calls fail right away.
+	pub(crate) fn close(&self) {
+		let senders = {
+			let mut inner = self.inner.lock().unwrap();
+			inner.closed = true;
+			inner.in_flight.clear();
+			std::mem::take(&mut inner.senders)
+		};
+		for (_, sender) in senders.into_values().flatten() {
+			let _ = sender.try_send(Err(Error::Stopped));
+		}
+	}
+
+	fn unregister(&self, payment_hash: &PaymentHash, id: u64) {
+		let mut inner = self.inner.lock().unwrap();
+		if let Some(senders) = inner.senders.get_mut(payment_hash) {
+			senders.retain(|(sender_id, _)| *sender_id != id);
+			if senders.is_empty() {
+				inner.senders.remove(payment_hash);
+			}
+		}
+	}
+
+	#[cfg(test)]
+	fn waiting(&self, payment_hash: &PaymentHash) -> usize {
+		self.inner.lock().unwrap().senders.get(payment_hash).map_or(0, |senders| senders.len())
+	}
+}
+
+/// A registered wait on the outcome of a payment, which is given up when dropped.
+pub(crate) struct Waiter<'a> {
+	waiters: &'a PaymentWaiters,
+	payment_hash: PaymentHash,
+	id: u64,
+	receiver: mpsc::Receiver<PaymentOutcome>,
+}
+
+impl Waiter<'_> {
+	/// Blocks until the payment settles, or the waiters are closed.
+	pub(crate) fn wait(self) -> PaymentOutcome {
+		self.receiver.recv().expect("waiters are only dropped once notified")
+	}
+}
+
+impl Drop for Waiter<'_> {
+	fn drop(&mut self) {
+		self.waiters.unregister(&self.payment_hash, self.id);
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use std::sync::atomic::{AtomicUsize, Ordering};
+	use std::sync::{Arc, Barrier};
+	use std::thread;
+	use std::time::Duration;
+
+	use super::*;
+
+	/// How long we give a call to return before considering it hung.
+	const TIMEOUT: Duration = Duration::from_secs(10);
+
+	/// Calls `pay` on another thread, returning where its outcome arrives.
+	fn pay_on_thread(
+		waiters: &Arc<PaymentWaiters>, payment_hash: PaymentHash, share_outcome: bool,
+		initiated: &Arc<AtomicUsize>, initiate_result: Result<(), Error>,
+	) -> mpsc::Receiver<PaymentOutcome> {
+		let (sender, receiver) = mpsc::channel();
+		let waiters = Arc::clone(waiters);
+		let initiated = Arc::clone(initiated);
+		thread::spawn(move || {
+			let outcome = waiters.pay(payment_hash, share_outcome, || {
+				initiated.fetch_add(1, Ordering::SeqCst);
+				initiate_result
+			});
+			sender.send(outcome).unwrap();
+		});
+		receiver
+	}
+
+	/// Waits until `count` callers wait on the payment with the given hash.
+	fn wait_for_waiters(waiters: &PaymentWaiters, payment_hash: &PaymentHash, count: usize) {
+		while waiters.waiting(payment_hash) < count {
+			thread::sleep(Duration::from_millis(1));
+		}
+	}
+
+	#[test]
+	fn concurrent_sends_with_a_shared_outcome_pay_once() {
+		let waiters = Arc::new(PaymentWaiters::new());
+		let payment_hash = PaymentHash([1; 32]);
+		let initiated = Arc::new(AtomicUsize::new(0));
+
+		let first = pay_on_thread(&waiters, payment_hash, true, &initiated, Ok(()));
+		let second = pay_on_thread(&waiters, payment_hash, true, &initiated, Ok(()));
+		wait_for_waiters(&waiters, &payment_hash, 2);
+
+		waiters.notify(&payment_hash, Ok(1_000));
+		assert_eq!(first.recv_timeout(TIMEOUT).unwrap(), Ok(1_000));
+		assert_eq!(second.recv_timeout(TIMEOUT).unwrap(), Ok(1_000));
+		assert_eq!(initiated.load(Ordering::SeqCst), 1);
+	}
+
+	#[test]
+	fn concurrent_sends_without_a_shared_outcome_are_duplicates() {
+		let waiters = Arc::new(PaymentWaiters::new());
+		let payment_hash = PaymentHash([1; 32]);
+		let initiated = Arc::new(AtomicUsize::new(0));
+
+		let first = pay_on_thread(&waiters, payment_hash, false, &initiated, Ok(()));
+		wait_for_waiters(&waiters, &payment_hash, 1);
+		let second = pay_on_thread(&waiters, payment_hash, false, &initiated, Ok(()));
+		assert_eq!(second.recv_timeout(TIMEOUT).unwrap(), Err(Error::DuplicatePayment));
+
+		waiters.notify(&payment_hash, Err(Error::RetriesExhausted));
+		assert_eq!(first.recv_timeout(TIMEOUT).unwrap(), Err(Error::RetriesExhausted));
+		assert_eq!(initiated.load(Ordering::SeqCst), 1);
+
+		// Once the outcome is known, the payment can be retried.
+		let retry = pay_on_thread(&waiters, payment_hash, false, &initiated, Ok(()));
+		wait_for_waiters(&waiters, &payment_hash, 1);
+		waiters.notify(&payment_hash, Ok(0));
+		assert_eq!(retry.recv_timeout(TIMEOUT).unwrap(), Ok(0));
+		assert_eq!(initiated.load(Ordering::SeqCst), 2);
+	}
+
+	#[test]
+	fn failing_to_initiate_is_the_shared_outcome() {
+		let waiters = Arc::new(PaymentWaiters::new());
+		let payment_hash = PaymentHash([1; 32]);
+		let joined = Arc::new(Barrier::new(2));
+
+		let first = {
+			let waiters = Arc::clone(&waiters);
+			let joined = Arc::clone(&joined);
+			thread::spawn(move || {
+				waiters.pay(payment_hash, true, || {
+					joined.wait();
+					wait_for_waiters(&waiters, &payment_hash, 2);
+					Err(Error::RouteNotFound)
+				})
+			})
+		};
+		joined.wait();
+		let initiated = Arc::new(AtomicUsize::new(0));
+		let second = pay_on_thread(&waiters, payment_hash, true, &initiated, Ok(()));
+
+		assert_eq!(second.recv_timeout(TIMEOUT).unwrap(), Err(Error::RouteNotFound));
+		assert_eq!(first.join().unwrap(), Err(Error::RouteNotFound));
+		assert_eq!(initiated.load(Ordering::SeqCst), 0);
+	}
+
+	#[test]
+	fn closing_wakes_waiters() {
+		let waiters = Arc::new(PaymentWaiters::new());
+		let payment_hash = PaymentHash([1; 32]);
+		let initiated = Arc::new(AtomicUsize::new(0));
+
+		let paying = pay_on_thread(&waiters, payment_hash, true, &initiated, Ok(()));
+		let sharing = pay_on_thread(&waiters, payment_hash, true, &initiated, Ok(()));
+		wait_for_waiters(&waiters, &payment_hash, 2);
+
+		waiters.close();
+		assert_eq!(paying.recv_timeout(TIMEOUT).unwrap(), Err(Error::Stopped));
+		assert_eq!(sharing.recv_timeout(TIMEOUT).unwrap(), Err(Error::Stopped));
+
+		// Nothing is paid or waited on after closing.
+		let late = pay_on_thread(&waiters, payment_hash, true, &initiated, Ok(()));
+		assert_eq!(late.recv_timeout(TIMEOUT).unwrap(), Err(Error::Stopped));
+		assert_eq!(waiters.register(payment_hash).wait(), Err(Error::Stopped));
+		assert_eq!(initiated.load(Ordering::SeqCst), 1);
+	}
+
+	#[test]
+	fn notifies_every_concurrent_waiter() {
+		let waiters = Arc::new(PaymentWaiters::new());
+		let payment_hash = PaymentHash([1; 32]);
+		let registered = Arc::new(Barrier::new(9));
+
+		let handles: Vec<_> = (0..8)
+			.map(|_| {
+				let waiters = Arc::clone(&waiters);
+				let registered = Arc::clone(&registered);
+				thread::spawn(move || {
+					let waiter = waiters.register(payment_hash);
+					registered.wait();
+					waiter.wait()
+				})
+			})
+			.collect();
+
+		registered.wait();
+		waiters.notify(&PaymentHash([2; 32]), Err(Error::RouteNotFound));
+		waiters.notify(&payment_hash, Ok(1_000));
+		for handle in handles {
+			assert_eq!(handle.join().unwrap(), Ok(1_000));
+		}
+		assert_eq!(waiters.waiting(&payment_hash), 0);
+	}
+
+	#[test]
+	fn dropped_waiters_unregister() {
+		let waiters = PaymentWaiters::new();
+		let payment_hash = PaymentHash([1; 32]);
+
+		let first = waiters.register(payment_hash);
+		let second = waiters.register(payment_hash);
+		assert_eq!(waiters.waiting(&payment_hash), 2);
+		drop(first);
+		assert_eq!(waiters.waiting(&payment_hash), 1);
+
+		waiters.notify(&payment_hash, Err(Error::RetriesExhausted));
+		assert_eq!(second.wait(), Err(Error::RetriesExhausted));
+
+		// A waiter registered after the outcome doesn't see a stale one.
+		let late = waiters.register(payment_hash);
+		assert!(late.receiver.try_recv().is_err());
+	}
+}
@@ -0,0 +1,97 @@
+// This file is Copyright its original authors, visible in version contror
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::time::Duration;
+
+/// Runs blocking calls we may have to give up on in the background, with at most a fixed number
+/// of them running at once.
+///
+/// A call we gave up on keeps its thread until it returns, so calls that never return, e.g. to
+/// an LSP that doesn't answer, can't pile up threads.
+pub(crate) struct BoundedWorkers {
+	running: Arc<AtomicUsize>,
+	max_running: usize,
+}
+
+impl BoundedWorkers {
+	pub(crate) fn new(max_running: usize) -> Self {
+		Self { running: Arc::new(AtomicUsize::new(0)), max_running }
+	}
+
+	/// Runs `call` and waits up to `timeout` for its result.
+	///
+	/// Returns `None` if it didn't return in time, or right away if `max_running` calls are still
+	/// running.
+	pub(crate) fn run<T, F>(&self, timeout: Duration, call: F) -> Option<T>
+	where
+		T: Send + 'static,
+		F: FnOnce() -> T + Send + 'static,
+	{
+		let reserved = self.running.fetch_update(Ordering::AcqRel, Ordering::Acquire, |running| {
+			(running < self.max_running).then(|| running + 1)
+		});
+		if reserved.is_err() {
+			return None;
+		}
+
+		let (sender, receiver) = mpsc::sync_channel(1);
+		let running = Arc::clone(&self.running);
+		std::thread::spawn(move || {
+			let _ = sender.send(call());
+			running.fetch_sub(1, Ordering::AcqRel);
+		});
+		receiver.recv_timeout(timeout).ok()
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use std::time::Instant;
+
+	use super::*;
+
+	#[test]
+	fn returns_the_result_in_time() {
+		let workers = BoundedWorkers::new(1);
+		assert_eq!(workers.run(Duration::from_secs(10), || 42), Some(42));
+		// The thread may not have released its slot yet.
+		let start = Instant::now();
+		while workers.running.load(Ordering::Acquire) > 0 {
+			assert!(start.elapsed() < Duration::from_secs(10));
+			std::thread::yield_now();
+		}
+		assert_eq!(workers.run(Duration::from_secs(10), || 43), Some(43));
+	}
+
+	#[test]
+	fn gives_up_on_calls_and_bounds_the_threads_left_running() {
+		let workers = BoundedWorkers::new(2);
+		let (release, released) = mpsc::channel::<()>();
+		let released = Arc::new(std::sync::Mutex::new(released));
+		for _ in 0..2 {
+			let released = Arc::clone(&released);
+			let call = move || released.lock().unwrap().recv().is_ok();
+			assert_eq!(workers.run(Duration::from_millis(10), call), None);
+		}
+
+		// Both calls still run, so another one isn't even started.
+		let start = Instant::now();
+		assert_eq!(workers.run(Duration::from_secs(10), || true), None);
+		assert!(start.elapsed() < Duration::from_secs(1));
+
+		drop(release);
+		let start = Instant::now();
+		while workers.running.load(Ordering::Acquire) > 0 {
+			assert!(start.elapsed() < Duration::from_secs(10));
+			std::thread::yield_now();
+		}
+		assert_eq!(workers.run(Duration::from_secs(10), || true), Some(true));
+	}
+}